@@ -1,12 +1,14 @@
+use failure::Fail;
 use log::*;
 
 #[tokio::main]
-pub async fn main() {
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init();
 
     let addr = "127.0.0.1:2121";
     let server = libunftp::Server::new_with_fs_root(std::env::temp_dir());
 
     info!("Starting ftp server on {}", addr);
-    server.listen(addr).await;
+    server.listen(addr).await.map_err(Fail::compat)?;
+    Ok(())
 }
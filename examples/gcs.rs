@@ -1,4 +1,5 @@
 use clap::{App, Arg};
+use failure::Fail;
 use std::{error::Error, result::Result};
 use tracing::Level;
 
@@ -71,13 +72,15 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         }))
         .ftps(ftps_certs_file, ftps_key_file)
         .listen(BIND_ADDRESS)
-        .await;
+        .await
+        .map_err(Fail::compat)?;
     } else {
         libunftp::Server::new(Box::new(move || {
             libunftp::storage::cloud_storage::CloudStorage::new(&bucket_name, service_account_key.clone())
         }))
         .listen(BIND_ADDRESS)
-        .await;
+        .await
+        .map_err(Fail::compat)?;
     }
 
     Ok(())
@@ -1,3 +1,4 @@
+use failure::Fail;
 use libunftp::auth::jsonfile;
 use log::info;
 use std::sync::Arc;
@@ -12,7 +13,7 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Starting ftp server on {}", addr);
     let mut runtime = tokio::runtime::Builder::new().build().unwrap();
-    runtime.block_on(server.listen(addr));
+    runtime.block_on(server.listen(addr)).map_err(Fail::compat)?;
 
     Ok(())
 }
@@ -1,3 +1,4 @@
+use failure::Fail;
 use libunftp::auth::rest;
 use log::info;
 use std::env;
@@ -24,6 +25,6 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Starting ftp server on {}", addr);
     let mut runtime = Builder::new().build()?;
-    runtime.block_on(server.listen(addr));
+    runtime.block_on(server.listen(addr)).map_err(Fail::compat)?;
     Ok(())
 }
@@ -1,6 +1,6 @@
 //! The service provider interface (SPI) for auth
 
-use super::UserDetail;
+use super::{ClientCert, UserDetail};
 use async_trait::async_trait;
 use std::{
     error::Error,
@@ -15,6 +15,46 @@ where
 {
     /// Authenticate the given user with the given password.
     async fn authenticate(&self, username: &str, password: &str) -> Result<U, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Authenticate the given user with the given credentials, which include the client certificate
+    /// when FTPS clients are asked for one with [`Server::ftps_client_auth`].
+    ///
+    /// When a client that presented a certificate sends `USER`, this is called without a password
+    /// so that the user can be logged in by certificate alone. If that fails the client is asked
+    /// for its password and this is called again with both, which allows using the certificate as a
+    /// second factor.
+    ///
+    /// The default implementation ignores the certificate and calls
+    /// [`authenticate`](#tymethod.authenticate) when there is a password.
+    ///
+    /// [`Server::ftps_client_auth`]: ../struct.Server.html#method.ftps_client_auth
+    async fn authenticate_with_credentials(&self, username: &str, credentials: &Credentials) -> Result<U, Box<dyn std::error::Error + Send + Sync>> {
+        match &credentials.password {
+            Some(password) => self.authenticate(username, password).await,
+            None => Err(Box::new(BadPasswordError)),
+        }
+    }
+}
+
+/// The credentials that a client logs in with, see
+/// [`Authenticator::authenticate_with_credentials`].
+///
+/// [`Authenticator::authenticate_with_credentials`]: trait.Authenticator.html#method.authenticate_with_credentials
+#[derive(Clone, Default)]
+pub struct Credentials {
+    /// The password given with `PASS`, or `None` when trying to log in by certificate alone.
+    pub password: Option<String>,
+    /// The verified certificate the client presented on the control channel, if any.
+    pub certificate: Option<ClientCert>,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("password", &self.password.as_ref().map(|_| "*******"))
+            .field("certificate", &self.certificate)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,238 @@
+//! Contains the [`ClientCert`] that FTPS clients can log in with.
+//!
+//! [`ClientCert`]: struct.ClientCert.html
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A certificate that an FTPS client presented on the control channel. It has been verified
+/// against the certificate authorities given to [`Server::ftps_client_auth`] by the time an
+/// [`Authenticator`] gets to see it.
+///
+/// [`Server::ftps_client_auth`]: ../struct.Server.html#method.ftps_client_auth
+/// [`Authenticator`]: trait.Authenticator.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCert {
+    der: Vec<u8>,
+    common_name: Option<String>,
+    subject_alt_names: Vec<SubjectAltName>,
+}
+
+/// A name from the subject alternative name extension of a [`ClientCert`].
+///
+/// [`ClientCert`]: struct.ClientCert.html
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubjectAltName {
+    /// A DNS name (`dNSName`).
+    Dns(String),
+    /// An email address (`rfc822Name`).
+    Email(String),
+    /// A URI (`uniformResourceIdentifier`).
+    Uri(String),
+    /// An IP address (`iPAddress`).
+    Ip(IpAddr),
+}
+
+impl ClientCert {
+    pub(crate) fn from_der(der: Vec<u8>) -> Self {
+        let (common_name, subject_alt_names) = parse(&der).unwrap_or_default();
+        ClientCert {
+            der,
+            common_name,
+            subject_alt_names,
+        }
+    }
+
+    /// The DER encoded certificate, for use with an X.509 library when more than the names below
+    /// is needed.
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// The common name (CN) in the subject of the certificate, if it has one.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The DNS names, email addresses, URIs and IP addresses in the subject alternative name
+    /// extension of the certificate. Other kinds of names are left out.
+    pub fn subject_alt_names(&self) -> &[SubjectAltName] {
+        &self.subject_alt_names
+    }
+}
+
+// Just enough DER to get the names out of a certificate that webpki accepted already.
+const SEQUENCE: u8 = 0x30;
+const OID: u8 = 0x06;
+const EXTENSIONS: u8 = 0xa3;
+// The rfc822Name, dNSName, uniformResourceIdentifier and iPAddress choices of GeneralName.
+const EMAIL: u8 = 0x81;
+const DNS: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP: u8 = 0x87;
+// The string types that a DirectoryString like the common name can be encoded in.
+const UTF8_STRING: u8 = 0x0c;
+const PRINTABLE_STRING: u8 = 0x13;
+const TELETEX_STRING: u8 = 0x14;
+const IA5_STRING: u8 = 0x16;
+const UNIVERSAL_STRING: u8 = 0x1c;
+const BMP_STRING: u8 = 0x1e;
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+fn parse(der: &[u8]) -> Option<(Option<String>, Vec<SubjectAltName>)> {
+    let (_, certificate, _) = read(der)?;
+    let (_, tbs_certificate, _) = read(certificate)?;
+    let fields = elements(tbs_certificate)?;
+    // The sequences are the signature algorithm, issuer, validity, subject and public key.
+    let (_, subject) = fields.iter().filter(|(tag, _)| *tag == SEQUENCE).nth(3)?;
+    let common_name = common_name(subject)?;
+
+    let mut subject_alt_names = vec![];
+    if let Some((_, extensions)) = fields.iter().find(|(tag, _)| *tag == EXTENSIONS) {
+        let (_, extensions, _) = read(extensions)?;
+        for (_, extension) in elements(extensions)? {
+            let parts = elements(extension)?;
+            if parts.first() != Some(&(OID, SUBJECT_ALT_NAME)) {
+                continue;
+            }
+            let (_, value) = parts.last()?;
+            let (_, general_names, _) = read(value)?;
+            for (tag, name) in elements(general_names)? {
+                let ia5 = || if name.is_ascii() { Some(latin1(name)) } else { None };
+                let name = match tag {
+                    EMAIL => ia5().map(SubjectAltName::Email),
+                    DNS => ia5().map(SubjectAltName::Dns),
+                    URI => ia5().map(SubjectAltName::Uri),
+                    IP => ip_addr(name).map(SubjectAltName::Ip),
+                    _ => None,
+                };
+                subject_alt_names.extend(name);
+            }
+        }
+    }
+    Some((common_name, subject_alt_names))
+}
+
+fn common_name(subject: &[u8]) -> Option<Option<String>> {
+    for (_, relative_name) in elements(subject)? {
+        for (_, attribute) in elements(relative_name)? {
+            let parts = elements(attribute)?;
+            if parts.len() == 2 && parts[0] == (OID, COMMON_NAME) {
+                let (tag, value) = parts[1];
+                return Some(directory_string(tag, value));
+            }
+        }
+    }
+    Some(None)
+}
+
+// Decodes a DirectoryString, or returns None if it isn't one or is not encoded properly.
+fn directory_string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        UTF8_STRING => String::from_utf8(value.to_vec()).ok(),
+        PRINTABLE_STRING | IA5_STRING if value.is_ascii() => Some(latin1(value)),
+        // T.61 strings are treated as Latin-1 in practice.
+        TELETEX_STRING => Some(latin1(value)),
+        // UTF-16 and UTF-32, both big endian.
+        BMP_STRING => {
+            let units = value.chunks_exact(2);
+            if !units.remainder().is_empty() {
+                return None;
+            }
+            String::from_utf16(&units.map(|unit| u16::from_be_bytes([unit[0], unit[1]])).collect::<Vec<_>>()).ok()
+        }
+        UNIVERSAL_STRING => {
+            let chars = value.chunks_exact(4);
+            if !chars.remainder().is_empty() {
+                return None;
+            }
+            chars.map(|c| std::char::from_u32(u32::from_be_bytes([c[0], c[1], c[2], c[3]]))).collect()
+        }
+        _ => None,
+    }
+}
+
+fn latin1(value: &[u8]) -> String {
+    value.iter().map(|&b| b as char).collect()
+}
+
+fn ip_addr(value: &[u8]) -> Option<IpAddr> {
+    match value.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(value[0], value[1], value[2], value[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(value);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+// Splits the input into its elements as (tag, contents) pairs.
+fn elements(mut input: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut elements = vec![];
+    while !input.is_empty() {
+        let (tag, contents, rest) = read(input)?;
+        elements.push((tag, contents));
+        input = rest;
+    }
+    Some(elements)
+}
+
+// Reads one element and returns its tag, its contents and what follows it.
+fn read(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets].iter().fold(0usize, |len, &octet| (len << 8) | octet as usize);
+        (len, &rest[octets..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{directory_string, ClientCert, SubjectAltName};
+
+    #[test]
+    fn reads_the_names_of_a_certificate() {
+        let pem = include_bytes!("../../tests/resources/client.pem");
+        let der = rustls::internal::pemfile::certs(&mut &pem[..]).unwrap().remove(0).0;
+        let cert = ClientCert::from_der(der);
+        assert_eq!(cert.common_name(), Some("partner-bot"));
+        assert_eq!(
+            cert.subject_alt_names(),
+            &[
+                SubjectAltName::Dns("bot.partner.example".to_string()),
+                SubjectAltName::Email("bot@partner.example".to_string())
+            ][..]
+        );
+    }
+
+    #[test]
+    fn decodes_directory_strings() {
+        assert_eq!(directory_string(0x0c, "Zoë".as_bytes()), Some("Zoë".to_string()));
+        assert_eq!(directory_string(0x13, b"bot"), Some("bot".to_string()));
+        assert_eq!(directory_string(0x14, &[0x5a, 0x6f, 0xeb]), Some("Zoë".to_string()));
+        assert_eq!(directory_string(0x1e, &[0x00, 0x5a, 0x00, 0x6f, 0x00, 0xeb]), Some("Zoë".to_string()));
+        assert_eq!(directory_string(0x1c, &[0, 0, 0, 0x5a, 0, 0, 0, 0x6f, 0, 0, 0, 0xeb]), Some("Zoë".to_string()));
+        assert_eq!(directory_string(0x0c, &[0xff]), None);
+        assert_eq!(directory_string(0x1e, &[0x00]), None);
+    }
+
+    #[test]
+    fn garbage_has_no_names() {
+        let cert = ClientCert::from_der(vec![0x30, 0x03, 0x02]);
+        assert_eq!(cert.common_name(), None);
+        assert!(cert.subject_alt_names().is_empty());
+    }
+}
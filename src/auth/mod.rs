@@ -41,9 +41,12 @@ pub mod anonymous;
 pub use anonymous::AnonymousAuthenticator;
 
 pub(crate) mod authenticator;
-pub use authenticator::Authenticator;
+pub use authenticator::{Authenticator, Credentials};
+
+mod client_cert;
 #[allow(unused_imports)]
 pub(crate) use authenticator::{BadPasswordError, UnknownUsernameError};
+pub use client_cert::{ClientCert, SubjectAltName};
 
 mod user;
 pub use user::{DefaultUser, UserDetail};
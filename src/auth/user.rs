@@ -1,3 +1,4 @@
+use crate::options::{FtpsRequired, RateLimits};
 use std::fmt::{self, Debug, Display, Formatter};
 
 /// UserDetail defines the requirements for implementations that hold _Security Subject_
@@ -16,6 +17,25 @@ pub trait UserDetail: Send + Sync + Display + Debug {
     fn account_enabled(&self) -> bool {
         true
     }
+
+    /// Tells which channels this subject must protect with TLS. This is applied on top of what
+    /// [`Server::ftps_required`] demands and thus can only make the policy stricter. This default
+    /// implementation adds no requirements.
+    ///
+    /// [`Server::ftps_required`]: ../struct.Server.html#method.ftps_required
+    fn ftps_required(&self) -> FtpsRequired {
+        FtpsRequired::Off
+    }
+
+    /// Returns the transfer rate limits for this subject's sessions, replacing the ones set with
+    /// [`Server::session_rate_limits`]. The server wide limits set with [`Server::rate_limits`]
+    /// still apply. This default implementation returns `None` to keep the session limits.
+    ///
+    /// [`Server::session_rate_limits`]: ../struct.Server.html#method.session_rate_limits
+    /// [`Server::rate_limits`]: ../struct.Server.html#method.rate_limits
+    fn rate_limits(&self) -> Option<RateLimits> {
+        None
+    }
 }
 
 /// DefaultUser is a default implementation of the `UserDetail` trait that doesn't hold any user
@@ -24,10 +24,13 @@
 
 pub mod auth;
 pub(crate) mod metrics;
+pub mod options;
 pub(crate) mod server;
 pub mod storage;
 
+pub use crate::server::error::{ServerError, ServerErrorKind};
 pub use crate::server::ftpserver::Server;
+pub use crate::server::handle::{ServerHandle, SessionInfo, TransferInfo};
 
 #[cfg(feature = "rest_auth")]
 #[macro_use]
@@ -1,80 +1,350 @@
-//! Contains the `add...metric` functions that are used for gathering metrics.
+//! Contains the `Metrics` struct and the label types that are used for gathering metrics.
 
-use crate::server::{Command, ControlChanErrorKind, Event, InternalMsg, Reply, ReplyCode};
+use crate::options::MetricsOptions;
+use crate::server::{Command, ControlChanErrorKind, Event, InternalMsg, LockoutKey, Reply, ReplyCode};
+use lazy_static::lazy_static;
+use prometheus::{core::Collector, exponential_buckets, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use lazy_static::*;
-use prometheus::{opts, register_int_counter, register_int_counter_vec, register_int_gauge, IntCounter, IntCounterVec, IntGauge};
+// The label shared by the users that don't get a label of their own, see UserLabels.
+const OTHER_USERS_LABEL: &str = "other";
+
+// The namespace and sorted constant labels of a set of metrics.
+type MetricsKey = (String, Vec<(String, String)>);
 
 lazy_static! {
-    static ref FTP_AUTH_FAILURES: IntCounter = register_int_counter!(opts!("ftp_auth_failures", "Total number of authentication failures.")).unwrap();
-    static ref FTP_SESSIONS: IntGauge = register_int_gauge!(opts!("ftp_sessions_total", "Total number of FTP sessions.")).unwrap();
-    static ref FTP_BACKEND_WRITE_BYTES: IntCounter =
-        register_int_counter!(opts!("ftp_backend_write_bytes", "Total number of bytes written to the backend.")).unwrap();
-    static ref FTP_BACKEND_READ_BYTES: IntCounter =
-        register_int_counter!(opts!("ftp_backend_read_bytes", "Total number of bytes retrieved from the backend.")).unwrap();
-    static ref FTP_BACKEND_WRITE_FILES: IntCounter =
-        register_int_counter!(opts!("ftp_backend_write_files", "Total number of files written to the backend.")).unwrap();
-    static ref FTP_BACKEND_READ_FILES: IntCounter =
-        register_int_counter!(opts!("ftp_backend_read_files", "Total number of files retrieved from the backend.")).unwrap();
-    static ref FTP_COMMAND_TOTAL: IntCounterVec = register_int_counter_vec!("ftp_command_total", "Total number of commands received.", &["command"]).unwrap();
-    static ref FTP_REPLY_TOTAL: IntCounterVec =
-        register_int_counter_vec!("ftp_reply_total", "Total number of reply codes server sent to clients.", &["range"]).unwrap();
-    static ref FTP_ERROR_TOTAL: IntCounterVec = register_int_counter_vec!("ftp_error_total", "Total number of errors encountered.", &["type"]).unwrap();
+    // The metrics registered with the default registry, which are shared by all servers that use
+    // the same namespace and constant labels.
+    static ref DEFAULT_REGISTRY_METRICS: Mutex<HashMap<MetricsKey, Arc<Metrics>>> = Mutex::new(HashMap::new());
+}
+
+/// The metrics of a server, registered with the registry given in its `MetricsOptions`.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    auth_failures: IntCounter,
+    sessions: IntGauge,
+    backend_write_bytes: IntCounter,
+    backend_read_bytes: IntCounter,
+    backend_write_files: IntCounter,
+    backend_read_files: IntCounter,
+    command_total: IntCounterVec,
+    reply_total: IntCounterVec,
+    error_total: IntCounterVec,
+    auth_lockouts: IntCounterVec,
+    transfer_duration: HistogramVec,
+    transfer_size: HistogramVec,
+    transfers_active: IntGauge,
+    passive_port_failures: IntCounter,
+    tls_handshake_failures: IntCounterVec,
+    user_transfers: IntCounterVec,
+    user_transfer_bytes: IntCounterVec,
+}
+
+/// The data transfer commands, used to label the transfer metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TransferKind {
+    Retr,
+    Stor,
+    List,
+    Nlst,
 }
 
-/// Add a metric for an event.
-pub fn add_event_metric(event: &Event) {
-    match event {
-        Event::Command(cmd) => {
-            add_command_metric(&cmd);
+impl TransferKind {
+    /// Returns the kind of transfer done by the given command, if any.
+    pub fn of(cmd: &Command) -> Option<TransferKind> {
+        match cmd {
+            Command::Retr { .. } => Some(TransferKind::Retr),
+            Command::Stor { .. } => Some(TransferKind::Stor),
+            Command::List { .. } => Some(TransferKind::List),
+            Command::Nlst { .. } => Some(TransferKind::Nlst),
+            _ => None,
+        }
+    }
+
+    /// Returns the label value of this kind of transfer.
+    pub fn label(self) -> &'static str {
+        match self {
+            TransferKind::Retr => "retr",
+            TransferKind::Stor => "stor",
+            TransferKind::List => "list",
+            TransferKind::Nlst => "nlst",
         }
-        Event::InternalMsg(msg) => match msg {
-            InternalMsg::SendData { bytes } => {
-                FTP_BACKEND_READ_BYTES.inc_by(*bytes);
-                FTP_BACKEND_READ_FILES.inc();
-            }
-            InternalMsg::WrittenData { bytes } => {
-                FTP_BACKEND_WRITE_BYTES.inc_by(*bytes);
-                FTP_BACKEND_WRITE_FILES.inc();
-            }
-            _ => {}
-        },
     }
 }
 
-/// Increase the metrics gauge for client sessions
-pub fn inc_session() {
-    FTP_SESSIONS.inc();
+/// The channel that a TLS handshake was done for, used to label the TLS metrics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TlsChannel {
+    Control,
+    Data,
 }
 
-/// Decrease the metrics gauge for client sessions
-pub fn dec_session() {
-    FTP_SESSIONS.dec();
+impl TlsChannel {
+    fn label(self) -> &'static str {
+        match self {
+            TlsChannel::Control => "control",
+            TlsChannel::Data => "data",
+        }
+    }
 }
 
-/// Add a metric for an FTP server error.
-pub fn add_error_metric(error: &ControlChanErrorKind) {
-    let error_str = error.to_string();
-    let label = error_str.split_whitespace().next().unwrap_or("unknown").to_lowercase();
-    FTP_ERROR_TOTAL.with_label_values(&[&label]).inc();
+/// Hands out user name labels for at most `max_users` distinct users, to keep the number of time
+/// series in check. Users that come after that share the `other` label.
+#[derive(Debug)]
+pub(crate) struct UserLabels {
+    max_users: usize,
+    seen: Mutex<HashSet<String>>,
 }
 
-fn add_command_metric(cmd: &Command) {
-    let cmd_str = cmd.to_string();
-    let label = cmd_str.split_whitespace().next().unwrap_or("unknown").to_lowercase();
-    FTP_COMMAND_TOTAL.with_label_values(&[&label]).inc();
+impl UserLabels {
+    pub fn new(max_users: usize) -> Self {
+        UserLabels {
+            max_users,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the label value for the given user.
+    pub fn label(&self, username: &str) -> String {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.contains(username) {
+            return username.to_string();
+        }
+        if seen.len() < self.max_users {
+            seen.insert(username.to_string());
+            return username.to_string();
+        }
+        OTHER_USERS_LABEL.to_string()
+    }
 }
 
-/// Add a metric for a reply.
-pub fn add_reply_metric(reply: &Reply) {
-    match *reply {
-        Reply::None => {}
-        Reply::CodeAndMsg { code, .. } => add_replycode_metric(code),
-        Reply::MultiLine { code, .. } => add_replycode_metric(code),
+/// Keeps the active transfers gauge raised for as long as it lives.
+#[derive(Debug)]
+pub(crate) struct ActiveTransfer(IntGauge);
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        self.0.dec();
     }
 }
 
-fn add_replycode_metric(code: ReplyCode) {
-    let range = format!("{}xx", code as u32 / 100 % 10);
-    FTP_REPLY_TOTAL.with_label_values(&[&range]).inc();
+impl Metrics {
+    /// Returns the metrics for the given options, registering them unless they were registered with
+    /// the default registry before.
+    pub fn for_options(options: &MetricsOptions) -> prometheus::Result<Arc<Metrics>> {
+        if options.registry.is_some() {
+            return Metrics::register(options).map(Arc::new);
+        }
+        let mut const_labels: Vec<(String, String)> = options.const_labels.clone().into_iter().collect();
+        const_labels.sort();
+        let mut shared = DEFAULT_REGISTRY_METRICS.lock().unwrap();
+        let key = (options.namespace.clone(), const_labels);
+        if let Some(metrics) = shared.get(&key) {
+            return Ok(metrics.clone());
+        }
+        let metrics = Arc::new(Metrics::register(options)?);
+        shared.insert(key, metrics.clone());
+        Ok(metrics)
+    }
+
+    // Creates the metrics and registers them with the registry of the given options.
+    fn register(options: &MetricsOptions) -> prometheus::Result<Metrics> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace(options.namespace.clone())
+                .const_labels(options.const_labels.clone())
+        };
+        let histogram_opts = |name: &str, help: &str, buckets: Vec<f64>| {
+            HistogramOpts::new(name, help)
+                .namespace(options.namespace.clone())
+                .const_labels(options.const_labels.clone())
+                .buckets(buckets)
+        };
+        let metrics = Metrics {
+            registry: options.target_registry(),
+            auth_failures: IntCounter::with_opts(opts("auth_failures", "Total number of authentication failures."))?,
+            sessions: IntGauge::with_opts(opts("sessions_total", "Total number of FTP sessions."))?,
+            backend_write_bytes: IntCounter::with_opts(opts("backend_write_bytes", "Total number of bytes written to the backend."))?,
+            backend_read_bytes: IntCounter::with_opts(opts("backend_read_bytes", "Total number of bytes retrieved from the backend."))?,
+            backend_write_files: IntCounter::with_opts(opts("backend_write_files", "Total number of files written to the backend."))?,
+            backend_read_files: IntCounter::with_opts(opts("backend_read_files", "Total number of files retrieved from the backend."))?,
+            command_total: IntCounterVec::new(opts("command_total", "Total number of commands received."), &["command"])?,
+            reply_total: IntCounterVec::new(opts("reply_total", "Total number of reply codes server sent to clients."), &["range"])?,
+            error_total: IntCounterVec::new(opts("error_total", "Total number of errors encountered."), &["type"])?,
+            auth_lockouts: IntCounterVec::new(
+                opts(
+                    "auth_lockouts",
+                    "Total number of times a client IP address or user name got blocked after failed logins.",
+                ),
+                &["type"],
+            )?,
+            transfer_duration: HistogramVec::new(
+                histogram_opts(
+                    "transfer_duration_seconds",
+                    "Duration of completed data transfers.",
+                    exponential_buckets(0.005, 4.0, 10)?,
+                ),
+                &["command"],
+            )?,
+            transfer_size: HistogramVec::new(
+                histogram_opts("transfer_size_bytes", "Size of completed data transfers.", exponential_buckets(256.0, 8.0, 10)?),
+                &["command"],
+            )?,
+            transfers_active: IntGauge::with_opts(opts("transfers_active", "Number of data transfers in progress."))?,
+            passive_port_failures: IntCounter::with_opts(opts(
+                "passive_port_allocation_failures",
+                "Total number of times no passive mode data port could be allocated.",
+            ))?,
+            tls_handshake_failures: IntCounterVec::new(opts("tls_handshake_failures", "Total number of failed TLS handshakes."), &["channel"])?,
+            user_transfers: IntCounterVec::new(
+                opts("user_transfers_total", "Total number of completed data transfers per user."),
+                &["user", "command"],
+            )?,
+            user_transfer_bytes: IntCounterVec::new(opts("user_transfer_bytes", "Total number of bytes transferred per user."), &["user", "command"])?,
+        };
+
+        // Don't leave half of the metrics behind in the registry when one of them can't be registered.
+        for (registered, collector) in metrics.collectors().into_iter().enumerate() {
+            if let Err(err) = metrics.registry.register(collector) {
+                for collector in metrics.collectors().into_iter().take(registered) {
+                    let _ = metrics.registry.unregister(collector);
+                }
+                return Err(err);
+            }
+        }
+        Ok(metrics)
+    }
+
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.auth_failures.clone()),
+            Box::new(self.sessions.clone()),
+            Box::new(self.backend_write_bytes.clone()),
+            Box::new(self.backend_read_bytes.clone()),
+            Box::new(self.backend_write_files.clone()),
+            Box::new(self.backend_read_files.clone()),
+            Box::new(self.command_total.clone()),
+            Box::new(self.reply_total.clone()),
+            Box::new(self.error_total.clone()),
+            Box::new(self.auth_lockouts.clone()),
+            Box::new(self.transfer_duration.clone()),
+            Box::new(self.transfer_size.clone()),
+            Box::new(self.transfers_active.clone()),
+            Box::new(self.passive_port_failures.clone()),
+            Box::new(self.tls_handshake_failures.clone()),
+            Box::new(self.user_transfers.clone()),
+            Box::new(self.user_transfer_bytes.clone()),
+        ]
+    }
+
+    /// Add a metric for an event.
+    pub fn add_event_metric(&self, event: &Event) {
+        match event {
+            Event::Command(cmd) => {
+                self.add_command_metric(&cmd);
+            }
+            Event::InternalMsg(msg) => match msg {
+                InternalMsg::SendData { bytes } => {
+                    self.backend_read_bytes.inc_by(*bytes);
+                    self.backend_read_files.inc();
+                }
+                InternalMsg::WrittenData { bytes } => {
+                    self.backend_write_bytes.inc_by(*bytes);
+                    self.backend_write_files.inc();
+                }
+                InternalMsg::AuthFailed => {
+                    self.auth_failures.inc();
+                }
+                _ => {}
+            },
+        }
+    }
+
+    /// Increase the metrics gauge for client sessions
+    pub fn inc_session(&self) {
+        self.sessions.inc();
+    }
+
+    /// Decrease the metrics gauge for client sessions
+    pub fn dec_session(&self) {
+        self.sessions.dec();
+    }
+
+    /// Add a metric for an FTP server error.
+    pub fn add_error_metric(&self, error: &ControlChanErrorKind) {
+        let label = match error {
+            ControlChanErrorKind::IOError => "io",
+            ControlChanErrorKind::ParseError => "parse",
+            ControlChanErrorKind::InternalServerError => "internal_server",
+            ControlChanErrorKind::AuthenticationError => "authentication",
+            ControlChanErrorKind::InternalMsgError => "internal_msg",
+            ControlChanErrorKind::UTF8Error => "utf8",
+            ControlChanErrorKind::UnknownCommand { .. } => "unknown_command",
+            ControlChanErrorKind::InvalidCommand => "invalid_command",
+            ControlChanErrorKind::ControlChannelTimeout => "control_channel_timeout",
+        };
+        self.error_total.with_label_values(&[label]).inc();
+    }
+
+    /// Raises the active transfers gauge until the returned guard is dropped.
+    pub fn start_transfer(&self) -> ActiveTransfer {
+        self.transfers_active.inc();
+        ActiveTransfer(self.transfers_active.clone())
+    }
+
+    /// Add the metrics for a completed data transfer. The user label is only given when per-user
+    /// metrics are enabled.
+    pub fn add_transfer_metric(&self, kind: TransferKind, duration: Duration, bytes: u64, user: Option<&str>) {
+        self.transfer_duration.with_label_values(&[kind.label()]).observe(duration.as_secs_f64());
+        self.transfer_size.with_label_values(&[kind.label()]).observe(bytes as f64);
+        if let Some(user) = user {
+            self.user_transfers.with_label_values(&[user, kind.label()]).inc();
+            self.user_transfer_bytes.with_label_values(&[user, kind.label()]).inc_by(bytes as i64);
+        }
+    }
+
+    /// Add a metric for a passive mode data port that could not be allocated.
+    pub fn add_passive_port_failure_metric(&self) {
+        self.passive_port_failures.inc();
+    }
+
+    /// Add a metric for a failed TLS handshake.
+    pub fn add_tls_handshake_failure_metric(&self, channel: TlsChannel) {
+        self.tls_handshake_failures.with_label_values(&[channel.label()]).inc();
+    }
+
+    /// Add a metric for a client IP address or user name that got blocked after failed logins.
+    pub fn add_lockout_metric(&self, key: &LockoutKey) {
+        self.auth_lockouts.with_label_values(&[key.kind()]).inc();
+    }
+
+    fn add_command_metric(&self, cmd: &Command) {
+        self.command_total.with_label_values(&[cmd.name()]).inc();
+    }
+
+    /// Add a metric for a reply.
+    pub fn add_reply_metric(&self, reply: &Reply) {
+        match *reply {
+            Reply::None => {}
+            Reply::CodeAndMsg { code, .. } => self.add_replycode_metric(code),
+            Reply::MultiLine { code, .. } => self.add_replycode_metric(code),
+        }
+    }
+
+    fn add_replycode_metric(&self, code: ReplyCode) {
+        let range = match code as u32 / 100 {
+            1 => "1xx",
+            2 => "2xx",
+            3 => "3xx",
+            4 => "4xx",
+            5 => "5xx",
+            _ => "0xx",
+        };
+        self.reply_total.with_label_values(&[range]).inc();
+    }
 }
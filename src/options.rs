@@ -0,0 +1,655 @@
+//! Contains the types that can be passed to the configuration methods of the [`Server`].
+//!
+//! [`Server`]: ../struct.Server.html
+
+use async_trait::async_trait;
+use log::error;
+use prometheus::Registry;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS: u64 = 10;
+const DEFAULT_FAILED_LOGINS_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_FAILED_LOGINS_WINDOW_SECS: u64 = 300;
+const DEFAULT_FAILED_LOGINS_BLOCK_SECS: u64 = 60;
+const DEFAULT_FAILED_LOGINS_MAX_BLOCK_SECS: u64 = 3600;
+const DEFAULT_PASSIVE_ACCEPT_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_DATA_COMMAND_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_TRANSFER_INACTIVITY_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_METRICS_NAMESPACE: &str = "ftp";
+
+/// The options for [`Server::shutdown_indicator`] that allows users to specify the way in which
+/// a (graceful) shutdown of libunftp should happen.
+///
+/// [`Server::shutdown_indicator`]: ../struct.Server.html#method.shutdown_indicator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shutdown {
+    pub(crate) grace_period: Duration,
+}
+
+impl Shutdown {
+    /// Creates a new `Shutdown` with the default grace period of 10 seconds.
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Sets the maximum time libunftp waits for running data transfers to finish before the
+    /// `listen` future resolves anyway.
+    pub fn grace_period(mut self, d: Duration) -> Self {
+        self.grace_period = d;
+        self
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            grace_period: Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECS),
+        }
+    }
+}
+
+/// The options for [`Server::active_mode`] that tell libunftp how to set up active mode data
+/// connections, that is data connections that the server opens to the client after it sent a
+/// `PORT` or `EPRT` command.
+///
+/// [`Server::active_mode`]: ../struct.Server.html#method.active_mode
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ActiveMode {
+    pub(crate) source_ports: Option<Range<u16>>,
+    pub(crate) allow_foreign_address: bool,
+}
+
+impl ActiveMode {
+    /// Creates a new `ActiveMode`. By default the operating system picks the source port of data
+    /// connections and clients can only ask for data connections to their own IP address.
+    pub fn new() -> Self {
+        ActiveMode::default()
+    }
+
+    /// Sets the range of local ports that data connections are made from. This is useful when a
+    /// firewall only allows outgoing connections from certain ports.
+    pub fn source_ports(mut self, range: Range<u16>) -> Self {
+        self.source_ports = Some(range);
+        self
+    }
+
+    /// Allows clients to ask for data connections to an IP address other than the one of their
+    /// control connection. This opens the door to FTP bounce attacks so it is off by default. When
+    /// it is on, data connections to privileged ports (below 1024) are still refused.
+    pub fn allow_foreign_address(mut self, allow: bool) -> Self {
+        self.allow_foreign_address = allow;
+        self
+    }
+}
+
+/// The option for [`Server::ftps_required`] that tells which channels clients must protect with
+/// TLS. The variants are ordered from least to most strict.
+///
+/// [`Server::ftps_required`]: ../struct.Server.html#method.ftps_required
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FtpsRequired {
+    /// TLS is offered but clients are free to use plaintext connections.
+    Off,
+    /// Clients must send `AUTH TLS` before they can log in.
+    Control,
+    /// Clients must send `AUTH TLS` before they can log in and `PROT P` before they can
+    /// transfer data.
+    ControlAndData,
+}
+
+impl FtpsRequired {
+    pub(crate) fn requires_control(self) -> bool {
+        self >= FtpsRequired::Control
+    }
+
+    pub(crate) fn requires_data(self) -> bool {
+        self == FtpsRequired::ControlAndData
+    }
+}
+
+/// The option for [`Server::ftps_client_auth`] that tells whether FTPS clients have to present a
+/// certificate.
+///
+/// [`Server::ftps_client_auth`]: ../struct.Server.html#method.ftps_client_auth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FtpsClientAuth {
+    /// Clients are asked for a certificate but can do without one.
+    Request,
+    /// Clients must present a certificate, the TLS handshake fails otherwise.
+    Require,
+}
+
+/// A TLS protocol version, see [`TlsOptions`].
+///
+/// [`TlsOptions`]: struct.TlsOptions.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    V1_2,
+    /// TLS 1.3
+    V1_3,
+}
+
+/// The options for [`Server::ftps_tls_options`] that tune the TLS settings of FTPS. They apply to
+/// both the control and data channels.
+///
+/// By default TLS 1.2 and 1.3 are allowed with all cipher suites that rustls supports, sessions
+/// are cached for resumption but no session tickets are issued, and no keys are logged.
+///
+/// [`Server::ftps_tls_options`]: ../struct.Server.html#method.ftps_tls_options
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub(crate) min_version: TlsVersion,
+    pub(crate) max_version: TlsVersion,
+    // None for all cipher suites that rustls supports.
+    pub(crate) cipher_suites: Option<Vec<String>>,
+    pub(crate) key_log: bool,
+    pub(crate) session_tickets: bool,
+    pub(crate) session_cache_size: usize,
+}
+
+impl TlsOptions {
+    /// Creates a new `TlsOptions` with the defaults.
+    pub fn new() -> Self {
+        TlsOptions::default()
+    }
+
+    /// Sets the lowest TLS version that clients may use.
+    pub fn min_version(mut self, version: TlsVersion) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    /// Sets the highest TLS version that clients may use.
+    pub fn max_version(mut self, version: TlsVersion) -> Self {
+        self.max_version = version;
+        self
+    }
+
+    /// Restricts the cipher suites to the given ones, most preferred first. Suites are named like
+    /// in the IANA registry, for instance `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384` or
+    /// `TLS_AES_256_GCM_SHA384`. The rustls names of the TLS 1.3 suites, like
+    /// `TLS13_AES_256_GCM_SHA384`, work as well. Unknown names make
+    /// [`Server::listen`](../struct.Server.html#method.listen) fail.
+    pub fn cipher_suites<I, T>(mut self, suites: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.cipher_suites = Some(suites.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Enables writing the TLS session secrets to the file named by the `SSLKEYLOGFILE`
+    /// environment variable, to inspect traffic with tools like Wireshark. Anyone who can read that
+    /// file can decrypt the sessions, so only enable this for debugging.
+    pub fn key_log(mut self, enabled: bool) -> Self {
+        self.key_log = enabled;
+        self
+    }
+
+    /// Enables issuing session tickets, which let clients resume sessions without the server
+    /// keeping state. The tickets are valid for 12 hours and encrypted with randomly generated keys.
+    pub fn session_tickets(mut self, enabled: bool) -> Self {
+        self.session_tickets = enabled;
+        self
+    }
+
+    /// Sets how many sessions the server keeps in memory for clients to resume, for instance
+    /// when they open data connections. Zero disables resumption by session ID. The default is
+    /// 256.
+    pub fn session_cache_size(mut self, size: usize) -> Self {
+        self.session_cache_size = size;
+        self
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        TlsOptions {
+            min_version: TlsVersion::V1_2,
+            max_version: TlsVersion::V1_3,
+            cipher_suites: None,
+            key_log: false,
+            session_tickets: false,
+            session_cache_size: 256,
+        }
+    }
+}
+
+/// The option for [`Server::passive_host`] that tells libunftp which IP address to advertise to
+/// clients in its reply to the `PASV` command. This is needed when the server sits behind NAT
+/// and the address it accepted the control connection on is not reachable by clients.
+///
+/// [`Server::passive_host`]: ../struct.Server.html#method.passive_host
+#[derive(Clone)]
+pub enum PassiveHost {
+    /// Advertise the address the control connection was accepted on. This is the default.
+    FromConnection,
+    /// Advertise a fixed IP address.
+    Ip(Ipv4Addr),
+    /// Advertise the IPv4 address of the given host name. The name is resolved once, when the
+    /// server starts listening or on the first call to `Server::serve_connection`.
+    Dns(String),
+    /// Advertise the address returned by the given function. It is called with the IP address of
+    /// the client for every `PASV` command, which allows handing out different addresses to
+    /// clients on different networks.
+    Dynamic(Arc<dyn Fn(IpAddr) -> Ipv4Addr + Send + Sync>),
+}
+
+impl PassiveHost {
+    // Returns the address to advertise given the local and peer IP addresses of the control
+    // connection. Host names are expected to have been resolved by the time this gets called.
+    pub(crate) fn advertised_ip(&self, local_ip: IpAddr, peer_ip: IpAddr) -> IpAddr {
+        match self {
+            PassiveHost::FromConnection => local_ip,
+            PassiveHost::Dns(name) => {
+                error!("Passive host {} was never resolved, advertising {} instead", name, local_ip);
+                local_ip
+            }
+            PassiveHost::Ip(ip) => IpAddr::V4(*ip),
+            PassiveHost::Dynamic(resolve) => IpAddr::V4(resolve(peer_ip)),
+        }
+    }
+}
+
+impl fmt::Debug for PassiveHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PassiveHost::FromConnection => write!(f, "FromConnection"),
+            PassiveHost::Ip(ip) => f.debug_tuple("Ip").field(ip).finish(),
+            PassiveHost::Dns(name) => f.debug_tuple("Dns").field(name).finish(),
+            PassiveHost::Dynamic(_) => write!(f, "Dynamic(..)"),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for PassiveHost {
+    fn from(ip: Ipv4Addr) -> Self {
+        PassiveHost::Ip(ip)
+    }
+}
+
+impl From<[u8; 4]> for PassiveHost {
+    fn from(octets: [u8; 4]) -> Self {
+        PassiveHost::Ip(octets.into())
+    }
+}
+
+impl From<&str> for PassiveHost {
+    fn from(name: &str) -> Self {
+        match name.parse() {
+            Ok(ip) => PassiveHost::Ip(ip),
+            Err(_) => PassiveHost::Dns(name.to_string()),
+        }
+    }
+}
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `2001:db8::/32`, as used by
+/// [`Server::ip_allow_list`] and [`Server::ip_deny_list`]. A plain IP address is taken to be a
+/// range with just that address in it.
+///
+/// # Example
+///
+/// ```rust
+/// use libunftp::options::Cidr;
+///
+/// let range: Cidr = "192.168.0.0/16".parse().unwrap();
+/// assert!(range.contains("192.168.1.1".parse().unwrap()));
+/// assert!(!range.contains("10.0.0.1".parse().unwrap()));
+/// ```
+///
+/// [`Server::ip_allow_list`]: ../struct.Server.html#method.ip_allow_list
+/// [`Server::ip_deny_list`]: ../struct.Server.html#method.ip_deny_list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Tells whether the given address falls within this range. IPv4 addresses that are mapped
+    /// into IPv6, like the ones seen on a dual-stack socket, match IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len),
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.segments() {
+                [0, 0, 0, 0, 0, 0xffff, _, _] => {
+                    let octets = ip.octets();
+                    self.contains(IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15])))
+                }
+                _ => false,
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let host_bits = u32::from(bits - prefix_len);
+    net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or_default().parse().map_err(|_| CidrParseError)?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| CidrParseError)?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(CidrParseError);
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        Cidr { addr, prefix_len }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The error returned when a [`Cidr`] could not be parsed.
+///
+/// [`Cidr`]: struct.Cidr.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CidrParseError;
+
+impl fmt::Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid CIDR notation")
+    }
+}
+
+impl std::error::Error for CidrParseError {}
+
+/// What a [`ConnectionFilter`] decided about a new connection.
+///
+/// [`ConnectionFilter`]: trait.ConnectionFilter.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionDecision {
+    /// Let the client in.
+    Accept,
+    /// Let the client in and attach the given tag to its session. The tag shows up in the logs of
+    /// the session.
+    Tag(String),
+    /// Send the client a `421` reply with the given message and close the connection. Line breaks
+    /// in the message are replaced by spaces.
+    Reject(String),
+}
+
+/// A hook for [`Server::connection_filter`] that decides whether to let a client in. It is called
+/// for every new control connection before the greeting is sent, after the checks against
+/// [`Server::ip_allow_list`] and [`Server::ip_deny_list`] have passed.
+///
+/// # Example
+///
+/// ```rust
+/// use async_trait::async_trait;
+/// use libunftp::options::{ConnectionDecision, ConnectionFilter};
+/// use std::net::SocketAddr;
+///
+/// #[derive(Debug)]
+/// struct PartnerTagger;
+///
+/// #[async_trait]
+/// impl ConnectionFilter for PartnerTagger {
+///     async fn check(&self, peer_addr: SocketAddr) -> ConnectionDecision {
+///         if peer_addr.ip().is_loopback() {
+///             ConnectionDecision::Tag(String::from("local"))
+///         } else {
+///             ConnectionDecision::Accept
+///         }
+///     }
+/// }
+/// ```
+///
+/// [`Server::connection_filter`]: ../struct.Server.html#method.connection_filter
+/// [`Server::ip_allow_list`]: ../struct.Server.html#method.ip_allow_list
+/// [`Server::ip_deny_list`]: ../struct.Server.html#method.ip_deny_list
+#[async_trait]
+pub trait ConnectionFilter: Sync + Send + fmt::Debug {
+    /// Decides about a connection from the given client address. In PROXY protocol mode this is
+    /// the address of the client as reported by the proxy.
+    async fn check(&self, peer_addr: SocketAddr) -> ConnectionDecision;
+}
+
+/// The options for [`Server::failed_logins_policy`] that tell libunftp when to block clients that
+/// fail to log in. Failed logins are counted per client IP address and per user name within a
+/// sliding window. A client IP address or user name that reaches the maximum number of attempts is
+/// blocked for a while, and every next time it gets blocked the block lasts twice as long, up to a
+/// maximum.
+///
+/// [`Server::failed_logins_policy`]: ../struct.Server.html#method.failed_logins_policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FailedLoginsPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) window: Duration,
+    pub(crate) block_duration: Duration,
+    pub(crate) max_block_duration: Duration,
+}
+
+impl FailedLoginsPolicy {
+    /// Creates a new `FailedLoginsPolicy` that blocks after 5 failed attempts within 5 minutes,
+    /// for 1 minute at first and for at most an hour.
+    pub fn new() -> Self {
+        FailedLoginsPolicy::default()
+    }
+
+    /// Sets the number of failed attempts within the window after which a client IP address or
+    /// user name gets blocked.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Sets the length of the sliding window in which failed attempts are counted.
+    pub fn window(mut self, d: Duration) -> Self {
+        self.window = d;
+        self
+    }
+
+    /// Sets how long the first block lasts.
+    pub fn block_duration(mut self, d: Duration) -> Self {
+        self.block_duration = d;
+        self
+    }
+
+    /// Sets the maximum length of a block.
+    pub fn max_block_duration(mut self, d: Duration) -> Self {
+        self.max_block_duration = d;
+        self
+    }
+}
+
+impl Default for FailedLoginsPolicy {
+    fn default() -> Self {
+        FailedLoginsPolicy {
+            max_attempts: DEFAULT_FAILED_LOGINS_MAX_ATTEMPTS,
+            window: Duration::from_secs(DEFAULT_FAILED_LOGINS_WINDOW_SECS),
+            block_duration: Duration::from_secs(DEFAULT_FAILED_LOGINS_BLOCK_SECS),
+            max_block_duration: Duration::from_secs(DEFAULT_FAILED_LOGINS_MAX_BLOCK_SECS),
+        }
+    }
+}
+
+/// Data transfer rate limits in bytes per second, as used by [`Server::rate_limits`],
+/// [`Server::session_rate_limits`] and [`UserDetail::rate_limits`]. Downloads are files sent to
+/// clients with `RETR`, uploads are files received from clients with `STOR` and `STOU`.
+///
+/// # Example
+///
+/// ```rust
+/// use libunftp::options::RateLimits;
+///
+/// // Limit downloads to 1 MiB/s and leave uploads unlimited.
+/// let limits = RateLimits::new().download(1024 * 1024);
+/// ```
+///
+/// [`Server::rate_limits`]: ../struct.Server.html#method.rate_limits
+/// [`Server::session_rate_limits`]: ../struct.Server.html#method.session_rate_limits
+/// [`UserDetail::rate_limits`]: ../auth/trait.UserDetail.html#method.rate_limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    pub(crate) download: Option<u64>,
+    pub(crate) upload: Option<u64>,
+}
+
+impl RateLimits {
+    /// Creates a new `RateLimits` without any limits.
+    pub fn new() -> Self {
+        RateLimits::default()
+    }
+
+    /// Limits downloads to the given number of bytes per second.
+    pub fn download(mut self, bytes_per_sec: u64) -> Self {
+        self.download = Some(bytes_per_sec);
+        self
+    }
+
+    /// Limits uploads to the given number of bytes per second.
+    pub fn upload(mut self, bytes_per_sec: u64) -> Self {
+        self.upload = Some(bytes_per_sec);
+        self
+    }
+}
+
+/// The options for [`Server::data_timeouts`] that tell how long libunftp waits on data
+/// connections before giving up on them.
+///
+/// [`Server::data_timeouts`]: ../struct.Server.html#method.data_timeouts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataTimeouts {
+    pub(crate) passive_accept: Duration,
+    pub(crate) command: Duration,
+    pub(crate) inactivity: Duration,
+    pub(crate) upload_grace_period: Duration,
+}
+
+impl DataTimeouts {
+    /// Creates a new `DataTimeouts` with a passive accept timeout of 60 seconds, command and
+    /// inactivity timeouts of 5 minutes and no upload grace period.
+    pub fn new() -> Self {
+        DataTimeouts::default()
+    }
+
+    /// Sets how long to wait for the client to connect after it sent `PASV` or `EPSV`.
+    pub fn passive_accept(mut self, d: Duration) -> Self {
+        self.passive_accept = d;
+        self
+    }
+
+    /// Sets how long an established data connection waits for the client to send the command
+    /// that uses it, like `RETR` or `LIST`.
+    pub fn command(mut self, d: Duration) -> Self {
+        self.command = d;
+        self
+    }
+
+    /// Sets how long a transfer may go on without any data being moved before it is aborted.
+    pub fn inactivity(mut self, d: Duration) -> Self {
+        self.inactivity = d;
+        self
+    }
+
+    /// Sets how long an upload may continue after its control connection was closed. Transfers
+    /// are otherwise aborted as soon as that happens.
+    pub fn upload_grace_period(mut self, d: Duration) -> Self {
+        self.upload_grace_period = d;
+        self
+    }
+}
+
+impl Default for DataTimeouts {
+    fn default() -> Self {
+        DataTimeouts {
+            passive_accept: Duration::from_secs(DEFAULT_PASSIVE_ACCEPT_TIMEOUT_SECS),
+            command: Duration::from_secs(DEFAULT_DATA_COMMAND_TIMEOUT_SECS),
+            inactivity: Duration::from_secs(DEFAULT_TRANSFER_INACTIVITY_TIMEOUT_SECS),
+            upload_grace_period: Duration::from_secs(0),
+        }
+    }
+}
+
+/// The options for [`Server::metrics_options`] that tell where and under which names the
+/// prometheus metrics of a server are registered.
+///
+/// [`Server::metrics_options`]: ../struct.Server.html#method.metrics_options
+#[derive(Debug, Clone)]
+pub struct MetricsOptions {
+    // None for the default registry of the prometheus crate.
+    pub(crate) registry: Option<Registry>,
+    pub(crate) namespace: String,
+    pub(crate) const_labels: HashMap<String, String>,
+}
+
+impl MetricsOptions {
+    /// Creates a new `MetricsOptions` that registers the metrics with the default registry of the
+    /// prometheus crate, under the `ftp` namespace and without constant labels.
+    pub fn new() -> Self {
+        MetricsOptions::default()
+    }
+
+    /// Sets the registry to register the metrics with. Servers that use the default registry with
+    /// the same namespace and constant labels share their metrics, while registering a second
+    /// server with another registry under the same names and labels fails.
+    pub fn registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Sets the prefix of the metric names, for example `ftp` gives `ftp_sessions_total`. An
+    /// empty namespace leaves the names without prefix.
+    pub fn namespace<T: Into<String>>(mut self, namespace: T) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// Adds a label with a fixed value to all metrics, for example to tell the servers that
+    /// register with the same registry apart.
+    pub fn const_label<K: Into<String>, V: Into<String>>(mut self, name: K, value: V) -> Self {
+        self.const_labels.insert(name.into(), value.into());
+        self
+    }
+}
+
+impl Default for MetricsOptions {
+    fn default() -> Self {
+        MetricsOptions {
+            registry: None,
+            namespace: DEFAULT_METRICS_NAMESPACE.to_string(),
+            const_labels: HashMap::new(),
+        }
+    }
+}
+
+impl MetricsOptions {
+    // Returns the registry that the metrics are registered with.
+    pub(crate) fn target_registry(&self) -> Registry {
+        match &self.registry {
+            Some(registry) => registry.clone(),
+            None => prometheus::default_registry().clone(),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Contains the checks that decide whether a client may connect, based on its IP address.
+
+use super::failed_logins::{FailedLogins, LockoutKey};
+use crate::options::{Cidr, ConnectionDecision, ConnectionFilter};
+use std::{net::SocketAddr, sync::Arc};
+
+const ACCESS_DENIED: &str = "Access denied";
+const TOO_MANY_FAILED_LOGINS: &str = "Too many failed login attempts, try again later";
+
+// AccessControl is owned by the Server and cloned into the task that sets up each session.
+#[derive(Clone, Debug, Default)]
+pub struct AccessControl {
+    pub allow: Arc<Vec<Cidr>>,
+    pub deny: Arc<Vec<Cidr>>,
+    pub filter: Option<Arc<dyn ConnectionFilter>>,
+    pub failed_logins: Option<Arc<FailedLogins>>,
+}
+
+impl AccessControl {
+    // Checks the client address against the deny list, the allow list (when not empty), the
+    // failed logins tracker and the connection filter, in that order.
+    pub async fn check(&self, peer_addr: SocketAddr) -> ConnectionDecision {
+        let ip = peer_addr.ip();
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return ConnectionDecision::Reject(ACCESS_DENIED.to_string());
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|range| range.contains(ip)) {
+            return ConnectionDecision::Reject(ACCESS_DENIED.to_string());
+        }
+        if let Some(failed_logins) = &self.failed_logins {
+            if failed_logins.is_blocked(&LockoutKey::Ip(ip)) {
+                return ConnectionDecision::Reject(TOO_MANY_FAILED_LOGINS.to_string());
+            }
+        }
+        match &self.filter {
+            Some(filter) => filter.check(peer_addr).await,
+            None => ConnectionDecision::Accept,
+        }
+    }
+}
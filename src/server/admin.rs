@@ -0,0 +1,80 @@
+//! Contains the HTTP listener that serves the prometheus metrics and the health and readiness
+//! checks of a server. It is only available with the `admin_http` feature.
+
+use super::{
+    error::{ServerError, ServerErrorKind},
+    handle::ServerHandle,
+};
+use failure::Fail;
+use futures::channel::oneshot;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use log::{info, warn};
+use prometheus::{Encoder, Registry, TextEncoder};
+use std::{convert::Infallible, net::SocketAddr};
+
+// Binds to the given address and serves the admin endpoints in the background until the returned
+// sender is dropped or fired. The metrics are gathered from the given registry.
+pub async fn start(bind_address: String, handle: ServerHandle, registry: Registry) -> Result<oneshot::Sender<()>, ServerError> {
+    let addr = match bind_address.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(err) => return Err(err.context(ServerErrorKind::InvalidBindAddress { address: bind_address }).into()),
+    };
+    let builder = match hyper::Server::try_bind(&addr) {
+        Ok(builder) => builder,
+        Err(err) => return Err(err.context(ServerErrorKind::BindError { address: bind_address }).into()),
+    };
+    let make_service = make_service_fn(move |_| {
+        let handle = handle.clone();
+        let registry = registry.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| respond(request, handle.clone(), registry.clone()))) }
+    });
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let server = builder.serve(make_service).with_graceful_shutdown(async {
+        let _ = stop_rx.await;
+    });
+    info!("Serving admin endpoints on {}", addr);
+    tokio::spawn(async move {
+        if let Err(err) = server.await {
+            warn!("Admin listener failed: {}", err);
+        }
+    });
+    Ok(stop_tx)
+}
+
+async fn respond(request: Request<Body>, handle: ServerHandle, registry: Registry) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => metrics(&registry),
+        (&Method::GET, "/health") => text(StatusCode::OK, "OK"),
+        (&Method::GET, "/ready") => match handle.local_addr() {
+            Some(_) => text(StatusCode::OK, "Ready"),
+            None => text(StatusCode::SERVICE_UNAVAILABLE, "Not ready"),
+        },
+        _ => text(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+// Renders the gathered metrics in the prometheus text exposition format.
+fn metrics(registry: &Registry) -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&registry.gather(), &mut buffer) {
+        warn!("Could not encode metrics: {}", err);
+        return text(StatusCode::INTERNAL_SERVER_ERROR, "Could not encode metrics");
+    }
+    let mut response = Response::new(Body::from(buffer));
+    if let Ok(content_type) = encoder.format_type().parse() {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
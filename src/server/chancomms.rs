@@ -3,7 +3,7 @@
 use super::{controlchan::command::Command, session::SharedSession};
 use crate::{
     auth::UserDetail,
-    server::controlchan::ReplyCode,
+    server::controlchan::{commands::PassiveCommand, ReplyCode},
     storage::{Error, StorageBackend},
 };
 use futures::channel::mpsc::{Receiver, Sender};
@@ -70,6 +70,14 @@ pub enum InternalMsg {
     StorageError(Error),
     /// Reply on the command channel
     CommandChannelReply(ReplyCode, String),
+    /// The client did not connect or did not use the data connection of the given generation in time
+    DataConnectionTimedOut(u64),
+    /// The active mode data connection of the given generation could not be opened
+    DataConnectionFailed(u64),
+    /// A transfer was aborted because no data moved for too long
+    TransferTimedOut,
+    /// A transfer was aborted on request of the client (ABOR)
+    TransferAborted,
 }
 
 // ProxyLoopMsg is sent to the proxy loop when proxy protocol mode is enabled. See the
@@ -80,8 +88,11 @@ where
     S: StorageBackend<U>,
     U: UserDetail,
 {
-    /// Command to assign a data port to a session
-    AssignDataPortCommand(SharedSession<S, U>),
+    /// Command to assign a data port to a session. The passive command tells how to reply to the
+    /// client and the number is the data connection generation of the session it is meant for.
+    AssignDataPortCommand(SharedSession<S, U>, PassiveCommand, u64),
+    /// Sent when the client of the session did not connect to the given reserved data port in time.
+    DataPortExpired(SharedSession<S, U>, u16, u64),
 }
 
 pub type ProxyLoopSender<S, U> = Sender<ProxyLoopMsg<S, U>>;
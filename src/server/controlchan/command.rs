@@ -1,10 +1,14 @@
 use super::parse_error::{ParseErrorKind, Result};
-use crate::server::controlchan::commands::{AuthParam, ModeParam, Opt, ProtParam, StruParam};
+use crate::server::controlchan::commands::{AuthParam, EpsvParam, ModeParam, Opt, ProtParam, StruParam};
 use crate::server::password::Password;
 
 use bytes::Bytes;
 use failure::*;
-use std::{fmt, str};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
@@ -44,7 +48,20 @@ pub enum Command {
     Help,
     Noop,
     Pasv,
-    Port,
+    Port {
+        /// The address the client wants the server to connect to.
+        addr: SocketAddr,
+    },
+    /// Extended Passive Mode (EPSV) as specified in RFC 2428.
+    Epsv {
+        /// The network protocol the client wants to use, or `ALL`.
+        param: EpsvParam,
+    },
+    /// Extended Data Port (EPRT) as specified in RFC 2428.
+    Eprt {
+        /// The address the client wants the server to connect to.
+        addr: SocketAddr,
+    },
     Retr {
         /// The path to the file the client would like to retrieve.
         path: String,
@@ -89,7 +106,7 @@ pub enum Command {
     },
     Allo {
         // The `ALLO` command can actually have an optional argument, but since we regard `ALLO`
-    // as noop, we won't even parse it.
+        // as noop, we won't even parse it.
     },
     Abor,
     Stou,
@@ -129,6 +146,51 @@ impl fmt::Display for Command {
 }
 
 impl Command {
+    /// Returns the name of the command in lower case, as used to label metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::User { .. } => "user",
+            Command::Pass { .. } => "pass",
+            Command::Acct { .. } => "acct",
+            Command::Syst => "syst",
+            Command::Stat { .. } => "stat",
+            Command::Type => "type",
+            Command::Stru { .. } => "stru",
+            Command::Mode { .. } => "mode",
+            Command::Help => "help",
+            Command::Noop => "noop",
+            Command::Pasv => "pasv",
+            Command::Port { .. } => "port",
+            Command::Epsv { .. } => "epsv",
+            Command::Eprt { .. } => "eprt",
+            Command::Retr { .. } => "retr",
+            Command::Stor { .. } => "stor",
+            Command::List { .. } => "list",
+            Command::Nlst { .. } => "nlst",
+            Command::Feat => "feat",
+            Command::Pwd => "pwd",
+            Command::Cwd { .. } => "cwd",
+            Command::Cdup => "cdup",
+            Command::Opts { .. } => "opts",
+            Command::Dele { .. } => "dele",
+            Command::Rmd { .. } => "rmd",
+            Command::Quit => "quit",
+            Command::Mkd { .. } => "mkd",
+            Command::Allo { .. } => "allo",
+            Command::Abor => "abor",
+            Command::Stou => "stou",
+            Command::Rnfr { .. } => "rnfr",
+            Command::Rnto { .. } => "rnto",
+            Command::Auth { .. } => "auth",
+            Command::CCC => "ccc",
+            Command::PBSZ { .. } => "pbsz",
+            Command::PROT { .. } => "prot",
+            Command::SIZE { .. } => "size",
+            Command::Rest { .. } => "rest",
+            Command::MDTM { .. } => "mdtm",
+        }
+    }
+
     /// Parse the given bytes into a [`Command`].
     ///
     /// [`Command`]: ./enum.Command.html
@@ -208,10 +270,34 @@ impl Command {
             }
             "PORT" => {
                 let params = parse_to_eol(cmd_params)?;
-                if params.is_empty() {
-                    return Err(ParseErrorKind::InvalidCommand.into());
+                let params = str::from_utf8(&params)?;
+                match parse_port_param(params) {
+                    Some(addr) => Command::Port { addr },
+                    None => return Err(ParseErrorKind::InvalidCommand.into()),
+                }
+            }
+            "EPSV" => {
+                let params = parse_to_eol(cmd_params)?;
+                let param = if params.is_empty() {
+                    EpsvParam::Unspecified
+                } else if params.eq_ignore_ascii_case(b"ALL") {
+                    EpsvParam::All
+                } else {
+                    let protocol = str::from_utf8(&params)?;
+                    match protocol.parse() {
+                        Ok(protocol) => EpsvParam::Protocol(protocol),
+                        Err(_) => return Err(ParseErrorKind::InvalidCommand.into()),
+                    }
+                };
+                Command::Epsv { param }
+            }
+            "EPRT" => {
+                let params = parse_to_eol(cmd_params)?;
+                let params = str::from_utf8(&params)?;
+                match parse_eprt_param(params) {
+                    Some(addr) => Command::Eprt { addr },
+                    None => return Err(ParseErrorKind::InvalidCommand.into()),
                 }
-                Command::Port
             }
             "RETR" => {
                 let path = parse_to_eol(cmd_params)?;
@@ -493,6 +579,38 @@ fn parse_to_eol<T: AsRef<[u8]> + Into<Bytes>>(bytes: T) -> Result<Bytes> {
     }
 }
 
+// Parses the `h1,h2,h3,h4,p1,p2` argument of the PORT command.
+fn parse_port_param(params: &str) -> Option<SocketAddr> {
+    let fields = params.split(',').map(|f| f.trim().parse::<u8>().ok()).collect::<Option<Vec<u8>>>()?;
+    if fields.len() != 6 {
+        return None;
+    }
+    let ip = Ipv4Addr::new(fields[0], fields[1], fields[2], fields[3]);
+    let port = u16::from(fields[4]) << 8 | u16::from(fields[5]);
+    Some(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+// Parses the `<d><net-prt><d><net-addr><d><tcp-port><d>` argument of the EPRT command, where `<d>`
+// is a delimiter character chosen by the client. Only the IPv4 (1) and IPv6 (2) network protocols
+// are supported.
+fn parse_eprt_param(params: &str) -> Option<SocketAddr> {
+    let delimiter = params.chars().next()?;
+    if !delimiter.is_ascii_graphic() {
+        return None;
+    }
+    let fields: Vec<&str> = params.split(delimiter).collect();
+    if fields.len() != 5 || !fields[0].is_empty() || !fields[4].is_empty() {
+        return None;
+    }
+    let ip = match fields[1] {
+        "1" => IpAddr::V4(fields[2].parse::<Ipv4Addr>().ok()?),
+        "2" => IpAddr::V6(fields[2].parse::<Ipv6Addr>().ok()?),
+        _ => return None,
+    };
+    let port = fields[3].parse::<u16>().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
 fn normalize(token: &[u8]) -> Result<String> {
     Ok(str::from_utf8(token).map(|t| t.to_uppercase())?)
 }
@@ -687,7 +805,66 @@ mod tests {
         assert_eq!(Command::parse(input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
 
         let input = "PORT a1,a2,a3,a4,p1,p2\r\n";
-        assert_eq!(Command::parse(input).unwrap(), Command::Port);
+        assert_eq!(Command::parse(input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
+
+        let input = "PORT 127,0,0,1,256,1\r\n";
+        assert_eq!(Command::parse(input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
+
+        let input = "PORT 127,0,0,1,4\r\n";
+        assert_eq!(Command::parse(input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
+
+        let input = "PORT 192,168,1,2,78,33\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Port {
+                addr: "192.168.1.2:20001".parse().unwrap()
+            })
+        );
+    }
+
+    #[test]
+    fn parse_epsv() {
+        let input = "EPSV\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Epsv { param: EpsvParam::Unspecified }));
+
+        let input = "EPSV 2\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Epsv { param: EpsvParam::Protocol(2) }));
+
+        let input = "EPSV all\r\n";
+        assert_eq!(Command::parse(input), Ok(Command::Epsv { param: EpsvParam::All }));
+
+        let input = "EPSV bla\r\n";
+        assert_eq!(Command::parse(input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
+    }
+
+    #[test]
+    fn parse_eprt() {
+        let input = "EPRT |1|132.235.1.2|6275|\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Eprt {
+                addr: "132.235.1.2:6275".parse().unwrap()
+            })
+        );
+
+        let input = "EPRT !2!1080::8:800:200C:417A!5282!\r\n";
+        assert_eq!(
+            Command::parse(input),
+            Ok(Command::Eprt {
+                addr: "[1080::8:800:200C:417A]:5282".parse().unwrap()
+            })
+        );
+
+        let invalid = [
+            "EPRT\r\n",
+            "EPRT |3|132.235.1.2|6275|\r\n",
+            "EPRT |1|1080::8:800:200C:417A|5282|\r\n",
+            "EPRT |1|132.235.1.2|70000|\r\n",
+            "EPRT |1|132.235.1.2|6275\r\n",
+        ];
+        for input in invalid.iter() {
+            assert_eq!(Command::parse(*input), Err(ParseError::from(Context::new(ParseErrorKind::InvalidCommand))));
+        }
     }
 
     #[test]
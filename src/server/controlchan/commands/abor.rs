@@ -19,7 +19,6 @@ use crate::{
     storage::{Metadata, StorageBackend},
 };
 use async_trait::async_trait;
-use futures::prelude::*;
 use log::warn;
 
 #[derive(Debug)]
@@ -38,12 +37,20 @@ where
         let mut session = args.session.lock().await;
         match session.data_abort_tx.take() {
             Some(mut tx) => {
-                tokio::spawn(async move {
-                    if let Err(err) = tx.send(()).await {
-                        warn!("abort failed: {}", err);
-                    }
-                });
-                Ok(Reply::new(ReplyCode::ClosingDataConnection, "Closed data channel"))
+                if let Err(err) = tx.try_send(()) {
+                    // The data channel already went away, so no 426 is coming to finish this off.
+                    warn!("abort failed: {}", err);
+                    session.data_busy = false;
+                    return Ok(Reply::new(ReplyCode::ClosingDataConnection, "Closed data channel"));
+                }
+                if session.data_busy {
+                    // The data channel replies with 426 once the transfer stopped, after which the
+                    // control loop sends the 226 for this command.
+                    session.abort_pending = true;
+                    Ok(Reply::none())
+                } else {
+                    Ok(Reply::new(ReplyCode::ClosingDataConnection, "Closed data channel"))
+                }
             }
             None => Ok(Reply::new(ReplyCode::ClosingDataConnection, "Data channel already closed")),
         }
@@ -15,6 +15,7 @@ use crate::{
 use async_trait::async_trait;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Ccc;
@@ -31,12 +32,18 @@ where
     async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
         let mut tx: Sender<InternalMsg> = args.tx.clone();
         let session = args.session.lock().await;
+        if session.ftps_required.requires_control() {
+            return Ok(Reply::new(ReplyCode::FtpsRequired, "A TLS connection is required on the control channel"));
+        }
         if session.cmd_tls {
-            tokio::spawn(async move {
-                if let Err(err) = tx.send(InternalMsg::PlaintextControlChannel).await {
-                    warn!("{}", err);
+            tokio::spawn(
+                async move {
+                    if let Err(err) = tx.send(InternalMsg::PlaintextControlChannel).await {
+                        warn!("{}", err);
+                    }
                 }
-            });
+                .in_current_span(),
+            );
             Ok(Reply::new(ReplyCode::CommandOkay, "control channel in plaintext now"))
         } else {
             Ok(Reply::new(ReplyCode::Resp533, "control channel already in plaintext mode"))
@@ -32,6 +32,8 @@ where
     async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
         let mut session = args.session.lock().await;
         session.cwd.pop();
+        let cwd = session.cwd.clone();
+        session.update_info(|info| info.cwd = cwd);
         Ok(Reply::new(ReplyCode::FileActionOkay, "OK"))
     }
 }
@@ -60,6 +60,8 @@ where
         } else {
             let r = tx_success.send(InternalMsg::CwdSuccess).await;
             session.cwd.push(path);
+            let cwd = session.cwd.clone();
+            session.update_info(|info| info.cwd = cwd);
             if let Err(e) = r {
                 warn!("Could not send internal message to notify of CWD success: {}", e);
             }
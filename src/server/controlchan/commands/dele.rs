@@ -21,6 +21,7 @@ use async_trait::async_trait;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
 use std::{string::String, sync::Arc};
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Dele {
@@ -49,20 +50,23 @@ where
         let path = session.cwd.join(self.path.clone());
         let mut tx_success: Sender<InternalMsg> = args.tx.clone();
         let mut tx_fail: Sender<InternalMsg> = args.tx.clone();
-        tokio::spawn(async move {
-            match storage.del(&user, path).await {
-                Ok(_) => {
-                    if let Err(err) = tx_success.send(InternalMsg::DelSuccess).await {
-                        warn!("{}", err);
+        tokio::spawn(
+            async move {
+                match storage.del(&user, path).await {
+                    Ok(_) => {
+                        if let Err(err) = tx_success.send(InternalMsg::DelSuccess).await {
+                            warn!("{}", err);
+                        }
                     }
-                }
-                Err(err) => {
-                    if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
-                        warn!("{}", err);
+                    Err(err) => {
+                        if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+                            warn!("{}", err);
+                        }
                     }
                 }
             }
-        });
+            .in_current_span(),
+        );
         Ok(Reply::none())
     }
 }
@@ -0,0 +1,47 @@
+//! The RFC 2428 Extended Data Port (`EPRT`) command
+//
+// The EPRT command allows for the specification of an extended address
+// for the data connection.  The extended address MUST consist of the
+// network protocol as well as the network and transport addresses.  The
+// format of EPRT is:
+//
+// EPRT<space><d><net-prt><d><net-addr><d><tcp-port><d>
+
+use super::port::Port;
+use crate::{
+    auth::UserDetail,
+    server::controlchan::{
+        error::ControlChanError,
+        handler::{CommandContext, CommandHandler},
+        Reply,
+    },
+    storage::{Metadata, StorageBackend},
+};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub struct Eprt {
+    addr: SocketAddr,
+}
+
+impl Eprt {
+    pub fn new(addr: SocketAddr) -> Self {
+        Eprt { addr }
+    }
+}
+
+#[async_trait]
+impl<S, U> CommandHandler<S, U> for Eprt
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    #[tracing_attributes::instrument]
+    async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
+        // EPRT only differs from PORT in the way the address is specified.
+        Port::new(self.addr).handle(args).await
+    }
+}
@@ -0,0 +1,77 @@
+//! The RFC 2428 Extended Passive Mode (`EPSV`) command
+//
+// The EPSV command requests that a server listen on a data port and
+// wait for a connection.  The EPSV command takes an optional argument.
+// The response to this command includes only the TCP port number of the
+// listening connection.  The format of the response, however, is
+// similar to the argument of the EPRT command.  This allows the same
+// parsing routines to be used for both commands.  In addition, the
+// format leaves a place holder for the network protocol and/or network
+// address, which may be needed in the EPSV response in the future.
+
+use super::pasv::{to_ipv4, PassiveCommand, Pasv};
+use crate::{
+    auth::UserDetail,
+    server::controlchan::{
+        error::ControlChanError,
+        handler::{CommandContext, CommandHandler},
+        Reply, ReplyCode,
+    },
+    storage::{Metadata, StorageBackend},
+};
+use async_trait::async_trait;
+
+// The network protocol numbers as assigned by IANA and used in EPSV and EPRT.
+const NET_PRT_IPV4: u16 = 1;
+const NET_PRT_IPV6: u16 = 2;
+
+// The parameter that can be given to the `EPSV` command.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EpsvParam {
+    // No parameter - Use the network protocol of the control connection
+    Unspecified,
+    // 'ALL' - The client will only use EPSV to set up data connections from now on
+    All,
+    // A network protocol number, 1 for IPv4 and 2 for IPv6
+    Protocol(u16),
+}
+
+#[derive(Debug)]
+pub struct Epsv {
+    param: EpsvParam,
+}
+
+impl Epsv {
+    pub fn new(param: EpsvParam) -> Self {
+        Epsv { param }
+    }
+}
+
+#[async_trait]
+impl<S, U> CommandHandler<S, U> for Epsv
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    #[tracing_attributes::instrument]
+    async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
+        // The data connection is always set up with the same network protocol as the control connection.
+        let net_prt = match to_ipv4(args.local_addr.ip()) {
+            Some(_) => NET_PRT_IPV4,
+            None => NET_PRT_IPV6,
+        };
+        match self.param {
+            EpsvParam::All => {
+                args.session.lock().await.epsv_all = true;
+                Ok(Reply::new(ReplyCode::CommandOkay, "EPSV ALL ok"))
+            }
+            EpsvParam::Protocol(p) if p != net_prt => Ok(Reply::new_with_string(
+                ReplyCode::NetworkProtocolNotSupported,
+                format!("Network protocol not supported, use ({})", net_prt),
+            )),
+            _ => Pasv::new().handle_passive(args, PassiveCommand::Epsv).await,
+        }
+    }
+}
@@ -24,7 +24,7 @@ where
 {
     #[tracing_attributes::instrument]
     async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
-        let mut feat_text = vec![" SIZE", " MDTM", "UTF8"];
+        let mut feat_text = vec![" SIZE", " MDTM", "UTF8", " EPRT", " EPSV"];
         // Add the features. According to the spec each feature line must be
         // indented by a space.
         if args.tls_configured {
@@ -25,6 +25,7 @@ use crate::{
 use async_trait::async_trait;
 use futures::prelude::*;
 use log::warn;
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct List;
@@ -43,11 +44,15 @@ where
         let cmd: Command = args.cmd.clone();
         match session.data_cmd_tx.take() {
             Some(mut tx) => {
-                tokio::spawn(async move {
-                    if let Err(err) = tx.send(cmd).await {
-                        warn!("could not notify data channel to respond with LIST. {}", err);
+                session.data_busy = true;
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = tx.send(cmd).await {
+                            warn!("could not notify data channel to respond with LIST. {}", err);
+                        }
                     }
-                });
+                    .in_current_span(),
+                );
                 Ok(Reply::new(ReplyCode::FileStatusOkay, "Sending directory list"))
             }
             None => Ok(Reply::new(ReplyCode::CantOpenDataConnection, "No data connection established")),
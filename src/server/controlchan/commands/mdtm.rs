@@ -15,6 +15,7 @@ use chrono::{offset::Utc, DateTime};
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
 use std::{path::PathBuf, sync::Arc};
+use tracing_futures::Instrument;
 
 const RFC3659_TIME: &str = "%Y%m%d%H%M%S";
 
@@ -46,38 +47,41 @@ where
         let mut tx_success: Sender<InternalMsg> = args.tx.clone();
         let mut tx_fail: Sender<InternalMsg> = args.tx.clone();
 
-        tokio::spawn(async move {
-            match storage.metadata(&user, &path).await {
-                Ok(metadata) => {
-                    let modification_time = match metadata.modified() {
-                        Ok(v) => Some(v),
-                        Err(err) => {
-                            if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+        tokio::spawn(
+            async move {
+                match storage.metadata(&user, &path).await {
+                    Ok(metadata) => {
+                        let modification_time = match metadata.modified() {
+                            Ok(v) => Some(v),
+                            Err(err) => {
+                                if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+                                    warn!("{}", err);
+                                };
+                                None
+                            }
+                        };
+
+                        if let Some(mtime) = modification_time {
+                            if let Err(err) = tx_success
+                                .send(InternalMsg::CommandChannelReply(
+                                    ReplyCode::FileStatus,
+                                    DateTime::<Utc>::from(mtime).format(RFC3659_TIME).to_string(),
+                                ))
+                                .await
+                            {
                                 warn!("{}", err);
-                            };
-                            None
+                            }
                         }
-                    };
-
-                    if let Some(mtime) = modification_time {
-                        if let Err(err) = tx_success
-                            .send(InternalMsg::CommandChannelReply(
-                                ReplyCode::FileStatus,
-                                DateTime::<Utc>::from(mtime).format(RFC3659_TIME).to_string(),
-                            ))
-                            .await
-                        {
+                    }
+                    Err(err) => {
+                        if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
                             warn!("{}", err);
                         }
                     }
                 }
-                Err(err) => {
-                    if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
-                        warn!("{}", err);
-                    }
-                }
             }
-        });
+            .in_current_span(),
+        );
         Ok(Reply::none())
     }
 }
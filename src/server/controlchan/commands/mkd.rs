@@ -21,6 +21,7 @@ use async_trait::async_trait;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
 use std::{path::PathBuf, sync::Arc};
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Mkd {
@@ -49,15 +50,18 @@ where
         let path: PathBuf = session.cwd.join(self.path.clone());
         let mut tx_success: Sender<InternalMsg> = args.tx.clone();
         let mut tx_fail: Sender<InternalMsg> = args.tx.clone();
-        tokio::spawn(async move {
-            if let Err(err) = storage.mkd(&user, &path).await {
-                if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+        tokio::spawn(
+            async move {
+                if let Err(err) = storage.mkd(&user, &path).await {
+                    if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+                        warn!("{}", err);
+                    }
+                } else if let Err(err) = tx_success.send(InternalMsg::MkdirSuccess(path)).await {
                     warn!("{}", err);
                 }
-            } else if let Err(err) = tx_success.send(InternalMsg::MkdirSuccess(path)).await {
-                warn!("{}", err);
             }
-        });
+            .in_current_span(),
+        );
         Ok(Reply::none())
     }
 }
@@ -3,6 +3,7 @@
 //! - [RFC 959 - FTP](https://tools.ietf.org/html/rfc959)
 //! - [RFC 3659 - Extensions to FTP](https://tools.ietf.org/html/rfc3659)
 //! - [RFC 2228 - FTP Security Extensions](https://tools.ietf.org/html/rfc2228)
+//! - [RFC 2428 - FTP Extensions for IPv6 and NATs](https://tools.ietf.org/html/rfc2428)
 
 mod abor;
 mod acct;
@@ -12,6 +13,8 @@ mod ccc;
 mod cdup;
 mod cwd;
 mod dele;
+mod eprt;
+mod epsv;
 mod feat;
 mod help;
 mod list;
@@ -50,6 +53,8 @@ pub use ccc::Ccc;
 pub use cdup::Cdup;
 pub use cwd::Cwd;
 pub use dele::Dele;
+pub use eprt::Eprt;
+pub use epsv::{Epsv, EpsvParam};
 pub use feat::Feat;
 pub use help::Help;
 pub use list::List;
@@ -60,7 +65,7 @@ pub use nlst::Nlst;
 pub use noop::Noop;
 pub use opts::{Opt, Opts};
 pub use pass::Pass;
-pub use pasv::Pasv;
+pub use pasv::{PassiveCommand, Pasv};
 pub use pbsz::Pbsz;
 pub use port::Port;
 pub use prot::{Prot, ProtParam};
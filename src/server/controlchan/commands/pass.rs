@@ -11,7 +11,7 @@
 // the sensitive password information.
 
 use crate::{
-    auth::UserDetail,
+    auth::{Credentials, UserDetail},
     server::{
         chancomms::InternalMsg,
         controlchan::{
@@ -19,8 +19,10 @@ use crate::{
             handler::{CommandContext, CommandHandler},
             Reply, ReplyCode,
         },
+        failed_logins::LockoutKey,
         password,
-        session::SessionState,
+        session::{Session, SessionState},
+        throttle::Buckets,
     },
     storage::{Metadata, StorageBackend},
 };
@@ -28,6 +30,7 @@ use async_trait::async_trait;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::{error, info, warn};
 use std::sync::Arc;
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Pass {
@@ -53,15 +56,30 @@ where
         let session = args.session.lock().await;
         match &session.state {
             SessionState::WaitPass => {
+                if session.ftps_required.requires_control() && !session.cmd_tls {
+                    return Ok(Reply::new(ReplyCode::NotLoggedIn, "A TLS connection is required on the control channel"));
+                }
                 let pass: &str = std::str::from_utf8(&self.password.as_ref())?;
-                let pass: String = pass.to_string();
-                let user: String = match session.username.clone() {
+                let credentials = Credentials {
+                    password: Some(pass.to_string()),
+                    certificate: session.client_cert.clone(),
+                };
+                let username: String = match session.username.clone() {
                     Some(v) => v,
                     None => {
                         error!("NoneError for username. This shouldn't happen.");
                         return Ok(Reply::new(ReplyCode::NotLoggedIn, "Please open a new connection to re-authenticate"));
                     }
                 };
+                let failed_logins = session.failed_logins.clone();
+                if let Some(failed_logins) = &failed_logins {
+                    if failed_logins.is_blocked(&LockoutKey::Ip(session.source.ip())) || failed_logins.is_blocked(&LockoutKey::User(username.clone())) {
+                        warn!("Refusing login for user {} from {:?}: too many failed attempts", username, session.source);
+                        return Ok(Reply::new(ReplyCode::NotLoggedIn, "Too many failed login attempts, try again later"));
+                    }
+                }
+                let source_ip = session.source.ip();
+                let metrics = session.metrics.clone();
                 let mut tx: Sender<InternalMsg> = args.tx.clone();
 
                 let auther = args.authenticator.clone();
@@ -69,31 +87,74 @@ where
                 // without this, the REST authenticator hangs when
                 // performing a http call through Hyper
                 let session2clone = args.session.clone();
-                tokio::spawn(async move {
-                    let msg = match auther.authenticate(&user, &pass).await {
-                        Ok(user) => {
-                            if user.account_enabled() {
-                                let mut session = session2clone.lock().await;
-                                info!("User {} logged in", user);
-                                session.user = Arc::new(Some(user));
-                                InternalMsg::AuthSuccess
-                            } else {
-                                warn!("User {} authenticated but account is disabled", user);
-                                InternalMsg::AuthFailed
+                tokio::spawn(
+                    async move {
+                        let msg = match auther.authenticate_with_credentials(&username, &credentials).await {
+                            Ok(user) => log_in(&mut *session2clone.lock().await, &username, user),
+                            Err(_) => InternalMsg::AuthFailed,
+                        };
+                        if let Some(failed_logins) = failed_logins {
+                            match msg {
+                                InternalMsg::AuthSuccess => failed_logins.record_success(&username),
+                                _ => {
+                                    for key in failed_logins.record_failure(source_ip, &username) {
+                                        warn!("Blocking {:?} after too many failed login attempts", key);
+                                        if let Some(metrics) = &metrics {
+                                            metrics.add_lockout_metric(&key);
+                                        }
+                                    }
+                                }
                             }
                         }
-                        Err(_) => InternalMsg::AuthFailed,
-                    };
-                    tokio::spawn(async move {
-                        if let Err(err) = tx.send(msg).await {
-                            warn!("{}", err);
-                        }
-                    });
-                });
+                        tokio::spawn(
+                            async move {
+                                if let Err(err) = tx.send(msg).await {
+                                    warn!("{}", err);
+                                }
+                            }
+                            .in_current_span(),
+                        );
+                    }
+                    .in_current_span(),
+                );
                 Ok(Reply::none())
             }
             SessionState::New => Ok(Reply::new(ReplyCode::BadCommandSequence, "Please supply a username first")),
+            SessionState::WaitCertAuth => Ok(Reply::new(ReplyCode::BadCommandSequence, "Still checking the client certificate")),
             _ => Ok(Reply::new(ReplyCode::NotLoggedIn, "Please open a new connection to re-authenticate")),
         }
     }
 }
+
+// Logs in the user that the authenticator accepted, unless the account is disabled or the user is
+// required to use TLS on the control channel and doesn't. Returns the message that tells the
+// control loop how it went.
+pub(super) fn log_in<S, U>(session: &mut Session<S, U>, username: &str, user: U) -> InternalMsg
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    let ftps_required = std::cmp::max(session.ftps_required, user.ftps_required());
+    if !user.account_enabled() {
+        warn!("User {} authenticated but account is disabled", user);
+        InternalMsg::AuthFailed
+    } else if ftps_required.requires_control() && !session.cmd_tls {
+        warn!("User {} authenticated but is required to use TLS on the control channel", user);
+        InternalMsg::AuthFailed
+    } else {
+        info!("User {} logged in", user);
+        session.span.record("username", tracing::field::display(username));
+        if let Some(limits) = user.rate_limits() {
+            session.session_buckets = Buckets::new(limits);
+        }
+        session.update_info(|info| {
+            info.username = Some(username.to_string());
+            info.logged_in_at = Some(std::time::SystemTime::now());
+        });
+        session.user = Arc::new(Some(user));
+        session.ftps_required = ftps_required;
+        InternalMsg::AuthSuccess
+    }
+}
@@ -9,33 +9,84 @@
 use crate::{
     auth::UserDetail,
     server::{
-        chancomms::{ProxyLoopMsg, ProxyLoopSender},
+        chancomms::{InternalMsg, ProxyLoopMsg, ProxyLoopSender},
         controlchan::{
             error::ControlChanError,
             handler::{CommandContext, CommandHandler},
-            Command, Reply, ReplyCode,
+            Reply, ReplyCode,
         },
         datachan,
-        session::SharedSession,
-        ControlChanErrorKind,
     },
     storage::{Metadata, StorageBackend},
 };
 use async_trait::async_trait;
-use futures::{
-    channel::mpsc::{channel, Receiver, Sender},
-    prelude::*,
-};
+use futures::{channel::oneshot, prelude::*};
 use lazy_static::lazy_static;
+use log::{debug, warn};
 use rand::{rngs::OsRng, RngCore};
-use std::{io, net::SocketAddr, ops::Range};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+};
 use tokio::{net::TcpListener, sync::Mutex};
+use tracing_futures::Instrument;
 
 const BIND_RETRIES: u8 = 10;
 lazy_static! {
     static ref OS_RNG: Mutex<OsRng> = Mutex::new(OsRng);
 }
 
+// The command that put the session in passive mode. It determines the format of the reply that
+// tells the client where to connect to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PassiveCommand {
+    Pasv,
+    Epsv,
+}
+
+impl PassiveCommand {
+    // Creates the reply for a data port that was opened on the given address. The caller should
+    // have made sure that a PASV reply can be given for the address, see `can_reply`.
+    pub fn reply(self, ip: IpAddr, port: u16) -> Reply {
+        match (self, to_ipv4(ip)) {
+            (PassiveCommand::Pasv, Some(ip)) => {
+                let octets = ip.octets();
+                let p1 = port >> 8;
+                let p2 = port - (p1 * 256);
+                Reply::new_with_string(
+                    ReplyCode::EnteringPassiveMode,
+                    format!("Entering Passive Mode ({},{},{},{},{},{})", octets[0], octets[1], octets[2], octets[3], p1, p2),
+                )
+            }
+            (PassiveCommand::Pasv, None) => Reply::new(ReplyCode::CantOpenDataConnection, "PASV is not supported for IPv6, use EPSV instead"),
+            (PassiveCommand::Epsv, _) => {
+                Reply::new_with_string(ReplyCode::EnteringExtendedPassiveMode, format!("Entering Extended Passive Mode (|||{}|)", port))
+            }
+        }
+    }
+
+    // PASV replies can only express IPv4 addresses while EPSV replies don't contain an address at all.
+    fn can_reply(self, ip: IpAddr) -> bool {
+        self == PassiveCommand::Epsv || to_ipv4(ip).is_some()
+    }
+}
+
+// Returns the IPv4 address for the given address, also when it is an IPv4-mapped IPv6 address like
+// we see for IPv4 clients when listening on a dual-stack socket.
+pub fn to_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    match ip {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => {
+                let octets = ip.octets();
+                Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+            }
+            _ => None,
+        },
+    }
+}
+
 #[derive(Debug)]
 pub struct Pasv {}
 
@@ -62,93 +113,126 @@ impl Pasv {
         listener
     }
 
-    // modifies the session by adding channels that are used to communicate with the data connection
-    // processing loop.
-    #[tracing_attributes::instrument]
-    async fn setup_data_loop_comms<S, U>(&self, session: SharedSession<S, U>)
-    where
-        U: UserDetail + 'static,
-        S: StorageBackend<U> + 'static,
-        S::File: tokio::io::AsyncRead + Send,
-        S::Metadata: Metadata,
-    {
-        let (cmd_tx, cmd_rx): (Sender<Command>, Receiver<Command>) = channel(1);
-        let (data_abort_tx, data_abort_rx): (Sender<()>, Receiver<()>) = channel(1);
-
-        let mut session = session.lock().await;
-        session.data_cmd_tx = Some(cmd_tx);
-        session.data_cmd_rx = Some(cmd_rx);
-        session.data_abort_tx = Some(data_abort_tx);
-        session.data_abort_rx = Some(data_abort_rx);
-    }
-
     // For non-proxy mode we choose a data port here and start listening on it while letting the control
     // channel know (via method return) what the address is that the client should connect to.
     #[tracing_attributes::instrument]
-    async fn handle_nonproxy_mode<S, U>(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError>
+    async fn handle_nonproxy_mode<S, U>(&self, args: CommandContext<S, U>, command: PassiveCommand) -> Result<Reply, ControlChanError>
     where
         U: UserDetail + 'static,
         S: StorageBackend<U> + 'static,
         S::File: tokio::io::AsyncRead + Send,
         S::Metadata: Metadata,
     {
-        // obtain the ip address the client is connected to
-        let conn_addr = match args.local_addr {
-            std::net::SocketAddr::V4(addr) => addr,
-            std::net::SocketAddr::V6(_) => {
-                log::error!("local address is ipv6! we only listen on ipv4, so this shouldn't happen");
-                return Err(ControlChanErrorKind::InternalServerError.into());
-            }
-        };
-
         let listener = Pasv::try_port_range(args.local_addr, args.passive_ports).await;
 
         let mut listener = match listener {
-            Err(_) => return Ok(Reply::new(ReplyCode::CantOpenDataConnection, "No data connection established")),
+            Err(err) => {
+                warn!("Could not allocate a passive port: {}", err);
+                if let Some(metrics) = &args.session.lock().await.metrics {
+                    metrics.add_passive_port_failure_metric();
+                }
+                return Ok(Reply::new(ReplyCode::CantOpenDataConnection, "No data connection established"));
+            }
             Ok(l) => l,
         };
 
-        let octets = conn_addr.ip().octets();
         let port = listener.local_addr()?.port();
-        let p1 = port >> 8;
-        let p2 = port - (p1 * 256);
         let tx = args.tx.clone();
 
-        self.setup_data_loop_comms(args.session.clone()).await;
+        let generation = datachan::setup_data_loop_comms(args.session.clone()).await;
 
         let session = args.session.clone();
+        let (replaced_tx, replaced) = oneshot::channel::<()>();
+        let (peer_ip, accept_timeout, session_end) = {
+            let mut session = session.lock().await;
+            session.passive_listener = Some(replaced_tx);
+            (session.source.ip(), session.data_timeouts.passive_accept, session.end.clone())
+        };
 
         // Open the data connection in a new task and process it.
         // We cannot await this since we first need to let the client know where to connect :-)
-        tokio::spawn(async move {
-            if let Ok((socket, _socket_addr)) = listener.accept().await {
-                let tx = tx.clone();
-                let session_arc = session.clone();
-                let mut session = session_arc.lock().await;
-                datachan::spawn_processing(&mut session, socket, tx);
+        tokio::spawn(
+            async move {
+                let accepted = tokio::select! {
+                    accepted = tokio::time::timeout(accept_timeout, listener.accept()) => accepted,
+                    _ = session_end => {
+                        debug!("Closing passive listener on port {} of ended session", port);
+                        return;
+                    }
+                    _ = replaced => {
+                        debug!("Closing passive listener on port {} that was replaced", port);
+                        return;
+                    }
+                };
+                match accepted {
+                    Ok(Ok((socket, _socket_addr))) => {
+                        let tx = tx.clone();
+                        let session_arc = session.clone();
+                        let mut session = session_arc.lock().await;
+                        if let Err(err) = datachan::spawn_processing(&mut session, socket, tx, generation) {
+                            warn!("Dropping passive data connection on port {}: {}", port, err);
+                        }
+                    }
+                    Ok(Err(err)) => warn!("Could not accept passive data connection: {}", err),
+                    Err(_) => {
+                        warn!("Client did not connect to passive port {} in time", port);
+                        let mut tx = tx;
+                        if let Err(err) = tx.send(InternalMsg::DataConnectionTimedOut(generation)).await {
+                            warn!("Could not notify control channel of data connection timeout: {}", err);
+                        }
+                    }
+                }
             }
-        });
+            .in_current_span(),
+        );
 
-        Ok(Reply::new_with_string(
-            ReplyCode::EnteringPassiveMode,
-            format!("Entering Passive Mode ({},{},{},{},{},{})", octets[0], octets[1], octets[2], octets[3], p1, p2),
-        ))
+        Ok(command.reply(args.passive_host.advertised_ip(args.local_addr.ip(), peer_ip), port))
     }
 
     // For proxy mode we prepare the session and let the proxy loop know (via channel) that it
     // should choose a data port and check for connections on it.
     #[tracing_attributes::instrument]
-    async fn handle_proxy_mode<S, U>(&self, args: CommandContext<S, U>, mut tx: ProxyLoopSender<S, U>) -> Result<Reply, ControlChanError>
+    async fn handle_proxy_mode<S, U>(
+        &self,
+        args: CommandContext<S, U>,
+        mut tx: ProxyLoopSender<S, U>,
+        command: PassiveCommand,
+    ) -> Result<Reply, ControlChanError>
     where
         U: UserDetail + 'static,
         S: StorageBackend<U> + 'static,
         S::File: tokio::io::AsyncRead + Send,
         S::Metadata: Metadata,
     {
-        self.setup_data_loop_comms(args.session.clone()).await;
-        tx.send(ProxyLoopMsg::AssignDataPortCommand(args.session.clone())).await.unwrap();
+        let generation = datachan::setup_data_loop_comms(args.session.clone()).await;
+        tx.send(ProxyLoopMsg::AssignDataPortCommand(args.session.clone(), command, generation))
+            .await
+            .unwrap();
         Ok(Reply::None)
     }
+
+    // Puts the session in passive mode on behalf of the PASV or EPSV command.
+    #[tracing_attributes::instrument]
+    pub async fn handle_passive<S, U>(&self, args: CommandContext<S, U>, command: PassiveCommand) -> Result<Reply, ControlChanError>
+    where
+        U: UserDetail + 'static,
+        S: StorageBackend<U> + 'static,
+        S::File: tokio::io::AsyncRead + Send,
+        S::Metadata: Metadata,
+    {
+        // The reply holds the address that is advertised, which is IPv4 for a control connection
+        // over IPv6 when an IPv4 passive host is set.
+        let peer_ip = args.session.lock().await.source.ip();
+        let advertised_ip = args.passive_host.advertised_ip(args.local_addr.ip(), peer_ip);
+        if !command.can_reply(advertised_ip) {
+            return Ok(command.reply(advertised_ip, 0));
+        }
+        let sender: Option<ProxyLoopSender<S, U>> = args.proxyloop_msg_tx.clone();
+        match sender {
+            Some(tx) => self.handle_proxy_mode(args, tx.clone(), command).await,
+            None => self.handle_nonproxy_mode(args, command).await,
+        }
+    }
 }
 
 #[async_trait]
@@ -161,10 +245,9 @@ where
 {
     #[tracing_attributes::instrument]
     async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
-        let sender: Option<ProxyLoopSender<S, U>> = args.proxyloop_msg_tx.clone();
-        match sender {
-            Some(tx) => self.handle_proxy_mode(args, tx.clone()).await,
-            None => self.handle_nonproxy_mode(args).await,
+        if args.session.lock().await.epsv_all {
+            return Ok(Reply::new(ReplyCode::BadCommandSequence, "EPSV ALL is in effect, use EPSV instead"));
         }
+        self.handle_passive(args, PassiveCommand::Pasv).await
     }
 }
@@ -16,19 +16,91 @@
 // where h1 is the high order 8 bits of the internet host
 // address.
 
+use super::pasv::to_ipv4;
 use crate::{
     auth::UserDetail,
-    server::controlchan::{
-        error::ControlChanError,
-        handler::{CommandContext, CommandHandler},
-        Reply, ReplyCode,
+    server::{
+        controlchan::{
+            error::ControlChanError,
+            handler::{CommandContext, CommandHandler},
+            Reply, ReplyCode,
+        },
+        datachan,
     },
     storage::{Metadata, StorageBackend},
 };
 use async_trait::async_trait;
+use log::warn;
+use rand::{rngs::OsRng, RngCore};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    time::Duration,
+};
+use tokio::net::TcpStream;
+
+const CONNECT_RETRIES: u8 = 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// Ports below this one are reserved for well-known services.
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
 
 #[derive(Debug)]
-pub struct Port;
+pub struct Port {
+    addr: SocketAddr,
+}
+
+impl Port {
+    pub fn new(addr: SocketAddr) -> Self {
+        Port { addr }
+    }
+
+    // Connects to the client from a random port in the given range, retrying with another port if
+    // the chosen one is in use. Without a range the operating system picks the source port.
+    #[tracing_attributes::instrument]
+    async fn connect(addr: SocketAddr, source_ports: Option<Range<u16>>) -> io::Result<TcpStream> {
+        let source_ports = match source_ports {
+            Some(range) => range,
+            None => return TcpStream::connect(addr).await,
+        };
+        let rng_length = source_ports.end.saturating_sub(source_ports.start);
+        if rng_length == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty source port range"));
+        }
+
+        let mut stream: io::Result<TcpStream> = Err(io::Error::new(io::ErrorKind::InvalidInput, "Connect retries cannot be 0"));
+        for _ in 0..CONNECT_RETRIES {
+            let port = OsRng.next_u32() % rng_length as u32 + source_ports.start as u32;
+            stream = Port::connect_from(port as u16, addr).await;
+            match &stream {
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse || e.kind() == io::ErrorKind::AddrNotAvailable => continue,
+                _ => break,
+            }
+        }
+        stream
+    }
+
+    async fn connect_from(port: u16, addr: SocketAddr) -> io::Result<TcpStream> {
+        let (domain, ip) = match addr {
+            SocketAddr::V4(_) => (Domain::ipv4(), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (Domain::ipv6(), IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(ip, port).into())?;
+        TcpStream::connect_std(socket.into_tcp_stream(), &addr).await
+    }
+}
+
+// Tells if the two addresses point to the same host, also when one of them is an IPv4-mapped IPv6
+// address.
+fn same_host(a: IpAddr, b: IpAddr) -> bool {
+    match (to_ipv4(a), to_ipv4(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
 
 #[async_trait]
 impl<S, U> CommandHandler<S, U> for Port
@@ -39,10 +111,56 @@ where
     S::Metadata: Metadata,
 {
     #[tracing_attributes::instrument]
-    async fn handle(&self, _args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
-        Ok(Reply::new(
-            ReplyCode::CommandNotImplemented,
-            "ACTIVE mode is not supported - use PASSIVE instead",
-        ))
+    async fn handle(&self, args: CommandContext<S, U>) -> Result<Reply, ControlChanError> {
+        let active_mode = match &args.active_mode {
+            Some(mode) => mode.clone(),
+            None => {
+                return Ok(Reply::new(
+                    ReplyCode::CommandNotImplemented,
+                    "ACTIVE mode is not supported - use PASSIVE instead",
+                ))
+            }
+        };
+
+        {
+            let session = args.session.lock().await;
+            if session.epsv_all {
+                return Ok(Reply::new(ReplyCode::BadCommandSequence, "EPSV ALL is in effect, use EPSV instead"));
+            }
+            if !active_mode.allow_foreign_address && !same_host(self.addr.ip(), session.source.ip()) {
+                warn!(
+                    "Refusing data connection to {}, which is not the address of the client ({})",
+                    self.addr, session.source
+                );
+                return Ok(Reply::new(
+                    ReplyCode::CommandNotImplementedForParameter,
+                    "Data connections are only allowed to the address of the client",
+                ));
+            }
+            // With foreign addresses allowed, privileged ports are still off limits to keep FTP
+            // bounce attacks away from the well-known services.
+            if active_mode.allow_foreign_address && self.addr.port() < FIRST_UNPRIVILEGED_PORT {
+                warn!("Refusing data connection to privileged port of {}", self.addr);
+                return Ok(Reply::new(
+                    ReplyCode::CommandNotImplementedForParameter,
+                    "Data connections to privileged ports are not allowed",
+                ));
+            }
+        }
+
+        // The connection is opened once the client sends a transfer command.
+        let addr = self.addr;
+        let connect = async move {
+            match tokio::time::timeout(CONNECT_TIMEOUT, Port::connect(addr, active_mode.source_ports)).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Timed out connecting to {}", addr))),
+            }
+        };
+        let generation = datachan::setup_data_loop_comms(args.session.clone()).await;
+        if let Err(err) = datachan::spawn_active_processing(args.session.clone(), connect, args.tx.clone(), generation).await {
+            warn!("Could not set up data connection to {}: {}", addr, err);
+            return Ok(Reply::new(ReplyCode::CantOpenDataConnection, "Can't open data connection"));
+        }
+        Ok(Reply::new(ReplyCode::CommandOkay, "Data port set"))
     }
 }
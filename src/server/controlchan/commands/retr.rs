@@ -21,6 +21,7 @@ use crate::{
 use async_trait::async_trait;
 use futures::prelude::*;
 use log::warn;
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Retr;
@@ -39,11 +40,15 @@ where
         let cmd: Command = args.cmd.clone();
         match session.data_cmd_tx.take() {
             Some(mut tx) => {
-                tokio::spawn(async move {
-                    if let Err(err) = tx.send(cmd).await {
-                        warn!("{}", err);
+                session.data_busy = true;
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = tx.send(cmd).await {
+                            warn!("{}", err);
+                        }
                     }
-                });
+                    .in_current_span(),
+                );
                 Ok(Reply::new(ReplyCode::FileStatusOkay, "Sending data"))
             }
             None => Err(ControlChanErrorKind::InternalServerError.into()),
@@ -14,6 +14,7 @@ use async_trait::async_trait;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
 use std::{path::PathBuf, sync::Arc};
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Size {
@@ -44,26 +45,29 @@ where
         let mut tx_success: Sender<InternalMsg> = args.tx.clone();
         let mut tx_fail: Sender<InternalMsg> = args.tx.clone();
 
-        tokio::spawn(async move {
-            match storage.metadata(&user, &path).await {
-                Ok(metadata) => {
-                    if let Err(err) = tx_success
-                        .send(InternalMsg::CommandChannelReply(
-                            ReplyCode::FileStatus,
-                            (metadata.len() - start_pos).to_string(),
-                        ))
-                        .await
-                    {
-                        warn!("{}", err);
+        tokio::spawn(
+            async move {
+                match storage.metadata(&user, &path).await {
+                    Ok(metadata) => {
+                        if let Err(err) = tx_success
+                            .send(InternalMsg::CommandChannelReply(
+                                ReplyCode::FileStatus,
+                                (metadata.len() - start_pos).to_string(),
+                            ))
+                            .await
+                        {
+                            warn!("{}", err);
+                        }
                     }
-                }
-                Err(err) => {
-                    if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
-                        warn!("{}", err);
+                    Err(err) => {
+                        if let Err(err) = tx_fail.send(InternalMsg::StorageError(err)).await {
+                            warn!("{}", err);
+                        }
                     }
                 }
             }
-        });
+            .in_current_span(),
+        );
         Ok(Reply::none())
     }
 }
@@ -34,6 +34,7 @@ use bytes::Bytes;
 use futures::{channel::mpsc::Sender, prelude::*};
 use log::warn;
 use std::{io::Read, sync::Arc};
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct Stat {
@@ -73,26 +74,29 @@ where
                 let mut tx_success: Sender<InternalMsg> = args.tx.clone();
                 let mut tx_fail: Sender<InternalMsg> = args.tx.clone();
 
-                tokio::spawn(async move {
-                    match storage.list_fmt(&user, path).await {
-                        Ok(mut cursor) => {
-                            let mut result: String = String::new();
-                            match cursor.read_to_string(&mut result) {
-                                Ok(_) => {
-                                    if let Err(err) = tx_success.send(InternalMsg::CommandChannelReply(ReplyCode::CommandOkay, result)).await {
-                                        warn!("{}", err);
+                tokio::spawn(
+                    async move {
+                        match storage.list_fmt(&user, path).await {
+                            Ok(mut cursor) => {
+                                let mut result: String = String::new();
+                                match cursor.read_to_string(&mut result) {
+                                    Ok(_) => {
+                                        if let Err(err) = tx_success.send(InternalMsg::CommandChannelReply(ReplyCode::CommandOkay, result)).await {
+                                            warn!("{}", err);
+                                        }
                                     }
+                                    Err(err) => warn!("{}", err),
                                 }
-                                Err(err) => warn!("{}", err),
                             }
-                        }
-                        Err(_) => {
-                            if let Err(err) = tx_fail.send(InternalMsg::StorageError(Error::from(ErrorKind::LocalError))).await {
-                                warn!("{}", err);
+                            Err(_) => {
+                                if let Err(err) = tx_fail.send(InternalMsg::StorageError(Error::from(ErrorKind::LocalError))).await {
+                                    warn!("{}", err);
+                                }
                             }
                         }
                     }
-                });
+                    .in_current_span(),
+                );
                 Ok(Reply::none())
             }
         }
@@ -14,6 +14,7 @@ use async_trait::async_trait;
 use futures::prelude::*;
 use log::warn;
 use std::path::Path;
+use tracing_futures::Instrument;
 use uuid::Uuid;
 
 // TODO: Write functional test for STOU command.
@@ -36,11 +37,15 @@ where
         let path: String = session.cwd.join(&filename).to_string_lossy().to_string();
         match session.data_cmd_tx.take() {
             Some(mut tx) => {
-                tokio::spawn(async move {
-                    if let Err(err) = tx.send(Command::Stor { path }).await {
-                        warn!("sending command failed. {}", err);
+                session.data_busy = true;
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = tx.send(Command::Stor { path }).await {
+                            warn!("sending command failed. {}", err);
+                        }
                     }
-                });
+                    .in_current_span(),
+                );
                 Ok(Reply::new_with_string(ReplyCode::FileStatusOkay, filename.to_string_lossy().to_string()))
             }
             None => Ok(Reply::new(ReplyCode::CantOpenDataConnection, "No data connection established")),
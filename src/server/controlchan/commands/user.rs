@@ -1,17 +1,23 @@
+use super::pass::log_in;
 use crate::{
-    auth::UserDetail,
+    auth::{Credentials, UserDetail},
     server::{
+        chancomms::InternalMsg,
         controlchan::{
             error::ControlChanError,
             handler::{CommandContext, CommandHandler},
             Reply, ReplyCode,
         },
+        failed_logins::LockoutKey,
         session::SessionState,
     },
     storage::{Metadata, StorageBackend},
 };
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures::{channel::mpsc::Sender, prelude::*};
+use log::warn;
+use tracing_futures::Instrument;
 
 #[derive(Debug)]
 pub struct User {
@@ -27,7 +33,7 @@ impl User {
 #[async_trait]
 impl<S, U> CommandHandler<S, U> for User
 where
-    U: UserDetail,
+    U: UserDetail + 'static,
     S: StorageBackend<U> + 'static,
     S::File: tokio::io::AsyncRead + Send,
     S::Metadata: Metadata,
@@ -37,10 +43,58 @@ where
         let mut session = args.session.lock().await;
         match session.state {
             SessionState::New | SessionState::WaitPass => {
+                if session.ftps_required.requires_control() && !session.cmd_tls {
+                    return Ok(Reply::new(ReplyCode::FtpsRequired, "A TLS connection is required on the control channel"));
+                }
                 let user = std::str::from_utf8(&self.username)?;
                 session.username = Some(user.to_string());
                 session.state = SessionState::WaitPass;
-                Ok(Reply::new(ReplyCode::NeedPassword, "Password Required"))
+                let certificate = match session.client_cert.clone() {
+                    Some(certificate) => certificate,
+                    None => return Ok(Reply::new(ReplyCode::NeedPassword, "Password Required")),
+                };
+                if let Some(failed_logins) = &session.failed_logins {
+                    if failed_logins.is_blocked(&LockoutKey::Ip(session.source.ip())) || failed_logins.is_blocked(&LockoutKey::User(user.to_string())) {
+                        return Ok(Reply::new(ReplyCode::NeedPassword, "Password Required"));
+                    }
+                }
+
+                // The client presented a certificate, see if that is enough to log in. Spawned for the
+                // same reason as the authentication in PASS. PASS is refused until this is decided.
+                session.state = SessionState::WaitCertAuth;
+                let username = user.to_string();
+                let credentials = Credentials {
+                    password: None,
+                    certificate: Some(certificate),
+                };
+                let failed_logins = session.failed_logins.clone();
+                let auther = args.authenticator.clone();
+                let shared_session = args.session.clone();
+                let mut tx: Sender<InternalMsg> = args.tx.clone();
+                tokio::spawn(
+                    async move {
+                        let result = auther.authenticate_with_credentials(&username, &credentials).await;
+                        let mut session = shared_session.lock().await;
+                        let msg = match result {
+                            Ok(user) => log_in(&mut session, &username, user),
+                            Err(_) => InternalMsg::CommandChannelReply(ReplyCode::NeedPassword, "Password Required".to_string()),
+                        };
+                        match msg {
+                            // The control loop moves the session on once it handles the message.
+                            InternalMsg::AuthSuccess => {}
+                            _ => session.state = SessionState::WaitPass,
+                        }
+                        drop(session);
+                        if let (InternalMsg::AuthSuccess, Some(failed_logins)) = (&msg, failed_logins) {
+                            failed_logins.record_success(&username);
+                        }
+                        if let Err(err) = tx.send(msg).await {
+                            warn!("{}", err);
+                        }
+                    }
+                    .in_current_span(),
+                );
+                Ok(Reply::none())
             }
             _ => Ok(Reply::new(ReplyCode::BadCommandSequence, "Please create a new connection to switch user")),
         }
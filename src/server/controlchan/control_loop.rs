@@ -1,6 +1,7 @@
 use crate::{
     auth::{Authenticator, UserDetail},
-    metrics::{add_error_metric, add_event_metric, add_reply_metric},
+    metrics::{Metrics, TlsChannel, UserLabels},
+    options,
     server::{
         chancomms::{InternalMsg, ProxyLoopSender},
         controlchan::{
@@ -11,9 +12,13 @@ use crate::{
             handler::{CommandContext, CommandHandler},
             Reply, ReplyCode,
         },
+        failed_logins::FailedLogins,
         proxy_protocol::ConnectionTuple,
+        registry::SessionRegistry,
         session::SharedSession,
-        tls::FTPSConfig,
+        session_limits, shutdown,
+        throttle::Buckets,
+        tls::{self, FTPSConfig},
         Event, Session, SessionState,
     },
     storage::{ErrorKind, Metadata, StorageBackend},
@@ -24,13 +29,13 @@ use futures::{
     SinkExt, StreamExt,
 };
 use log::{info, warn};
-use std::{convert::TryInto, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
+use std::{net::SocketAddr, ops::Range, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
     sync::Mutex,
 };
 use tokio_util::codec::{Decoder, Framed};
+use tracing_futures::Instrument;
 
 trait AsyncReadAsyncWriteSendUnpin: AsyncRead + AsyncWrite + Send + Unpin {}
 
@@ -46,63 +51,147 @@ where
     pub greeting: &'static str,
     pub authenticator: Arc<dyn Authenticator<U>>,
     pub passive_ports: Range<u16>,
+    pub passive_host: options::PassiveHost,
     pub ftps_config: FTPSConfig,
-    pub collect_metrics: bool,
+    pub ftps_implicit: bool,
+    pub ftps_required: options::FtpsRequired,
+    pub metrics: Option<Arc<Metrics>>,
+    pub user_labels: Option<Arc<UserLabels>>,
     pub idle_session_timeout: Duration,
+    pub active_mode: Option<options::ActiveMode>,
+    // The tag that the connection filter attached to this particular connection, if any.
+    pub tag: Option<String>,
+    pub failed_logins: Option<Arc<FailedLogins>>,
+    pub global_buckets: Buckets,
+    pub session_rate_limits: options::RateLimits,
+    pub data_timeouts: options::DataTimeouts,
+    pub sessions: SessionRegistry,
 }
 
-/// Does TCP processing when a FTP client connects
-#[tracing_attributes::instrument]
-pub async fn spawn<S, U>(
+/// Does TCP processing when a FTP client connects. The given stream is the control connection,
+/// `local_addr` the address it was accepted on and `peer_addr` the address of the client. The
+/// session holds on to `permit` until it ends.
+#[allow(clippy::too_many_arguments)]
+#[tracing_attributes::instrument(skip(stream))]
+pub async fn spawn<S, U, IO>(
     config: Config<S, U>,
-    tcp_stream: TcpStream,
+    stream: IO,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
     control_connection_info: Option<ConnectionTuple>,
     proxyloop_msg_tx: Option<ProxyLoopSender<S, U>>,
+    mut shutdown: shutdown::Listener,
+    permit: session_limits::Permit,
 ) -> Result<(), ControlChanError>
 where
     U: UserDetail + 'static,
     S: StorageBackend<U> + 'static,
     S::File: AsyncRead + Send,
     S::Metadata: Metadata,
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
     let Config {
         storage,
         authenticator,
         passive_ports,
+        passive_host,
         ftps_config,
-        collect_metrics,
+        ftps_implicit,
+        ftps_required,
+        metrics,
+        user_labels,
         idle_session_timeout,
+        active_mode,
+        tag,
+        failed_logins,
+        global_buckets,
+        session_rate_limits,
+        data_timeouts,
+        sessions,
         ..
     } = config;
 
+    // In implicit FTPS mode the TLS handshake takes place before anything else is sent. Clients
+    // get as long for it as they may stay idle once the session started.
+    let mut client_cert = None;
+    let stream: Box<dyn AsyncReadAsyncWriteSendUnpin> = if ftps_implicit {
+        let acceptor = ftps_config
+            .acceptor()
+            .map_err(|_| ControlChanError::new(ControlChanErrorKind::InternalServerError))?;
+        let handshake = tokio::time::timeout(idle_session_timeout, acceptor.accept(stream)).await;
+        if let Ok(Ok(stream)) = handshake {
+            client_cert = tls::client_cert(&stream);
+            Box::new(stream)
+        } else {
+            if let Some(metrics) = &metrics {
+                metrics.add_tls_handshake_failure_metric(TlsChannel::Control);
+            }
+            return Err(match handshake {
+                Ok(Err(err)) => err.into(),
+                _ => ControlChanError::new(ControlChanErrorKind::ControlChannelTimeout),
+            });
+        }
+    } else {
+        Box::new(stream)
+    };
+
     let tls_configured = if let FTPSConfig::On { .. } = ftps_config { true } else { false };
     let storage_features = storage.supported_features();
     let (control_msg_tx, control_msg_rx): (Sender<InternalMsg>, Receiver<InternalMsg>) = channel(1);
-    let session: Session<S, U> = Session::new(Arc::new(storage))
+    let mut session: Session<S, U> = Session::new(Arc::new(storage), peer_addr)
         .ftps(ftps_config.clone())
-        .metrics(config.collect_metrics)
+        .metrics(metrics.clone())
         .control_msg_tx(control_msg_tx.clone())
         .control_connection_info(control_connection_info);
+    // Both channels are protected from the start in implicit mode, as if AUTH TLS and PROT P were given.
+    session.cmd_tls = ftps_implicit;
+    session.data_tls = ftps_implicit;
+    session.client_cert = client_cert;
+    session.ftps_required = ftps_required;
+    if let Some(tag) = &tag {
+        info!("Session for {:?} tagged as {:?}", peer_addr, tag);
+    }
+    session.tag = tag;
+    session.failed_logins = failed_logins;
+    session.user_labels = user_labels;
+    session.global_buckets = global_buckets;
+    session.session_buckets = Buckets::new(session_rate_limits);
+    session.data_timeouts = data_timeouts;
+    let end_tx = session.end_tx.take();
+    let (registration, mut disconnect_rx) = sessions.register(peer_addr, session.tag.clone());
+    session.record = Some(registration.record());
+    // The root span of the session. Its id is the one that ServerHandle::sessions lists and the
+    // user name is filled in once the client logged in.
+    let span = tracing::info_span!(
+        parent: None,
+        "session",
+        id = registration.record().id(),
+        peer = %peer_addr,
+        username = tracing::field::Empty
+    );
+    session.span = span.clone();
 
     let shared_session: SharedSession<S, U> = Arc::new(Mutex::new(session));
-    let local_addr = tcp_stream.local_addr().unwrap();
 
     let event_handler_chain = handle_event::<S, U>(
         shared_session.clone(),
         authenticator,
         tls_configured,
         passive_ports,
+        passive_host,
+        active_mode,
         control_msg_tx,
         local_addr,
         storage_features,
         proxyloop_msg_tx,
         control_connection_info,
     );
-    let event_handler_chain = handle_with_auth::<S, U, _>(shared_session, event_handler_chain);
+    let event_handler_chain = handle_with_data_protection::<S, U, _>(shared_session.clone(), event_handler_chain);
+    let event_handler_chain = handle_with_auth::<S, U, _>(shared_session.clone(), event_handler_chain);
     let event_handler_chain = handle_with_logging::<S, U, _>(event_handler_chain);
 
     let codec = FTPCodec::new();
-    let cmd_and_reply_stream: Framed<Box<dyn AsyncReadAsyncWriteSendUnpin>, FTPCodec> = codec.framed(Box::new(tcp_stream));
+    let cmd_and_reply_stream: Framed<Box<dyn AsyncReadAsyncWriteSendUnpin>, FTPCodec> = codec.framed(stream);
     let (mut reply_sink, command_source) = cmd_and_reply_stream.split();
 
     reply_sink.send(Reply::new(ReplyCode::ServiceReady, config.greeting)).await?;
@@ -111,14 +200,23 @@ where
     let mut command_source = command_source.fuse();
     let mut control_msg_rx = control_msg_rx.fuse();
 
-    tokio::spawn(async move {
+    let control_loop = async move {
+        let _permit = permit;
+        // Tears down the data connections of the session when this task exits.
+        let _end_tx = end_tx;
+        let _registration = registration;
+        // Set when the server is shutting down but we still have to wait for a data transfer to finish.
+        let mut shutdown_pending = false;
         // The control channel event loop
         loop {
             #[allow(unused_assignments)]
             let mut incoming = None;
             let mut timeout_delay = tokio::time::delay_for(idle_session_timeout);
+            // Commands that the client sent right after USER wait until the certificate check
+            // decided whether a password is still needed.
+            let read_commands = shared_session.lock().await.state != SessionState::WaitCertAuth;
             tokio::select! {
-                Some(cmd_result) = command_source.next() => {
+                Some(cmd_result) = command_source.next(), if read_commands => {
                     incoming = Some(cmd_result.map(Event::Command));
                 },
                 Some(msg) = control_msg_rx.next() => {
@@ -127,18 +225,32 @@ where
                 _ = &mut timeout_delay => {
                     info!("Control connection timed out");
                     incoming = Some(Err(ControlChanError::new(ControlChanErrorKind::ControlChannelTimeout)));
+                },
+                _ = shutdown.listen(), if !shutdown_pending => {
+                    info!("Server shutdown signal received");
+                    shutdown_pending = true;
+                },
+                Ok(()) = &mut disconnect_rx => {
+                    info!("Disconnecting session on request of the administrator");
+                    if reply_sink.send(Reply::new(ReplyCode::ServiceNotAvailable, "Disconnected by the administrator")).await.is_err() {
+                        warn!("Could not send disconnect reply to client");
+                    }
+                    return;
                 }
             };
 
             match incoming {
+                None if shutdown_pending => {
+                    // Nothing to handle, we'll close the connection below if the session is idle.
+                }
                 None => {
                     // Should not happen.
                     warn!("No event polled in control channel...");
                     return;
                 }
                 Some(Ok(event)) => {
-                    if collect_metrics {
-                        add_event_metric(&event);
+                    if let Some(metrics) = &metrics {
+                        metrics.add_event_metric(&event);
                     };
 
                     if let Event::InternalMsg(InternalMsg::Quit) = event {
@@ -146,26 +258,6 @@ where
                         return;
                     }
 
-                    if let Event::InternalMsg(InternalMsg::SecureControlChannel) = event {
-                        info!("Upgrading to TLS");
-
-                        // Get back the original TCP Stream
-                        let codec_io = reply_sink.reunite(command_source.into_inner()).unwrap();
-                        let io = codec_io.into_inner();
-
-                        // Wrap in TLS Stream
-                        let acceptor: tokio_rustls::TlsAcceptor = ftps_config.clone().try_into().unwrap(); // unwrap because we can't be in upgrading to TLS if it was never configured.
-                        let io: Box<dyn AsyncReadAsyncWriteSendUnpin> = Box::new(acceptor.accept(io).await.unwrap());
-
-                        // Wrap in codec again and get sink + source
-                        let codec = FTPCodec::new();
-                        let cmd_and_reply_stream = codec.framed(io);
-                        let (sink, src) = cmd_and_reply_stream.split();
-                        let src = src.fuse();
-                        reply_sink = sink;
-                        command_source = src;
-                    }
-
                     // TODO: Handle Event::InternalMsg(InternalMsg::PlaintextControlChannel)
 
                     match event_handler_chain(event) {
@@ -174,19 +266,71 @@ where
                             return;
                         }
                         Ok(reply) => {
-                            if collect_metrics {
-                                add_reply_metric(&reply);
+                            if let Some(metrics) = &metrics {
+                                metrics.add_reply_metric(&reply);
+                            }
+                            // The client starts the TLS handshake right after our reply to AUTH, so we have to
+                            // upgrade before reading anything else from the connection.
+                            let mut upgrade_to_tls = false;
+                            if let Reply::CodeAndMsg {
+                                code: ReplyCode::AuthOkayNoDataNeeded,
+                                ..
+                            } = reply
+                            {
+                                upgrade_to_tls = true;
                             }
                             let result = reply_sink.send(reply).await;
                             if result.is_err() {
                                 warn!("Could not send reply to client");
                                 return;
                             }
+                            if upgrade_to_tls {
+                                info!("Upgrading to TLS");
+
+                                // Get back the original TCP Stream
+                                let codec_io = reply_sink.reunite(command_source.into_inner()).unwrap();
+                                let io = codec_io.into_inner();
+
+                                // Wrap in TLS Stream
+                                let acceptor = ftps_config.acceptor().unwrap(); // unwrap because we can't be in upgrading to TLS if it was never configured.
+                                let io: Box<dyn AsyncReadAsyncWriteSendUnpin> = match acceptor.accept(io).await {
+                                    Ok(io) => {
+                                        shared_session.lock().await.client_cert = tls::client_cert(&io);
+                                        Box::new(io)
+                                    }
+                                    Err(err) => {
+                                        warn!("TLS handshake on the control channel failed: {}", err);
+                                        if let Some(metrics) = &metrics {
+                                            metrics.add_tls_handshake_failure_metric(TlsChannel::Control);
+                                        }
+                                        return;
+                                    }
+                                };
+
+                                // Wrap in codec again and get sink + source
+                                let codec = FTPCodec::new();
+                                let cmd_and_reply_stream = codec.framed(io);
+                                let (sink, src) = cmd_and_reply_stream.split();
+                                let src = src.fuse();
+                                reply_sink = sink;
+                                command_source = src;
+
+                                if let Err(e) = event_handler_chain(Event::InternalMsg(InternalMsg::SecureControlChannel)) {
+                                    warn!("Event handler chain error: {:?}", e);
+                                    return;
+                                }
+                            }
+                            if let Some(reply) = abort_reply(&shared_session).await {
+                                if reply_sink.send(reply).await.is_err() {
+                                    warn!("Could not send reply to client");
+                                    return;
+                                }
+                            }
                         }
                     }
                 }
                 Some(Err(e)) => {
-                    let reply = handle_control_channel_error::<S, U>(e, collect_metrics);
+                    let reply = handle_control_channel_error::<S, U>(e, &metrics);
                     let mut close_connection = false;
                     if let Reply::CodeAndMsg {
                         code: ReplyCode::ClosingControlConnection,
@@ -205,12 +349,44 @@ where
                     }
                 }
             }
+
+            if shutdown_pending && !shared_session.lock().await.data_busy {
+                info!("Closing control connection because the server is shutting down");
+                let result = reply_sink
+                    .send(Reply::new(
+                        ReplyCode::ServiceNotAvailable,
+                        "Server is shutting down. Closing control connection",
+                    ))
+                    .await;
+                if result.is_err() {
+                    warn!("Could not send shutdown reply to client");
+                }
+                return;
+            }
         }
-    });
+    };
+    tokio::spawn(control_loop.instrument(span));
 
     Ok(())
 }
 
+// Returns the reply to an ABOR command that was received during a transfer, once that transfer
+// ended and its own reply was sent.
+async fn abort_reply<S, U>(session: &SharedSession<S, U>) -> Option<Reply>
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    let mut session = session.lock().await;
+    if !session.abort_pending || session.data_busy {
+        return None;
+    }
+    session.abort_pending = false;
+    Some(Reply::new(ReplyCode::ClosingDataConnection, "Closed data channel"))
+}
+
 fn handle_with_auth<S, U, N>(session: SharedSession<S, U>, next: N) -> impl Fn(Event) -> Result<Reply, ControlChanError>
 where
     U: UserDetail + 'static,
@@ -245,6 +421,37 @@ where
     }
 }
 
+// Refuses transfers over a data channel without TLS when the server or the user requires it there.
+fn handle_with_data_protection<S, U, N>(session: SharedSession<S, U>, next: N) -> impl Fn(Event) -> Result<Reply, ControlChanError>
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: AsyncRead + Send,
+    S::Metadata: Metadata,
+    N: Fn(Event) -> Result<Reply, ControlChanError>,
+{
+    move |event| match event {
+        Event::Command(Command::Retr { .. })
+        | Event::Command(Command::Stor { .. })
+        | Event::Command(Command::Stou)
+        | Event::Command(Command::List { .. })
+        | Event::Command(Command::Nlst { .. }) => {
+            let refused = block_on(async {
+                let session = session.lock().await;
+                session.ftps_required.requires_data() && !session.data_tls
+            });
+            if refused {
+                return Ok(Reply::new(
+                    ReplyCode::DataProtectionRequired,
+                    "A TLS connection is required on the data channel, use PROT P",
+                ));
+            }
+            next(event)
+        }
+        _ => next(event),
+    }
+}
+
 fn handle_with_logging<S, U, N>(next: N) -> impl Fn(Event) -> Result<Reply, ControlChanError>
 where
     U: UserDetail + 'static,
@@ -265,6 +472,8 @@ fn handle_event<S, U>(
     authenticator: Arc<dyn Authenticator<U>>,
     tls_configured: bool,
     passive_ports: Range<u16>,
+    passive_host: options::PassiveHost,
+    active_mode: Option<options::ActiveMode>,
     tx: Sender<InternalMsg>,
     local_addr: SocketAddr,
     storage_features: u32,
@@ -285,6 +494,8 @@ where
                 authenticator.clone(),
                 tls_configured,
                 passive_ports.clone(),
+                passive_host.clone(),
+                active_mode.clone(),
                 tx.clone(),
                 local_addr,
                 storage_features,
@@ -304,6 +515,8 @@ async fn handle_command<S, U>(
     authenticator: Arc<dyn Authenticator<U>>,
     tls_configured: bool,
     passive_ports: Range<u16>,
+    passive_host: options::PassiveHost,
+    active_mode: Option<options::ActiveMode>,
     tx: Sender<InternalMsg>,
     local_addr: SocketAddr,
     storage_features: u32,
@@ -322,6 +535,8 @@ where
         authenticator,
         tls_configured,
         passive_ports,
+        passive_host,
+        active_mode,
         tx,
         local_addr,
         storage_features,
@@ -341,7 +556,9 @@ where
         Command::Help => Box::new(commands::Help),
         Command::Noop => Box::new(commands::Noop),
         Command::Pasv => Box::new(commands::Pasv::new()),
-        Command::Port => Box::new(commands::Port),
+        Command::Port { addr } => Box::new(commands::Port::new(addr)),
+        Command::Epsv { param } => Box::new(commands::Epsv::new(param)),
+        Command::Eprt { addr } => Box::new(commands::Eprt::new(addr)),
         Command::Retr { .. } => Box::new(commands::Retr),
         Command::Stor { .. } => Box::new(commands::Stor),
         Command::List { .. } => Box::new(commands::List),
@@ -383,19 +600,36 @@ where
     use self::InternalMsg::*;
     use SessionState::*;
 
+    // These messages signal the end of a data transfer.
+    match msg {
+        SendData { .. }
+        | WrittenData { .. }
+        | WriteFailed
+        | ConnectionReset
+        | DirectorySuccessfullyListed
+        | DirectoryListFailure
+        | StorageError(_)
+        | TransferTimedOut => {
+            session.lock().await.data_busy = false;
+        }
+        _ => {}
+    }
+
     match msg {
         NotFound => Ok(Reply::new(ReplyCode::FileError, "File not found")),
         PermissionDenied => Ok(Reply::new(ReplyCode::FileError, "Permision denied")),
-        SendData { .. } => {
+        SendData { bytes } => {
             let mut session = session.lock().await;
             session.start_pos = 0;
+            session.update_info(|info| info.bytes_sent += bytes as u64);
             Ok(Reply::new(ReplyCode::ClosingDataConnection, "Successfully sent"))
         }
         WriteFailed => Ok(Reply::new(ReplyCode::TransientFileError, "Failed to write file")),
         ConnectionReset => Ok(Reply::new(ReplyCode::ConnectionClosed, "Datachannel unexpectedly closed")),
-        WrittenData { .. } => {
+        WrittenData { bytes } => {
             let mut session = session.lock().await;
             session.start_pos = 0;
+            session.update_info(|info| info.bytes_received += bytes as u64);
             Ok(Reply::new(ReplyCode::ClosingDataConnection, "File successfully written"))
         }
         DataConnectionClosedAfterStor => Ok(Reply::new(ReplyCode::FileActionOkay, "unFTP holds your data for you")),
@@ -437,18 +671,62 @@ where
             ErrorKind::PermissionDenied => Ok(Reply::new(ReplyCode::FileError, "Permission denied")),
         },
         CommandChannelReply(reply_code, message) => Ok(Reply::new(reply_code, &message)),
+        DataConnectionTimedOut(generation) => Ok(forget_data_connection(
+            &mut *session.lock().await,
+            generation,
+            "No data connection established in time",
+        )),
+        DataConnectionFailed(generation) => Ok(forget_data_connection(&mut *session.lock().await, generation, "Can't open data connection")),
+        TransferTimedOut => Ok(Reply::new(ReplyCode::ConnectionClosed, "Transfer aborted, no data moved in time")),
+        TransferAborted => {
+            let mut session = session.lock().await;
+            session.start_pos = 0;
+            // There is nothing to reply if the data connection was closed before a transfer started.
+            if session.data_busy {
+                session.data_busy = false;
+                Ok(Reply::new(ReplyCode::ConnectionClosed, "Connection closed; transfer aborted"))
+            } else {
+                Ok(Reply::none())
+            }
+        }
+    }
+}
+
+// Forgets the channels of a data connection that could not be set up so that transfer commands are
+// refused instead of waiting for it forever. Returns the reply for the client, which only expects
+// one if it is waiting for a transfer to start.
+fn forget_data_connection<S, U>(session: &mut Session<S, U>, generation: u64, msg: &str) -> Reply
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    // A newer data connection took the place of the failed one and is none of our business.
+    if session.data_generation != generation {
+        return Reply::none();
+    }
+    session.data_cmd_tx = None;
+    session.data_cmd_rx = None;
+    session.data_abort_tx = None;
+    session.data_abort_rx = None;
+    if session.data_busy {
+        session.data_busy = false;
+        Reply::new(ReplyCode::CantOpenDataConnection, msg)
+    } else {
+        Reply::none()
     }
 }
 
-fn handle_control_channel_error<S, U>(error: ControlChanError, with_metrics: bool) -> Reply
+fn handle_control_channel_error<S, U>(error: ControlChanError, metrics: &Option<Arc<Metrics>>) -> Reply
 where
     U: UserDetail + 'static,
     S: StorageBackend<U> + 'static,
     S::File: AsyncRead + Send,
     S::Metadata: Metadata,
 {
-    if with_metrics {
-        add_error_metric(&error.kind());
+    if let Some(metrics) = metrics {
+        metrics.add_error_metric(&error.kind());
     };
     warn!("Control channel error: {}", error);
     match error.kind() {
@@ -1,6 +1,7 @@
 use super::error::ControlChanError;
 use crate::{
     auth::{Authenticator, UserDetail},
+    options,
     server::{
         chancomms::ProxyLoopSender,
         controlchan::{Command, Reply},
@@ -39,6 +40,8 @@ where
     pub authenticator: Arc<dyn Authenticator<U>>,
     pub tls_configured: bool,
     pub passive_ports: Range<u16>,
+    pub passive_host: options::PassiveHost,
+    pub active_mode: Option<options::ActiveMode>,
     pub tx: Sender<InternalMsg>,
     pub local_addr: std::net::SocketAddr,
     pub storage_features: u32,
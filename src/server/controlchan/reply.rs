@@ -85,6 +85,8 @@ pub enum ReplyCode {
     CommandNotImplemented = 502,
     BadCommandSequence = 503,
     CommandNotImplementedForParameter = 504,
+    DataProtectionRequired = 521,
+    NetworkProtocolNotSupported = 522,
     NotLoggedIn = 530,
     NeedAccountToStore = 532,
     FileError = 550,
@@ -93,6 +95,7 @@ pub enum ReplyCode {
     BadFileName = 553,
 
     Resp533 = 533,
+    FtpsRequired = 534,
 }
 
 impl Reply {
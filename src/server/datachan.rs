@@ -3,18 +3,49 @@
 use super::{
     chancomms::{DataCommand, InternalMsg},
     controlchan::command::Command,
+    inactivity::Inactivity,
+    throttle::{SharedBucket, Throttled},
     tls::FTPSConfig,
 };
 use crate::{
     auth::UserDetail,
-    server::{tls::new_config, Session},
+    metrics::{Metrics, TlsChannel, TransferKind},
+    server::{
+        handle::TransferInfo,
+        registry::SessionRecord,
+        session::{SessionEnd, SharedSession},
+        Session,
+    },
     storage::{Error, ErrorKind, Metadata, StorageBackend},
 };
-use futures::{channel::mpsc::Sender, prelude::*};
+use failure::Fail;
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    prelude::*,
+    stream::Fuse,
+};
 use log::{debug, error, info, warn};
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::io::AsyncWriteExt;
-use tokio_rustls::TlsAcceptor;
+use tracing_futures::Instrument;
+
+// The reasons why a data connection cannot be processed.
+#[derive(Debug, Fail)]
+pub enum DataChanError {
+    // A newer PASV, EPSV, PORT or EPRT command replaced the one that the connection belongs to.
+    #[fail(display = "The data connection was replaced by a newer one")]
+    Replaced,
+    // The data connection of this generation is being processed already.
+    #[fail(display = "The data connection is in use already")]
+    InUse,
+}
 
 #[derive(Debug)]
 pub struct DataCommandExecutor<S, U>
@@ -31,6 +62,19 @@ where
     pub cwd: PathBuf,
     pub start_pos: u64,
     pub ftps_mode: FTPSConfig,
+    // The token buckets that RETR and STOR transfers are throttled with, respectively.
+    pub download_buckets: Vec<SharedBucket>,
+    pub upload_buckets: Vec<SharedBucket>,
+    // The transfer is aborted when no data moved for this long.
+    pub inactivity_timeout: Duration,
+    // Running transfers are aborted when this resolves, uploads after the grace period.
+    pub session_end: SessionEnd,
+    pub upload_grace_period: Duration,
+    // Tells the session registry which transfer is running.
+    pub record: Option<SessionRecord>,
+    pub metrics: Option<Arc<Metrics>>,
+    // The user label for the transfer metrics, when per-user metrics are enabled.
+    pub metrics_user: Option<String>,
 }
 
 impl<S, U: Send + Sync + 'static> DataCommandExecutor<S, U>
@@ -41,200 +85,365 @@ where
     U: UserDetail,
 {
     #[tracing_attributes::instrument]
-    pub async fn execute(self, cmd: Command) {
-        match cmd {
-            Command::Retr { path } => {
-                self.exec_retr(path).await;
+    pub async fn execute(self, cmd: Command, abort_rx: Fuse<Receiver<()>>) {
+        let record = self.record.clone();
+        if let Some(record) = &record {
+            let path = match &cmd {
+                Command::Retr { path } | Command::Stor { path } => Some(path),
+                Command::List { path, .. } | Command::Nlst { path } => path.as_ref(),
+                _ => None,
+            };
+            let transfer = TransferInfo {
+                command: cmd.name().to_uppercase(),
+                path: path.map(|path| self.cwd.join(path)).unwrap_or_else(|| self.cwd.clone()),
+                started_at: SystemTime::now(),
+            };
+            record.update(|info| info.transfer = Some(transfer));
+        }
+        let kind = TransferKind::of(&cmd);
+        let metrics_user = self.metrics_user.clone();
+        let metrics = self.metrics.clone();
+        let _active = metrics.as_ref().map(|metrics| metrics.start_transfer());
+        let started = Instant::now();
+        let bytes = self.run(cmd, abort_rx).await;
+        if let (Some(metrics), Some(kind), Some(bytes)) = (metrics, kind, bytes) {
+            metrics.add_transfer_metric(kind, started.elapsed(), bytes, metrics_user.as_deref());
+        }
+        if let Some(record) = record {
+            record.update(|info| info.transfer = None);
+        }
+    }
+
+    // Runs the given command, unless the client aborts it first through the given channel or the
+    // session ends. The data connection is closed when that happens. Returns the number of bytes
+    // transferred if the command completed successfully.
+    async fn run(self, cmd: Command, mut abort_rx: Fuse<Receiver<()>>) -> Option<u64> {
+        let storage = Arc::clone(&self.storage);
+        let user = Arc::clone(&self.user);
+        let mut tx = self.control_msg_tx.clone();
+        let session_end = self.session_end.clone();
+        let upload_grace_period = self.upload_grace_period;
+        let upload_path = match &cmd {
+            Command::Stor { path } => Some(self.cwd.join(path)),
+            _ => None,
+        };
+        let transfer = async move {
+            match cmd {
+                Command::Retr { path } => self.exec_retr(path).await,
+                Command::Stor { path } => self.exec_stor(path).await,
+                Command::List { path, .. } => self.exec_list(path).await,
+                Command::Nlst { path } => self.exec_nlst(path).await,
+                _ => unimplemented!(),
             }
-            Command::Stor { path } => {
-                self.exec_stor(path).await;
+        };
+        let mut transfer = Box::pin(transfer);
+        let aborted_by_client = tokio::select! {
+            bytes = &mut transfer => return bytes,
+            Some(_) = abort_rx.next() => {
+                info!("Transfer aborted by the client");
+                true
+            },
+            _ = session_end => {
+                if upload_path.is_some() && upload_grace_period > Duration::from_secs(0) {
+                    info!("Control connection closed, allowing the upload {:?} to finish", upload_grace_period);
+                    if let Ok(bytes) = tokio::time::timeout(upload_grace_period, &mut transfer).await {
+                        return bytes;
+                    }
+                }
+                info!("Control connection closed, aborting transfer");
+                false
             }
-            Command::List { path, .. } => {
-                self.exec_list(path).await;
+        };
+        // Closes the data connection.
+        drop(transfer);
+        if let Some(path) = upload_path {
+            if let Err(err) = storage.put_aborted(&user, path).await {
+                warn!("Storage back-end failed to clean up after aborted STOR: {}", err);
             }
-            Command::Nlst { path } => {
-                self.exec_nlst(path).await;
+        }
+        if aborted_by_client {
+            if let Err(err) = tx.send(InternalMsg::TransferAborted).await {
+                warn!("Could not notify control channel of aborted transfer: {}", err);
             }
-            _ => unimplemented!(),
         }
+        None
     }
 
     #[tracing_attributes::instrument]
-    async fn exec_retr(self, path: String) {
+    async fn exec_retr(self, path: String) -> Option<u64> {
         let path = self.cwd.join(path);
         let mut tx_sending: Sender<InternalMsg> = self.control_msg_tx.clone();
         let mut tx_error: Sender<InternalMsg> = self.control_msg_tx.clone();
-        tokio::spawn(async move {
-            match self.storage.get(&self.user, path, self.start_pos).await {
-                Ok(mut f) => {
-                    let mut output = Self::writer(self.socket, self.ftps_mode);
-                    match tokio::io::copy(&mut f, &mut output).await {
-                        Ok(bytes_copied) => {
-                            if let Err(err) = output.shutdown().await {
-                                warn!("Could not shutdown output stream after RETR: {}", err);
-                            }
-                            if let Err(err) = tx_sending.send(InternalMsg::SendData { bytes: bytes_copied as i64 }).await {
-                                error!("Could not notify control channel of successful RETR: {}", err);
-                            }
+        let socket = Inactivity::new(self.socket, self.inactivity_timeout);
+        let timed_out = socket.timed_out();
+        match self.storage.get(&self.user, path, self.start_pos).await {
+            Ok(mut f) => {
+                let output = match Self::writer(socket, self.ftps_mode).await {
+                    Ok(output) => output,
+                    Err(err) => {
+                        report_tls_failure(tx_error, err, &self.metrics).await;
+                        return None;
+                    }
+                };
+                let mut output = Throttled::new(output, self.download_buckets);
+                match tokio::io::copy(&mut f, &mut output).await {
+                    Ok(bytes_copied) => {
+                        if let Err(err) = output.shutdown().await {
+                            warn!("Could not shutdown output stream after RETR: {}", err);
                         }
-                        Err(err) => warn!("Error copying streams during RETR: {}", err),
+                        if let Err(err) = tx_sending.send(InternalMsg::SendData { bytes: bytes_copied as i64 }).await {
+                            error!("Could not notify control channel of successful RETR: {}", err);
+                        }
+                        Some(bytes_copied)
                     }
-                }
-                Err(err) => {
-                    if let Err(err) = tx_error.send(InternalMsg::StorageError(err)).await {
-                        warn!("Could not notify control channel of error with RETR: {}", err);
+                    Err(err) => {
+                        warn!("Error copying streams during RETR: {}", err);
+                        report_send_failure(tx_error, &timed_out).await;
+                        None
                     }
                 }
             }
-        });
+            Err(err) => {
+                if let Err(err) = tx_error.send(InternalMsg::StorageError(err)).await {
+                    warn!("Could not notify control channel of error with RETR: {}", err);
+                }
+                None
+            }
+        }
     }
 
     #[tracing_attributes::instrument]
-    async fn exec_stor(self, path: String) {
+    async fn exec_stor(self, path: String) -> Option<u64> {
         let path = self.cwd.join(path);
         let mut tx_ok = self.control_msg_tx.clone();
         let mut tx_error = self.control_msg_tx.clone();
-        tokio::spawn(async move {
-            match self
-                .storage
-                .put(&self.user, Self::reader(self.socket, self.ftps_mode), path, self.start_pos)
-                .await
-            {
-                Ok(bytes) => {
-                    if let Err(err) = tx_ok.send(InternalMsg::WrittenData { bytes: bytes as i64 }).await {
-                        error!("Could not notify control channel of successful STOR: {}", err);
-                    }
+        let socket = Inactivity::new(self.socket, self.inactivity_timeout);
+        let timed_out = socket.timed_out();
+        let input = match Self::reader(socket, self.ftps_mode).await {
+            Ok(input) => input,
+            Err(err) => {
+                report_tls_failure(tx_error, err, &self.metrics).await;
+                return None;
+            }
+        };
+        match self
+            .storage
+            .put(&self.user, Throttled::new(input, self.upload_buckets), path, self.start_pos)
+            .await
+        {
+            Ok(bytes) => {
+                if let Err(err) = tx_ok.send(InternalMsg::WrittenData { bytes: bytes as i64 }).await {
+                    error!("Could not notify control channel of successful STOR: {}", err);
                 }
-                Err(err) => {
+                Some(bytes)
+            }
+            Err(err) => {
+                if !report_timeout(tx_error.clone(), &timed_out).await {
                     if let Err(err) = tx_error.send(InternalMsg::StorageError(err)).await {
                         error!("Could not notify control channel of error with STOR: {}", err);
                     }
                 }
+                None
             }
-        });
+        }
     }
 
     #[tracing_attributes::instrument]
-    async fn exec_list(self, path: Option<String>) {
+    async fn exec_list(self, path: Option<String>) -> Option<u64> {
         let path = match path {
             Some(path) => self.cwd.join(path),
             None => self.cwd.clone(),
         };
         let mut tx_ok = self.control_msg_tx.clone();
-        tokio::spawn(async move {
-            let mut output = Self::writer(self.socket, self.ftps_mode);
-            let result = match self.storage.list_fmt(&self.user, path).await {
-                Ok(cursor) => {
-                    debug!("Copying future for List");
-                    let mut input = cursor;
-                    match tokio::io::copy(&mut input, &mut output).await {
-                        Ok(_) => Ok(InternalMsg::DirectorySuccessfullyListed),
-                        Err(e) => Err(e),
-                    }
+        let socket = Inactivity::new(self.socket, self.inactivity_timeout);
+        let timed_out = socket.timed_out();
+        let mut output = match Self::writer(socket, self.ftps_mode).await {
+            Ok(output) => output,
+            Err(err) => {
+                report_tls_failure(tx_ok, err, &self.metrics).await;
+                return None;
+            }
+        };
+        // The number of bytes sent is only known when the listing succeeded.
+        let result = match self.storage.list_fmt(&self.user, path).await {
+            Ok(cursor) => {
+                debug!("Copying future for List");
+                let mut input = cursor;
+                match tokio::io::copy(&mut input, &mut output).await {
+                    Ok(bytes) => Ok((InternalMsg::DirectorySuccessfullyListed, Some(bytes))),
+                    Err(e) => Err(e),
                 }
-                Err(err) => {
-                    warn!("Failed to send directory list: {:?}", err);
-                    match output.write_all(&format!("{}\r\n", err).into_bytes()).await {
-                        Ok(_) => Ok(InternalMsg::DirectoryListFailure),
-                        Err(e) => Err(e),
-                    }
+            }
+            Err(err) => {
+                warn!("Failed to send directory list: {:?}", err);
+                match output.write_all(&format!("{}\r\n", err).into_bytes()).await {
+                    Ok(_) => Ok((InternalMsg::DirectoryListFailure, None)),
+                    Err(e) => Err(e),
                 }
-            };
-            match result {
-                Ok(msg) => {
-                    if let Err(err) = output.shutdown().await {
-                        warn!("Could not shutdown output stream during LIST: {}", err);
-                    }
-                    if let Err(err) = tx_ok.send(msg).await {
-                        error!("Could not notify control channel of LIST result: {}", err);
-                    }
+            }
+        };
+        match result {
+            Ok((msg, bytes)) => {
+                if let Err(err) = output.shutdown().await {
+                    warn!("Could not shutdown output stream during LIST: {}", err);
+                }
+                if let Err(err) = tx_ok.send(msg).await {
+                    error!("Could not notify control channel of LIST result: {}", err);
                 }
-                Err(err) => warn!("Failed to send reply to LIST: {}", err),
+                bytes
             }
-        });
+            Err(err) => {
+                warn!("Failed to send reply to LIST: {}", err);
+                report_send_failure(tx_ok, &timed_out).await;
+                None
+            }
+        }
     }
 
     #[tracing_attributes::instrument]
-    async fn exec_nlst(self, path: Option<String>) {
+    async fn exec_nlst(self, path: Option<String>) -> Option<u64> {
         let path = match path {
             Some(path) => self.cwd.join(path),
             None => self.cwd.clone(),
         };
         let mut tx_ok = self.control_msg_tx.clone();
         let mut tx_error = self.control_msg_tx.clone();
-        tokio::spawn(async move {
-            match self.storage.nlst(&self.user, path).await {
-                Ok(mut input) => {
-                    let mut output = Self::writer(self.socket, self.ftps_mode);
-                    match tokio::io::copy(&mut input, &mut output).await {
-                        Ok(_) => {
-                            if let Err(err) = output.shutdown().await {
-                                warn!("Could not shutdown output stream during NLIST: {}", err);
-                            }
-                            if let Err(err) = tx_ok.send(InternalMsg::DirectorySuccessfullyListed).await {
-                                error!("Could not notify control channel of successful NLIST: {}", err);
-                            }
+        let socket = Inactivity::new(self.socket, self.inactivity_timeout);
+        let timed_out = socket.timed_out();
+        match self.storage.nlst(&self.user, path).await {
+            Ok(mut input) => {
+                let mut output = match Self::writer(socket, self.ftps_mode).await {
+                    Ok(output) => output,
+                    Err(err) => {
+                        report_tls_failure(tx_error, err, &self.metrics).await;
+                        return None;
+                    }
+                };
+                match tokio::io::copy(&mut input, &mut output).await {
+                    Ok(bytes) => {
+                        if let Err(err) = output.shutdown().await {
+                            warn!("Could not shutdown output stream during NLIST: {}", err);
+                        }
+                        if let Err(err) = tx_ok.send(InternalMsg::DirectorySuccessfullyListed).await {
+                            error!("Could not notify control channel of successful NLIST: {}", err);
                         }
-                        Err(err) => warn!("Could not copy from storage implementation during NLST: {}", err),
+                        Some(bytes)
                     }
-                }
-                Err(_) => {
-                    if let Err(err) = tx_error.send(InternalMsg::StorageError(Error::from(ErrorKind::LocalError))).await {
-                        warn!("Could not notify control channel of error with NLIST: {}", err);
+                    Err(err) => {
+                        warn!("Could not copy from storage implementation during NLST: {}", err);
+                        report_send_failure(tx_ok, &timed_out).await;
+                        None
                     }
                 }
             }
-        });
+            Err(_) => {
+                if let Err(err) = tx_error.send(InternalMsg::StorageError(Error::from(ErrorKind::LocalError))).await {
+                    warn!("Could not notify control channel of error with NLIST: {}", err);
+                }
+                None
+            }
+        }
     }
 
     // Lots of code duplication here. Should disappear completely when the storage backends are rewritten in async/.await style
-    #[tracing_attributes::instrument]
-    fn writer(socket: tokio::net::TcpStream, ftps_mode: FTPSConfig) -> Box<dyn tokio::io::AsyncWrite + Send + Unpin + Sync> {
+    #[tracing_attributes::instrument(skip(socket))]
+    async fn writer<IO>(socket: IO, ftps_mode: FTPSConfig) -> std::io::Result<Box<dyn tokio::io::AsyncWrite + Send + Unpin + Sync>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
+    {
         match ftps_mode {
-            FTPSConfig::Off => Box::new(socket),
-            FTPSConfig::On { certs_file, key_file } => {
-                let io = futures::executor::block_on(async move {
-                    let acceptor: TlsAcceptor = new_config(certs_file, key_file).into();
-                    acceptor.accept(socket).await.unwrap()
-                });
-                Box::new(io)
-            }
+            FTPSConfig::Off => Ok(Box::new(socket)),
+            FTPSConfig::On { acceptor, .. } => Ok(Box::new(acceptor.accept(socket).await?)),
         }
     }
 
     // Lots of code duplication here. Should disappear completely when the storage backends are rewritten in async/.await style
-    #[tracing_attributes::instrument]
-    fn reader(socket: tokio::net::TcpStream, ftps_mode: FTPSConfig) -> Box<dyn tokio::io::AsyncRead + Send + Unpin + Sync> {
+    #[tracing_attributes::instrument(skip(socket))]
+    async fn reader<IO>(socket: IO, ftps_mode: FTPSConfig) -> std::io::Result<Box<dyn tokio::io::AsyncRead + Send + Unpin + Sync>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Sync + Unpin + 'static,
+    {
         match ftps_mode {
-            FTPSConfig::Off => Box::new(socket),
-            FTPSConfig::On { certs_file, key_file } => {
-                let io = futures::executor::block_on(async move {
-                    let acceptor: TlsAcceptor = new_config(certs_file, key_file).into();
-                    acceptor.accept(socket).await.unwrap()
-                });
-                Box::new(io)
-            }
+            FTPSConfig::Off => Ok(Box::new(socket)),
+            FTPSConfig::On { acceptor, .. } => Ok(Box::new(acceptor.accept(socket).await?)),
         }
     }
 }
 
-/// Processing for the data connection. This will spawn a new async task with the actual processing.
-///
-/// socket: the data socket we'll be working with
-/// tls: tells if this should be a TLS connection
-/// tx: channel to send the result of our operation to the control process
+// Lets the control channel know that the data connection was closed because the TLS handshake
+// failed.
+async fn report_tls_failure(mut tx: Sender<InternalMsg>, err: std::io::Error, metrics: &Option<Arc<Metrics>>) {
+    warn!("TLS handshake on the data channel failed: {}", err);
+    if let Some(metrics) = metrics {
+        metrics.add_tls_handshake_failure_metric(TlsChannel::Data);
+    }
+    if let Err(err) = tx.send(InternalMsg::ConnectionReset).await {
+        warn!("Could not notify control channel of failed TLS handshake: {}", err);
+    }
+}
+
+// Lets the control channel know when a transfer failed because no data moved for too long.
+// Returns whether that was the case.
+async fn report_timeout(mut tx: Sender<InternalMsg>, timed_out: &AtomicBool) -> bool {
+    if !timed_out.load(Ordering::Relaxed) {
+        return false;
+    }
+    if let Err(err) = tx.send(InternalMsg::TransferTimedOut).await {
+        warn!("Could not notify control channel of transfer timeout: {}", err);
+    }
+    true
+}
+
+// Lets the control channel know that sending data to the client failed, either because no data
+// moved for too long or because the data connection broke.
+async fn report_send_failure(mut tx: Sender<InternalMsg>, timed_out: &AtomicBool) {
+    if report_timeout(tx.clone(), timed_out).await {
+        return;
+    }
+    if let Err(err) = tx.send(InternalMsg::ConnectionReset).await {
+        warn!("Could not notify control channel of broken data connection: {}", err);
+    }
+}
+
+/// Modifies the session by adding channels that are used to communicate with the data connection
+/// processing loop. This must be called before `spawn_processing`, which needs the returned
+/// generation of the data connection.
 #[tracing_attributes::instrument]
-pub fn spawn_processing<S, U>(session: &mut Session<S, U>, socket: tokio::net::TcpStream, tx: Sender<InternalMsg>)
+pub async fn setup_data_loop_comms<S, U>(session: SharedSession<S, U>) -> u64
+where
+    U: UserDetail + 'static,
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+{
+    let (cmd_tx, cmd_rx): (Sender<Command>, Receiver<Command>) = channel(1);
+    let (data_abort_tx, data_abort_rx): (Sender<()>, Receiver<()>) = channel(1);
+
+    let mut session = session.lock().await;
+    session.data_cmd_tx = Some(cmd_tx);
+    session.data_cmd_rx = Some(cmd_rx);
+    session.data_abort_tx = Some(data_abort_tx);
+    session.data_abort_rx = Some(data_abort_rx);
+    session.data_generation += 1;
+    // Closes the listener of an earlier PASV or EPSV that the client didn't connect to.
+    session.passive_listener = None;
+    session.data_generation
+}
+
+// Creates the executor for the transfers on the given data connection, with the current settings
+// of the session.
+fn command_executor<S, U>(session: &Session<S, U>, socket: tokio::net::TcpStream, tx: Sender<InternalMsg>) -> DataCommandExecutor<S, U>
 where
     S: StorageBackend<U> + 'static,
     S::File: tokio::io::AsyncRead + Send,
     S::Metadata: Metadata,
     U: UserDetail + 'static,
 {
-    let mut data_cmd_rx = session.data_cmd_rx.take().unwrap().fuse();
-    let mut data_abort_rx = session.data_abort_rx.take().unwrap().fuse();
     let ftps_mode = if session.data_tls { session.ftps_config.clone() } else { FTPSConfig::Off };
-    let command_executor = DataCommandExecutor {
+    let (global, own) = (&session.global_buckets, &session.session_buckets);
+    let download_buckets = global.download.iter().chain(own.download.iter()).cloned().collect();
+    let upload_buckets = global.upload.iter().chain(own.upload.iter()).cloned().collect();
+    DataCommandExecutor {
         user: session.user.clone(),
         socket,
         control_msg_tx: tx,
@@ -242,28 +451,146 @@ where
         cwd: session.cwd.clone(),
         start_pos: session.start_pos,
         ftps_mode,
+        download_buckets,
+        upload_buckets,
+        inactivity_timeout: session.data_timeouts.inactivity,
+        session_end: session.end.clone(),
+        upload_grace_period: session.data_timeouts.upload_grace_period,
+        record: session.record.clone(),
+        metrics: session.metrics.clone(),
+        metrics_user: match (&session.user_labels, &session.username) {
+            (Some(labels), Some(username)) => Some(labels.label(username)),
+            _ => None,
+        },
+    }
+}
+
+/// Processing for the data connection. This will spawn a new async task with the actual processing.
+///
+/// socket: the data socket we'll be working with
+/// tx: channel to send the result of our operation to the control process
+/// generation: the generation of the data connection that `setup_data_loop_comms` returned
+#[tracing_attributes::instrument]
+pub fn spawn_processing<S, U>(session: &mut Session<S, U>, socket: tokio::net::TcpStream, tx: Sender<InternalMsg>, generation: u64) -> Result<(), DataChanError>
+where
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+    U: UserDetail + 'static,
+{
+    if session.data_generation != generation {
+        return Err(DataChanError::Replaced);
+    }
+    let (mut data_cmd_rx, mut data_abort_rx) = match (session.data_cmd_rx.take(), session.data_abort_rx.take()) {
+        (Some(data_cmd_rx), Some(data_abort_rx)) => (data_cmd_rx.fuse(), data_abort_rx.fuse()),
+        _ => return Err(DataChanError::InUse),
     };
+    let command_executor = command_executor(session, socket, tx);
+    let command_timeout = session.data_timeouts.command;
+    let session_end = session.end.clone();
 
-    tokio::spawn(async move {
-        let mut timeout_delay = tokio::time::delay_for(std::time::Duration::from_secs(5 * 60));
-        // TODO: Use configured timeout
+    let data_loop = async move {
+        let mut timeout_delay = tokio::time::delay_for(command_timeout);
         tokio::select! {
             Some(command) = data_cmd_rx.next() => {
-                handle_incoming(DataCommand::ExternalCommand(command), command_executor).await;
+                handle_incoming(DataCommand::ExternalCommand(command), command_executor, data_abort_rx).await;
             },
             Some(_) = data_abort_rx.next() => {
-                handle_incoming(DataCommand::Abort, command_executor).await;
+                handle_incoming(DataCommand::Abort, command_executor, data_abort_rx).await;
+            },
+            _ = session_end => {
+                debug!("Closing data connection of ended session");
             },
             _ = &mut timeout_delay => {
-                warn!("Data channel connection timed out");
-                return;
+                warn!("Data channel connection timed out waiting for a command");
+                let mut tx = command_executor.control_msg_tx;
+                if let Err(err) = tx.send(InternalMsg::DataConnectionTimedOut(generation)).await {
+                    warn!("Could not notify control channel of data connection timeout: {}", err);
+                }
             }
         };
-    });
+    };
+    tokio::spawn(data_loop.instrument(session.span.clone()));
+    Ok(())
 }
 
-#[tracing_attributes::instrument]
-async fn handle_incoming<S, U>(incoming: DataCommand, command_executor: DataCommandExecutor<S, U>)
+/// Processing for an active mode data connection. Unlike with `spawn_processing` the connection
+/// to the client is only opened once a transfer command comes in. This will spawn a new async
+/// task with the actual processing.
+///
+/// connect: opens the data connection to the client
+/// tx: channel to send the result of our operation to the control process
+/// generation: the generation of the data connection that `setup_data_loop_comms` returned
+#[tracing_attributes::instrument(skip(connect))]
+pub async fn spawn_active_processing<S, U, F>(
+    session_arc: SharedSession<S, U>,
+    connect: F,
+    tx: Sender<InternalMsg>,
+    generation: u64,
+) -> Result<(), DataChanError>
+where
+    S: StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: Metadata,
+    U: UserDetail + 'static,
+    F: Future<Output = std::io::Result<tokio::net::TcpStream>> + Send + 'static,
+{
+    let mut session = session_arc.lock().await;
+    if session.data_generation != generation {
+        return Err(DataChanError::Replaced);
+    }
+    let (mut data_cmd_rx, mut data_abort_rx) = match (session.data_cmd_rx.take(), session.data_abort_rx.take()) {
+        (Some(data_cmd_rx), Some(data_abort_rx)) => (data_cmd_rx.fuse(), data_abort_rx.fuse()),
+        _ => return Err(DataChanError::InUse),
+    };
+    let session_end = session.end.clone();
+    let span = session.span.clone();
+    drop(session);
+
+    let data_loop = async move {
+        let mut tx = tx;
+        let command = tokio::select! {
+            command = data_cmd_rx.next() => match command {
+                Some(command) => command,
+                // A newer data connection took the place of this one.
+                None => return,
+            },
+            Some(_) = data_abort_rx.next() => {
+                if let Err(err) = tx.send(InternalMsg::TransferAborted).await {
+                    warn!("Could not notify control channel of aborted transfer: {}", err);
+                }
+                return;
+            },
+            _ = session_end.clone() => return,
+        };
+        let socket = tokio::select! {
+            connected = connect => match connected {
+                Ok(socket) => socket,
+                Err(err) => {
+                    warn!("Could not open active mode data connection: {}", err);
+                    if let Err(err) = tx.send(InternalMsg::DataConnectionFailed(generation)).await {
+                        warn!("Could not notify control channel of failed data connection: {}", err);
+                    }
+                    return;
+                }
+            },
+            Some(_) = data_abort_rx.next() => {
+                if let Err(err) = tx.send(InternalMsg::TransferAborted).await {
+                    warn!("Could not notify control channel of aborted transfer: {}", err);
+                }
+                return;
+            },
+            _ = session_end => return,
+        };
+        let command_executor = command_executor(&*session_arc.lock().await, socket, tx);
+        handle_incoming(DataCommand::ExternalCommand(command), command_executor, data_abort_rx).await;
+    };
+    tokio::spawn(data_loop.instrument(span));
+    Ok(())
+}
+
+#[tracing_attributes::instrument(skip(command_executor, abort_rx))]
+async fn handle_incoming<S, U>(incoming: DataCommand, command_executor: DataCommandExecutor<S, U>, abort_rx: Fuse<Receiver<()>>)
 where
     S: StorageBackend<U> + 'static,
     S::File: tokio::io::AsyncRead + Send,
@@ -273,10 +600,14 @@ where
     match incoming {
         DataCommand::Abort => {
             info!("Data channel abort received");
+            let mut tx = command_executor.control_msg_tx;
+            if let Err(err) = tx.send(InternalMsg::TransferAborted).await {
+                warn!("Could not notify control channel of aborted transfer: {}", err);
+            }
         }
         DataCommand::ExternalCommand(command) => {
             info!("Data command received: {:?}", command);
-            command_executor.execute(command).await;
+            command_executor.execute(command, abort_rx).await;
         }
     }
 }
@@ -0,0 +1,93 @@
+//! Contains the `ServerError` struct that defines the error type returned by the [`Server`].
+//!
+//! [`Server`]: ../struct.Server.html
+
+use failure::{Backtrace, Context, Fail};
+use std::fmt;
+
+/// The error type returned by [`Server::listen`] and friends.
+///
+/// [`Server::listen`]: ./struct.Server.html#method.listen
+#[derive(Debug)]
+pub struct ServerError {
+    inner: Context<ServerErrorKind>,
+}
+
+/// A list specifying categories of server errors. It is meant to be used with the [`ServerError`] type.
+///
+/// [`ServerError`]: ./struct.ServerError.html
+#[derive(Clone, Eq, PartialEq, Debug, Fail)]
+pub enum ServerErrorKind {
+    /// The given address could not be parsed as a socket address.
+    #[fail(display = "Invalid bind address: {}", address)]
+    InvalidBindAddress {
+        /// The address that could not be parsed.
+        address: String,
+    },
+    /// The server could not bind to the given address.
+    #[fail(display = "Failed to bind to address: {}", address)]
+    BindError {
+        /// The address that we tried to bind to.
+        address: String,
+    },
+    /// The session for a connection passed to [`Server::serve_connection`] could not be started.
+    ///
+    /// [`Server::serve_connection`]: ./struct.Server.html#method.serve_connection
+    #[fail(display = "Failed to serve the connection")]
+    ConnectionError,
+    /// The host name given to [`Server::passive_host`] could not be resolved to an IPv4 address.
+    ///
+    /// [`Server::passive_host`]: ./struct.Server.html#method.passive_host
+    #[fail(display = "Failed to resolve passive host: {}", host)]
+    PassiveHostResolveError {
+        /// The host name that could not be resolved.
+        host: String,
+    },
+    /// The metrics could not be registered with the registry given to [`Server::metrics_options`],
+    /// for example because another server already registered metrics under the same names and
+    /// labels.
+    ///
+    /// [`Server::metrics_options`]: ./struct.Server.html#method.metrics_options
+    #[fail(display = "Failed to register the metrics")]
+    MetricsError,
+    /// The certificates or private key given to [`Server::ftps`] could not be loaded.
+    ///
+    /// [`Server::ftps`]: ./struct.Server.html#method.ftps
+    #[fail(display = "Failed to load the FTPS certificates or key")]
+    TlsConfigError,
+}
+
+impl ServerError {
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> &ServerErrorKind {
+        self.inner.get_context()
+    }
+}
+
+impl Fail for ServerError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.inner.cause()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.inner.backtrace()
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+impl From<ServerErrorKind> for ServerError {
+    fn from(kind: ServerErrorKind) -> ServerError {
+        ServerError { inner: Context::new(kind) }
+    }
+}
+
+impl From<Context<ServerErrorKind>> for ServerError {
+    fn from(inner: Context<ServerErrorKind>) -> ServerError {
+        ServerError { inner }
+    }
+}
@@ -0,0 +1,172 @@
+//! Contains the tracker of failed logins that temporarily blocks client IP addresses and user
+//! names after too many failed attempts.
+
+use crate::options::FailedLoginsPolicy;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// What a failed login is counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl LockoutKey {
+    // The label used for this kind of key in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LockoutKey::Ip(_) => "ip",
+            LockoutKey::User(_) => "user",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    // The failures within the sliding window, oldest first.
+    failures: VecDeque<Instant>,
+    last_failure: Instant,
+    // The number of times the key got blocked, used to back off exponentially.
+    lockouts: u32,
+    blocked_until: Option<Instant>,
+}
+
+// FailedLogins is shared by all sessions of a Server.
+#[derive(Debug)]
+pub struct FailedLogins {
+    policy: FailedLoginsPolicy,
+    records: Mutex<HashMap<LockoutKey, Record>>,
+}
+
+impl FailedLogins {
+    pub fn new(policy: FailedLoginsPolicy) -> Self {
+        FailedLogins {
+            policy,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Tells whether the given key is blocked at the moment.
+    pub fn is_blocked(&self, key: &LockoutKey) -> bool {
+        self.is_blocked_at(key, Instant::now())
+    }
+
+    // Counts a failed login for the given IP address and user name. Returns the keys that got
+    // blocked because of it.
+    pub fn record_failure(&self, ip: IpAddr, username: &str) -> Vec<LockoutKey> {
+        self.record_failure_at(ip, username, Instant::now())
+    }
+
+    // Forgets the failures of a user after a successful login. Failures of the IP address are
+    // kept, otherwise logging in to one account would allow guessing the passwords of others.
+    pub fn record_success(&self, username: &str) {
+        self.records.lock().unwrap().remove(&LockoutKey::User(username.to_string()));
+    }
+
+    fn is_blocked_at(&self, key: &LockoutKey, now: Instant) -> bool {
+        match self.records.lock().unwrap().get(key) {
+            Some(Record {
+                blocked_until: Some(until), ..
+            }) => *until > now,
+            _ => false,
+        }
+    }
+
+    fn record_failure_at(&self, ip: IpAddr, username: &str, now: Instant) -> Vec<LockoutKey> {
+        let policy = &self.policy;
+        let mut records = self.records.lock().unwrap();
+        // A key's backoff is reset once it has been quiet for longer than the longest block.
+        records.retain(|_, record| now.duration_since(record.last_failure) < policy.window + policy.max_block_duration);
+
+        let mut blocked = vec![];
+        for key in &[LockoutKey::Ip(ip), LockoutKey::User(username.to_string())] {
+            let record = records.entry(key.clone()).or_insert_with(|| Record {
+                failures: VecDeque::new(),
+                last_failure: now,
+                lockouts: 0,
+                blocked_until: None,
+            });
+            record.last_failure = now;
+            while let Some(first) = record.failures.front() {
+                if now.duration_since(*first) < policy.window {
+                    break;
+                }
+                record.failures.pop_front();
+            }
+            record.failures.push_back(now);
+            if record.failures.len() >= policy.max_attempts as usize {
+                record.failures.clear();
+                record.blocked_until = Some(now + block_duration(policy, record.lockouts));
+                record.lockouts += 1;
+                blocked.push(key.clone());
+            }
+        }
+        blocked
+    }
+}
+
+// Doubles the block duration with every lockout, up to the maximum.
+fn block_duration(policy: &FailedLoginsPolicy, lockouts: u32) -> Duration {
+    let factor = 2u32.checked_pow(lockouts).unwrap_or(u32::MAX);
+    policy
+        .block_duration
+        .checked_mul(factor)
+        .unwrap_or(policy.max_block_duration)
+        .min(policy.max_block_duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailedLogins, LockoutKey};
+    use crate::options::FailedLoginsPolicy;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn blocks_with_exponential_backoff() {
+        let policy = FailedLoginsPolicy::new()
+            .max_attempts(2)
+            .window(Duration::from_secs(60))
+            .block_duration(Duration::from_secs(10))
+            .max_block_duration(Duration::from_secs(25));
+        let tracker = FailedLogins::new(policy);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip_key = LockoutKey::Ip(ip);
+        let user_key = LockoutKey::User("alice".to_string());
+        let start = Instant::now();
+
+        assert!(tracker.record_failure_at(ip, "alice", start).is_empty());
+        let blocked = tracker.record_failure_at(ip, "alice", start + Duration::from_secs(1));
+        assert_eq!(blocked, vec![ip_key.clone(), user_key.clone()]);
+        assert!(tracker.is_blocked_at(&ip_key, start + Duration::from_secs(10)));
+        assert!(!tracker.is_blocked_at(&ip_key, start + Duration::from_secs(11)));
+
+        // The second lockout lasts twice as long, the third is capped.
+        tracker.record_failure_at(ip, "bob", start + Duration::from_secs(12));
+        tracker.record_failure_at(ip, "bob", start + Duration::from_secs(13));
+        assert!(tracker.is_blocked_at(&ip_key, start + Duration::from_secs(32)));
+        assert!(!tracker.is_blocked_at(&ip_key, start + Duration::from_secs(33)));
+        tracker.record_failure_at(ip, "carol", start + Duration::from_secs(40));
+        tracker.record_failure_at(ip, "carol", start + Duration::from_secs(41));
+        assert!(tracker.is_blocked_at(&ip_key, start + Duration::from_secs(65)));
+        assert!(!tracker.is_blocked_at(&ip_key, start + Duration::from_secs(66)));
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let policy = FailedLoginsPolicy::new().max_attempts(2).window(Duration::from_secs(60));
+        let tracker = FailedLogins::new(policy);
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = Instant::now();
+
+        tracker.record_failure_at(ip, "alice", start);
+        assert!(tracker.record_failure_at(ip, "alice", start + Duration::from_secs(61)).is_empty());
+    }
+}
@@ -1,31 +1,50 @@
 use super::{
+    access::AccessControl,
     chancomms::{InternalMsg, ProxyLoopMsg, ProxyLoopReceiver, ProxyLoopSender},
-    controlchan::{spawn_loop, LoopConfig},
+    controlchan::{commands::PassiveCommand, spawn_loop, LoopConfig},
     datachan::spawn_processing,
-    tls::FTPSConfig,
-    ReplyCode,
+    error::{ServerError, ServerErrorKind},
+    failed_logins::FailedLogins,
+    handle::ServerHandle,
+    session_limits::SessionLimits,
+    shutdown,
+    throttle::Buckets,
+    tls::{self, CertFiles, FTPSConfig},
+    Reply, ReplyCode,
 };
 use crate::{
     auth::{anonymous::AnonymousAuthenticator, Authenticator, DefaultUser, UserDetail},
+    metrics::{Metrics, UserLabels},
+    options,
     server::{
         proxy_protocol::{get_peer_from_proxy_header, ConnectionTuple, ProxyMode, ProxyProtocolSwitchboard},
         session::SharedSession,
     },
     storage::{filesystem::Filesystem, Metadata, StorageBackend},
 };
+use failure::{Fail, ResultExt};
 use futures::{channel::mpsc::channel, SinkExt, StreamExt};
-use log::{info, warn};
+use log::{debug, info, warn};
 use std::{
+    collections::HashMap,
     fmt::Debug,
+    future::Future,
+    io,
     net::{IpAddr, Shutdown, SocketAddr},
     ops::Range,
     path::PathBuf,
+    pin::Pin,
     sync::Arc,
     time::Duration,
 };
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing_futures::Instrument;
 
 const DEFAULT_GREETING: &str = "Welcome to the libunftp FTP server";
 const DEFAULT_IDLE_SESSION_TIMEOUT_SECS: u64 = 600;
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+const TOO_MANY_CONNECTIONS: &str = "Too many connections";
 
 /// An instance of a FTP server. It contains a reference to an [`Authenticator`] that will be used
 /// for authentication, and a [`StorageBackend`] that will be used as the storage backend.
@@ -56,11 +75,38 @@ where
     greeting: &'static str,
     authenticator: Arc<dyn Authenticator<U>>,
     passive_ports: Range<u16>,
-    collect_metrics: bool,
-    ftps_mode: FTPSConfig,
+    passive_host: options::PassiveHost,
+    // The passive host with its name resolved, filled in on the first call to
+    // Server::serve_connection so that the name isn't looked up for every connection.
+    resolved_passive_host: tokio::sync::Mutex<Option<options::PassiveHost>>,
+    active_mode: Option<options::ActiveMode>,
+    metrics_options: Option<options::MetricsOptions>,
+    // Created and registered on first use, see Server::metrics_collectors.
+    metrics: std::sync::Mutex<Option<Arc<Metrics>>>,
+    user_labels: Option<Arc<UserLabels>>,
+    // Built on first use from the files below and Server::ftps_tls_options, see Server::ftps_config.
+    ftps_mode: std::sync::Mutex<Option<FTPSConfig>>,
+    // The files given to Server::ftps, Server::ftps_sni_certificate and Server::ftps_client_auth.
+    ftps_certs: Option<CertFiles>,
+    ftps_sni_certs: HashMap<String, CertFiles>,
+    ftps_client_auth: Option<(options::FtpsClientAuth, PathBuf)>,
+    ftps_tls_options: options::TlsOptions,
+    ftps_implicit: bool,
+    ftps_required: options::FtpsRequired,
+    ftps_reload_interval: Option<Duration>,
     idle_session_timeout: std::time::Duration,
+    session_limits: SessionLimits,
+    access: AccessControl,
+    global_buckets: Buckets,
+    session_rate_limits: options::RateLimits,
+    data_timeouts: options::DataTimeouts,
     proxy_protocol_mode: ProxyMode,
     proxy_protocol_switchboard: Option<ProxyProtocolSwitchboard<S, U>>,
+    shutdown_indicator: Option<Pin<Box<dyn Future<Output = options::Shutdown> + Send + Sync>>>,
+    shutdown_notifier: shutdown::Notifier,
+    handle: ServerHandle,
+    #[cfg(feature = "admin_http")]
+    admin_address: Option<String>,
 }
 
 impl<S, U> Debug for Server<S, U>
@@ -69,16 +115,31 @@ where
     U: UserDetail,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Server")
-            .field("greeting", &self.greeting)
+        let mut f = f.debug_struct("Server");
+        f.field("greeting", &self.greeting)
             .field("authenticator", &self.authenticator)
             .field("passive_ports", &self.passive_ports)
-            .field("collect_metrics", &self.collect_metrics)
+            .field("passive_host", &self.passive_host)
+            .field("active_mode", &self.active_mode)
+            .field("metrics_options", &self.metrics_options)
+            .field("user_labels", &self.user_labels)
             .field("ftps_mode", &self.ftps_mode)
+            .field("ftps_client_auth", &self.ftps_client_auth)
+            .field("ftps_tls_options", &self.ftps_tls_options)
+            .field("ftps_implicit", &self.ftps_implicit)
+            .field("ftps_required", &self.ftps_required)
+            .field("ftps_reload_interval", &self.ftps_reload_interval)
             .field("idle_session_timeout", &self.idle_session_timeout)
+            .field("session_limits", &self.session_limits)
+            .field("access", &self.access)
+            .field("global_buckets", &self.global_buckets)
+            .field("session_rate_limits", &self.session_rate_limits)
+            .field("data_timeouts", &self.data_timeouts)
             .field("proxy_protocol_mode", &self.proxy_protocol_mode)
-            .field("proxy_protocol_switchboard", &self.proxy_protocol_switchboard)
-            .finish()
+            .field("proxy_protocol_switchboard", &self.proxy_protocol_switchboard);
+        #[cfg(feature = "admin_http")]
+        f.field("admin_address", &self.admin_address);
+        f.finish()
     }
 }
 
@@ -131,11 +192,33 @@ where
             greeting: DEFAULT_GREETING,
             authenticator,
             passive_ports: 49152..65535,
-            ftps_mode: FTPSConfig::Off,
-            collect_metrics: false,
+            passive_host: options::PassiveHost::FromConnection,
+            resolved_passive_host: tokio::sync::Mutex::new(None),
+            active_mode: None,
+            ftps_mode: std::sync::Mutex::new(None),
+            ftps_certs: None,
+            ftps_sni_certs: HashMap::new(),
+            ftps_client_auth: None,
+            ftps_tls_options: options::TlsOptions::default(),
+            ftps_implicit: false,
+            ftps_required: options::FtpsRequired::Off,
+            ftps_reload_interval: None,
+            metrics_options: None,
+            metrics: std::sync::Mutex::new(None),
+            user_labels: None,
             idle_session_timeout: Duration::from_secs(DEFAULT_IDLE_SESSION_TIMEOUT_SECS),
+            session_limits: SessionLimits::default(),
+            access: AccessControl::default(),
+            global_buckets: Buckets::default(),
+            session_rate_limits: options::RateLimits::default(),
+            data_timeouts: options::DataTimeouts::default(),
             proxy_protocol_mode: ProxyMode::Off,
             proxy_protocol_switchboard: Option::None,
+            shutdown_indicator: None,
+            shutdown_notifier: shutdown::Notifier::new(),
+            handle: ServerHandle::default(),
+            #[cfg(feature = "admin_http")]
+            admin_address: None,
         }
     }
 
@@ -197,9 +280,65 @@ where
         self
     }
 
+    /// Set the IP address that is advertised to clients in the reply to the `PASV` command. By
+    /// default this is the address the control connection was accepted on, which is not what
+    /// clients need when the server runs behind NAT. See [`PassiveHost`] for the options: a fixed
+    /// IP address, a host name that is resolved when the server starts or a function that picks
+    /// an address based on the IP address of the client.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::PassiveHost, Server};
+    /// use std::{net::{IpAddr, Ipv4Addr}, sync::Arc};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").passive_host([203, 0, 113, 7]);
+    ///
+    /// // Or resolve a host name at startup:
+    /// let mut server = Server::new_with_fs_root("/tmp").passive_host("ftp.example.com");
+    ///
+    /// // Or give clients on the internal network a different address:
+    /// let mut server = Server::new_with_fs_root("/tmp").passive_host(PassiveHost::Dynamic(Arc::new(|peer: IpAddr| match peer {
+    ///     IpAddr::V4(ip) if ip.is_private() => Ipv4Addr::new(10, 0, 0, 7),
+    ///     _ => Ipv4Addr::new(203, 0, 113, 7),
+    /// })));
+    /// ```
+    ///
+    /// [`PassiveHost`]: options/enum.PassiveHost.html
+    pub fn passive_host<H: Into<options::PassiveHost>>(mut self, host: H) -> Self {
+        self.passive_host = host.into();
+        self.resolved_passive_host = tokio::sync::Mutex::new(None);
+        self
+    }
+
+    /// Enables active mode, allowing clients to use the `PORT` and `EPRT` commands to have the
+    /// server connect to them for data transfers. The server connects when the client sends the
+    /// transfer command that follows. Active mode is off unless this method is called.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options, Server};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .active_mode(options::ActiveMode::new().source_ports(2000..2100));
+    /// ```
+    pub fn active_mode(mut self, mode: options::ActiveMode) -> Self {
+        self.active_mode = Some(mode);
+        self
+    }
+
     /// Configures the path to the certificates file (DER-formatted PKCS #12 archive) and the
     /// associated password for the archive in order to configure FTPS.
     ///
+    /// The files are read when the server starts and the resulting TLS configuration is shared by
+    /// all control and data channels. If they cannot be loaded [`listen`](#method.listen) returns
+    /// an error.
+    /// The files can be loaded again while the server runs with
+    /// [`ServerHandle::reload_certificates`] or [`ftps_reload_interval`](#method.ftps_reload_interval).
+    ///
     /// # Example
     ///
     /// ```rust
@@ -207,15 +346,188 @@ where
     ///
     /// let mut server = Server::new_with_fs_root("/tmp").ftps("/srv/unftp/server.certs", "/srv/unftp/server.key");
     /// ```
+    ///
+    /// [`ServerHandle::reload_certificates`]: struct.ServerHandle.html#method.reload_certificates
     pub fn ftps<P: Into<PathBuf>>(mut self, certs_file: P, key_file: P) -> Self {
-        self.ftps_mode = FTPSConfig::On {
+        self.ftps_certs = Some(CertFiles {
             certs_file: certs_file.into(),
             key_file: key_file.into(),
-        };
+        });
+        self.ftps_implicit = false;
+        self
+    }
+
+    /// Adds a certificates file and key file for clients that ask for the given server name with
+    /// SNI, to serve several host names from one FTPS endpoint. They are used for the TLS handshakes
+    /// of both the control and data channels. Clients that send no server name or one without
+    /// certificates of its own get the certificates given to [`ftps`](#method.ftps). Server names
+    /// are matched case insensitively.
+    ///
+    /// The files are read when the server starts, like those given to [`ftps`](#method.ftps), and are
+    /// reloaded along with them. They are only used when FTPS is configured.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ftps("/srv/unftp/server.certs", "/srv/unftp/server.key")
+    ///     .ftps_sni_certificate("ftp.partner.example", "/srv/unftp/partner.certs", "/srv/unftp/partner.key");
+    /// ```
+    pub fn ftps_sni_certificate<N: Into<String>, P: Into<PathBuf>>(mut self, server_name: N, certs_file: P, key_file: P) -> Self {
+        self.ftps_sni_certs.insert(
+            server_name.into(),
+            CertFiles {
+                certs_file: certs_file.into(),
+                key_file: key_file.into(),
+            },
+        );
+        self
+    }
+
+    /// Configures FTPS in implicit mode, using the given certificates file and key file like
+    /// [`ftps`](#method.ftps) does. In implicit mode the TLS handshake takes place right after a
+    /// client connects, before the greeting is sent, and both the control and data channels are
+    /// protected from the start. Implicit FTPS is traditionally served on port 990.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// let mut server = Server::new_with_fs_root("/tmp").ftps_implicit("/srv/unftp/server.certs", "/srv/unftp/server.key");
+    /// ```
+    pub fn ftps_implicit<P: Into<PathBuf>>(mut self, certs_file: P, key_file: P) -> Self {
+        self = self.ftps(certs_file, key_file);
+        self.ftps_implicit = true;
+        self
+    }
+
+    /// Tells which channels clients must protect with TLS when FTPS is configured. When TLS is
+    /// required on the control channel, `USER` and `PASS` are refused until the client sent
+    /// `AUTH TLS`. When it is required on the data channel as well, transfers are refused until the
+    /// client sent `PROT P`. The policy can be made stricter for individual users through
+    /// [`UserDetail::ftps_required`]. The default is [`FtpsRequired::Off`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::FtpsRequired, Server};
+    ///
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ftps("/srv/unftp/server.certs", "/srv/unftp/server.key")
+    ///     .ftps_required(FtpsRequired::ControlAndData);
+    /// ```
+    ///
+    /// [`UserDetail::ftps_required`]: auth/trait.UserDetail.html#method.ftps_required
+    /// [`FtpsRequired::Off`]: options/enum.FtpsRequired.html#variant.Off
+    pub fn ftps_required(mut self, required: options::FtpsRequired) -> Self {
+        self.ftps_required = required;
         self
     }
 
-    /// Enable the collection of prometheus metrics.
+    /// Asks FTPS clients for a certificate, or requires one, and verifies it against the
+    /// certificate authorities in the given PEM file. The certificate a client presented when
+    /// securing the control channel is passed to
+    /// [`Authenticator::authenticate_with_credentials`], which can log users in by certificate
+    /// alone or use it as a second factor next to their password. Data channels are secured with
+    /// the same settings.
+    ///
+    /// The certificate authorities are read when the server starts and are reloaded along with the
+    /// certificates given to [`ftps`](#method.ftps). If they cannot be loaded
+    /// [`listen`](#method.listen) returns an error. They are only used when FTPS is configured.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::FtpsClientAuth, Server};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ftps("/srv/unftp/server.certs", "/srv/unftp/server.key")
+    ///     .ftps_client_auth(FtpsClientAuth::Require, "/srv/unftp/partners-ca.pem");
+    /// ```
+    ///
+    /// [`Authenticator::authenticate_with_credentials`]: auth/trait.Authenticator.html#method.authenticate_with_credentials
+    pub fn ftps_client_auth<P: Into<PathBuf>>(mut self, client_auth: options::FtpsClientAuth, ca_file: P) -> Self {
+        self.ftps_client_auth = Some((client_auth, ca_file.into()));
+        self
+    }
+
+    /// Sets the TLS protocol versions, cipher suites, session resumption and key logging options
+    /// for FTPS, see [`TlsOptions`]. They apply to both the control and data channels.
+    ///
+    /// If the options cannot be applied, for instance because of an unknown cipher suite,
+    /// [`listen`](#method.listen) returns an error.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::{TlsOptions, TlsVersion}, Server};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ftps("/srv/unftp/server.certs", "/srv/unftp/server.key")
+    ///     .ftps_tls_options(TlsOptions::new().min_version(TlsVersion::V1_3).session_tickets(true));
+    /// ```
+    ///
+    /// [`TlsOptions`]: options/struct.TlsOptions.html
+    pub fn ftps_tls_options(mut self, options: options::TlsOptions) -> Self {
+        self.ftps_tls_options = options;
+        self
+    }
+
+    /// Makes the server check the certificates and key files given to [`ftps`](#method.ftps) and
+    /// [`ftps_sni_certificate`](#method.ftps_sni_certificate) and the certificate authorities given
+    /// to [`ftps_client_auth`](#method.ftps_client_auth) for changes every `interval` while it
+    /// listens, and load them again when they changed. New TLS handshakes use the new certificates,
+    /// sessions that are secured already keep going. When the files cannot be loaded the current
+    /// certificates are kept and the next check tries again.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    /// use std::time::Duration;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ftps("/srv/unftp/server.certs", "/srv/unftp/server.key")
+    ///     .ftps_reload_interval(Duration::from_secs(60));
+    /// ```
+    pub fn ftps_reload_interval(mut self, interval: Duration) -> Self {
+        self.ftps_reload_interval = Some(interval);
+        self
+    }
+
+    /// Serve the prometheus metrics and health checks over HTTP on the given address while the
+    /// server is listening. This requires the `admin_http` feature. The endpoints are:
+    ///
+    /// - `/metrics`: the metrics in the prometheus text exposition format. See
+    ///   [`metrics`](#method.metrics) to enable their collection.
+    /// - `/health`: always replies `200 OK` while the process is running.
+    /// - `/ready`: replies `200 OK` once the FTP listener is bound and `503 Service Unavailable`
+    ///   before that and while shutting down.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").metrics().admin_listener("127.0.0.1:9090");
+    /// ```
+    #[cfg(feature = "admin_http")]
+    pub fn admin_listener<T: Into<String>>(mut self, bind_address: T) -> Self {
+        self.admin_address = Some(bind_address.into());
+        self
+    }
+
+    /// Enable the collection of prometheus metrics. They are registered with the default registry
+    /// of the prometheus crate, use [`metrics_options`](#method.metrics_options) to register them
+    /// elsewhere.
     ///
     /// # Example
     ///
@@ -230,7 +542,46 @@ where
     /// server.metrics();
     /// ```
     pub fn metrics(mut self) -> Self {
-        self.collect_metrics = true;
+        self.metrics_options.get_or_insert_with(options::MetricsOptions::default);
+        self
+    }
+
+    /// Enable the collection of prometheus metrics and set the registry, namespace and constant
+    /// labels they are registered with. This allows running several servers in one process with
+    /// each reporting separately, or adding the metrics to the registry of the application.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    /// use libunftp::options::MetricsOptions;
+    /// use prometheus::Registry;
+    ///
+    /// let registry = Registry::new();
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .metrics_options(MetricsOptions::new().registry(registry).namespace("partners_ftp").const_label("listener", "partners"));
+    /// ```
+    pub fn metrics_options(mut self, options: options::MetricsOptions) -> Self {
+        self.metrics_options = Some(options);
+        self
+    }
+
+    /// Enable the collection of prometheus metrics and label the transfer metrics with the user
+    /// name as well. To keep the number of time series in check only the first `max_users` users
+    /// get a label of their own, the transfers of all others are counted under `other`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").metrics_per_user(100);
+    /// ```
+    pub fn metrics_per_user(mut self, max_users: usize) -> Self {
+        self.metrics_options.get_or_insert_with(options::MetricsOptions::default);
+        self.user_labels = Some(Arc::new(UserLabels::new(max_users)));
         self
     }
 
@@ -253,6 +604,191 @@ where
         self
     }
 
+    /// Set the timeouts for data connections: how long to wait for a client to connect after a
+    /// passive mode command, how long an established data connection waits for a transfer command
+    /// and how long a transfer may go on without moving any data. Clients that are waiting for a
+    /// transfer get a `425` reply when the data connection is not set up in time, and a `426` reply
+    /// when a transfer is aborted for inactivity. Data connections are closed as soon as the control
+    /// connection goes away, except for uploads that are given the upload grace period to finish.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::DataTimeouts, Server};
+    /// use std::time::Duration;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .data_timeouts(DataTimeouts::new().passive_accept(Duration::from_secs(30)).inactivity(Duration::from_secs(120)));
+    /// ```
+    pub fn data_timeouts(mut self, timeouts: options::DataTimeouts) -> Self {
+        self.data_timeouts = timeouts;
+        self
+    }
+
+    /// Set the maximum number of sessions that can be active at the same time. Clients that
+    /// connect while the limit is reached get a `421 Too many connections` reply and are
+    /// disconnected. There is no limit by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").max_sessions(500);
+    /// ```
+    pub fn max_sessions(mut self, max: usize) -> Self {
+        self.session_limits.max_sessions = Some(max);
+        self
+    }
+
+    /// Set the maximum number of sessions that can be active at the same time for a single client
+    /// IP address. In PROXY protocol mode this is the address of the client as reported by the
+    /// proxy. Clients that connect while the limit is reached get a `421 Too many connections`
+    /// reply and are disconnected. There is no limit by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").max_sessions_per_ip(10);
+    /// ```
+    pub fn max_sessions_per_ip(mut self, max: usize) -> Self {
+        self.session_limits.max_sessions_per_ip = Some(max);
+        self
+    }
+
+    /// Only let in clients with an IP address in one of the given ranges. Other clients get a
+    /// `421 Access denied` reply and are disconnected before the greeting is sent. In PROXY
+    /// protocol mode the address of the client as reported by the proxy is checked. All clients
+    /// are let in when the list is empty, which is the default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .ip_allow_list(vec!["10.0.0.0/8".parse().unwrap(), "192.168.1.0/24".parse().unwrap()]);
+    /// ```
+    pub fn ip_allow_list<I: IntoIterator<Item = options::Cidr>>(mut self, ranges: I) -> Self {
+        self.access.allow = Arc::new(ranges.into_iter().collect());
+        self
+    }
+
+    /// Refuse clients with an IP address in one of the given ranges. They get a
+    /// `421 Access denied` reply and are disconnected before the greeting is sent. The deny list
+    /// takes precedence over the allow list set with [`ip_allow_list`](#method.ip_allow_list).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").ip_deny_list(vec!["203.0.113.0/24".parse().unwrap()]);
+    /// ```
+    pub fn ip_deny_list<I: IntoIterator<Item = options::Cidr>>(mut self, ranges: I) -> Self {
+        self.access.deny = Arc::new(ranges.into_iter().collect());
+        self
+    }
+
+    /// Set a [`ConnectionFilter`] that is consulted for every client that passed the
+    /// [`ip_allow_list`](#method.ip_allow_list) and [`ip_deny_list`](#method.ip_deny_list) checks.
+    /// It can let the client in, tag its session or refuse it with a `421` reply of its choosing,
+    /// all before the greeting is sent.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use async_trait::async_trait;
+    /// use libunftp::{options::{ConnectionDecision, ConnectionFilter}, Server};
+    /// use std::{net::SocketAddr, sync::Arc};
+    ///
+    /// #[derive(Debug)]
+    /// struct BusinessHours;
+    ///
+    /// #[async_trait]
+    /// impl ConnectionFilter for BusinessHours {
+    ///     async fn check(&self, _peer_addr: SocketAddr) -> ConnectionDecision {
+    ///         ConnectionDecision::Reject(String::from("Come back tomorrow"))
+    ///     }
+    /// }
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").connection_filter(Arc::new(BusinessHours));
+    /// ```
+    ///
+    /// [`ConnectionFilter`]: options/trait.ConnectionFilter.html
+    pub fn connection_filter(mut self, filter: Arc<dyn options::ConnectionFilter>) -> Self {
+        self.access.filter = Some(filter);
+        self
+    }
+
+    /// Limits the transfer rate of all sessions together. Downloads and uploads are limited
+    /// separately. There are no limits by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::RateLimits, Server};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp").rate_limits(RateLimits::new().download(100 * 1024 * 1024));
+    /// ```
+    pub fn rate_limits(mut self, limits: options::RateLimits) -> Self {
+        self.global_buckets = Buckets::new(limits);
+        self
+    }
+
+    /// Limits the transfer rate of every session. Downloads and uploads are limited separately.
+    /// The limits can be replaced for individual users through [`UserDetail::rate_limits`]. There
+    /// are no limits by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::RateLimits, Server};
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .session_rate_limits(RateLimits::new().download(1024 * 1024).upload(512 * 1024));
+    /// ```
+    ///
+    /// [`UserDetail::rate_limits`]: auth/trait.UserDetail.html#method.rate_limits
+    pub fn session_rate_limits(mut self, limits: options::RateLimits) -> Self {
+        self.session_rate_limits = limits;
+        self
+    }
+
+    /// Enables protection against brute-force attacks on passwords, independent of the
+    /// [`Authenticator`] in use. Failed logins are counted per client IP address and per user name
+    /// as described by the given [`FailedLoginsPolicy`]. Logins for a blocked user name or from a
+    /// blocked IP address are refused with a `530` reply without consulting the authenticator, and
+    /// new connections from a blocked IP address get a `421` reply. Protection is off by default.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options::FailedLoginsPolicy, Server};
+    /// use std::time::Duration;
+    ///
+    /// // Use it in a builder-like pattern:
+    /// let mut server = Server::new_with_fs_root("/tmp")
+    ///     .failed_logins_policy(FailedLoginsPolicy::new().max_attempts(3).block_duration(Duration::from_secs(30)));
+    /// ```
+    ///
+    /// [`Authenticator`]: auth/trait.Authenticator.html
+    /// [`FailedLoginsPolicy`]: options/struct.FailedLoginsPolicy.html
+    pub fn failed_logins_policy(mut self, policy: options::FailedLoginsPolicy) -> Self {
+        self.access.failed_logins = Some(Arc::new(FailedLogins::new(policy)));
+        self
+    }
+
     /// Enable PROXY protocol mode.
     ///
     /// If you use a proxy such as haproxy or nginx, you can enable
@@ -284,6 +820,54 @@ where
         self
     }
 
+    /// Allows telling libunftp when and how to shutdown gracefully.
+    ///
+    /// The passed argument is a future that resolves when libunftp should shut down. The future
+    /// should return a [`options::Shutdown`] instance. Once it resolves, libunftp stops accepting
+    /// new connections and sends a 421 reply to sessions that are not transferring data. Sessions
+    /// with a running data transfer are closed as soon as that transfer finishes. The `listen`
+    /// future resolves when all sessions are gone or when the grace period has elapsed, whichever
+    /// comes first.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::{options, Server};
+    /// use std::time::Duration;
+    ///
+    /// let mut server = Server::new_with_fs_root("/tmp").shutdown_indicator(async {
+    ///    tokio::time::delay_for(Duration::from_secs(10)).await; // Shut the server down after 10 seconds.
+    ///    options::Shutdown::new()
+    ///      .grace_period(Duration::from_secs(5)) // Allow 5 seconds to finish running transfers.
+    /// });
+    /// ```
+    ///
+    /// [`options::Shutdown`]: options/struct.Shutdown.html
+    pub fn shutdown_indicator<I>(mut self, indicator: I) -> Self
+    where
+        I: Future<Output = options::Shutdown> + Send + Sync + 'static,
+    {
+        self.shutdown_indicator = Some(Box::pin(indicator));
+        self
+    }
+
+    /// Returns a [`ServerHandle`] that can be used to inspect the server once it is running, for
+    /// instance to find out which address it is bound to.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// let server = Server::new_with_fs_root("/srv/ftp");
+    /// let handle = server.handle();
+    /// ```
+    ///
+    /// [`ServerHandle`]: struct.ServerHandle.html
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
     /// Runs the main ftp process asynchronously. Should be started in a async runtime context.
     ///
     /// # Example
@@ -299,39 +883,251 @@ where
     /// drop(rt);
     /// ```
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// This function panics when called with invalid addresses or when the process is unable to
-    /// `bind()` to the address.
+    /// This function returns an error when called with an invalid address or when the process is
+    /// unable to `bind()` to the address, or when the host name given to
+    /// [`passive_host`](#method.passive_host) cannot be resolved. The same goes for the address of
+    /// the [`admin_listener`](#method.admin_listener). It also fails when the metrics cannot be
+    /// registered or the certificates or key given to [`ftps`](#method.ftps) cannot be loaded.
+    /// Errors that occur while accepting connections are logged and the server keeps running.
     #[tracing_attributes::instrument]
-    pub async fn listen<T: Into<String> + Debug>(self, bind_address: T) {
-        match self.proxy_protocol_mode {
-            ProxyMode::On { external_control_port } => self.listen_proxy_protocol_mode(bind_address, external_control_port).await,
-            ProxyMode::Off => self.listen_normal_mode(bind_address).await,
-        }
+    pub async fn listen<T: Into<String> + Debug>(self, bind_address: T) -> Result<(), ServerError> {
+        let listener = bind(bind_address.into()).await?;
+        self.listen_on(listener).await
     }
 
+    /// Runs the main ftp process asynchronously on a listener that was bound by the caller. This
+    /// allows binding to port 0, passing on sockets inherited from a parent process or setting
+    /// socket options that libunftp does not expose.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    /// use tokio::net::TcpListener;
+    /// use tokio::runtime::Runtime;
+    ///
+    /// let mut rt = Runtime::new().unwrap();
+    /// let listener = rt.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    /// let addr = listener.local_addr().unwrap();
+    /// let server = Server::new_with_fs_root("/srv/ftp");
+    /// rt.spawn(server.listen_on(listener));
+    /// // ...
+    /// drop(rt);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error when the host name given to
+    /// [`passive_host`](#method.passive_host) cannot be resolved, when the
+    /// [`admin_listener`](#method.admin_listener) cannot bind to its address, when the metrics
+    /// cannot be registered or when the certificates or key given to [`ftps`](#method.ftps) cannot
+    /// be loaded. Errors that occur while accepting connections are logged and the server keeps
+    /// running.
     #[tracing_attributes::instrument]
-    async fn listen_normal_mode<T: Into<String> + Debug>(self, bind_address: T) {
-        // TODO: Propagate errors to caller instead of doing unwraps.
-        let addr: std::net::SocketAddr = bind_address.into().parse().unwrap();
-        let mut listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    pub async fn listen_on(mut self, listener: tokio::net::TcpListener) -> Result<(), ServerError> {
+        self.passive_host = resolve_passive_host(&self.passive_host).await?;
+        let ftps_mode = self.ftps_config()?;
+        self.metrics_collectors()?;
+        #[cfg(feature = "admin_http")]
+        let _admin = match self.admin_address.take() {
+            // Stops serving when dropped at the end of this function.
+            Some(address) => {
+                let registry = match &self.metrics_options {
+                    Some(options) => options.target_registry(),
+                    None => prometheus::default_registry().clone(),
+                };
+                Some(super::admin::start(address, self.handle.clone(), registry).await?)
+            }
+            None => None,
+        };
+        // Stops watching when dropped at the end of this function.
+        let _certs_watcher = match (ftps_mode.certs(), self.ftps_reload_interval) {
+            (Some(certs), Some(interval)) => Some(tls::watch(certs, interval)),
+            _ => None,
+        };
+        self.handle.set_local_addr(listener.local_addr().ok());
+        if let (true, FTPSConfig::Off) = (self.ftps_required.requires_control(), &ftps_mode) {
+            warn!("TLS is required by the FTPS policy but FTPS is not configured, clients won't be able to log in");
+        }
+        let shutdown_indicator = self.shutdown_indicator.take().unwrap_or_else(|| Box::pin(futures::future::pending()));
+        let shutdown_options = match self.proxy_protocol_mode {
+            ProxyMode::On { external_control_port } => self.listen_proxy_protocol_mode(listener, external_control_port, shutdown_indicator).await?,
+            ProxyMode::Off => self.listen_normal_mode(listener, shutdown_indicator).await?,
+        };
+        self.handle.set_local_addr(None);
+
+        info!("Shutting down, allowing {:?} for running sessions to finish", shutdown_options.grace_period);
+        let notifier = self.shutdown_notifier;
+        notifier.notify();
+        match tokio::time::timeout(shutdown_options.grace_period, notifier.linger()).await {
+            Ok(_) => info!("All sessions ended, shutdown complete"),
+            Err(_) => warn!("Shutdown grace period elapsed while sessions were still running"),
+        }
+        Ok(())
+    }
+
+    /// Serves a single FTP session over the given stream. The stream can be anything that
+    /// implements `AsyncRead` and `AsyncWrite`, like an in-memory duplex pipe in tests or a
+    /// connection accepted by another network stack. `local_addr` is the address that the client
+    /// connected to and `peer_addr` is the address of the client on the other end.
+    ///
+    /// This returns once the greeting has been sent; the session itself runs in the background
+    /// until the client quits, the session times out or the server is shut down by means of
+    /// [`shutdown_indicator`](#method.shutdown_indicator).
+    ///
+    /// Passive mode data connections are set up on the IP address of `local_addr`, which is also
+    /// the address given in `PASV` replies unless [`passive_host`](#method.passive_host) is set.
+    /// The PROXY protocol mode does not apply to these sessions. The limits set with
+    /// [`max_sessions`](#method.max_sessions) and [`max_sessions_per_ip`](#method.max_sessions_per_ip)
+    /// do apply.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when the greeting could not be written to the stream, when the host name
+    /// given to [`passive_host`](#method.passive_host) cannot be resolved, when the metrics cannot
+    /// be registered or when the certificates or key given to [`ftps`](#method.ftps) cannot be
+    /// loaded.
+    pub async fn serve_connection<IO>(&self, stream: IO, local_addr: SocketAddr, peer_addr: SocketAddr) -> Result<(), ServerError>
+    where
+        IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        info!("Serving control channel connection from {:?}", peer_addr);
+        let tag = match admit(&self.access, peer_addr).await {
+            Ok(tag) => tag,
+            Err(msg) => {
+                refuse_connection(stream, peer_addr, self.ftps_implicit, &msg).await;
+                return Ok(());
+            }
+        };
+        let permit = match self.session_limits.try_acquire(peer_addr.ip()) {
+            Some(permit) => permit,
+            None => {
+                refuse_connection(stream, peer_addr, self.ftps_implicit, TOO_MANY_CONNECTIONS).await;
+                return Ok(());
+            }
+        };
+        self.ftps_config()?;
+        self.metrics_collectors()?;
+        let mut params: LoopConfig<S, U> = self.into();
+        params.tag = tag;
+        params.passive_host = self.resolved_passive_host().await?;
+        spawn_loop::<S, U, IO>(params, stream, local_addr, peer_addr, None, None, self.shutdown_notifier.subscribe(), permit)
+            .await
+            .map_err(|err| err.context(ServerErrorKind::ConnectionError).into())
+    }
+
+    // Resolves the passive host on first use and hands out the cached result after that. Holding
+    // the lock while resolving makes concurrent callers wait for the one lookup.
+    async fn resolved_passive_host(&self) -> Result<options::PassiveHost, ServerError> {
+        let mut resolved = self.resolved_passive_host.lock().await;
+        if let Some(host) = &*resolved {
+            return Ok(host.clone());
+        }
+        let host = resolve_passive_host(&self.passive_host).await?;
+        *resolved = Some(host.clone());
+        Ok(host)
+    }
+
+    // Loads the files given to Server::ftps, Server::ftps_sni_certificate and
+    // Server::ftps_client_auth and applies the Server::ftps_tls_options the first time this is
+    // called, so that the files are read once and a failure to load them is reported when the
+    // server starts.
+    fn ftps_config(&self) -> Result<FTPSConfig, ServerError> {
+        let mut ftps_mode = self.ftps_mode.lock().unwrap();
+        if let Some(config) = &*ftps_mode {
+            return Ok(config.clone());
+        }
+        let config = match &self.ftps_certs {
+            Some(default) => FTPSConfig::new(default.clone(), &self.ftps_sni_certs, self.ftps_client_auth.clone(), &self.ftps_tls_options)
+                .context(ServerErrorKind::TlsConfigError)?,
+            None => FTPSConfig::Off,
+        };
+        self.handle.set_certs(config.certs());
+        *ftps_mode = Some(config.clone());
+        Ok(config)
+    }
+
+    // Creates and registers the metrics the first time this is called if they are enabled, so that
+    // a failure to register them is reported when the server starts.
+    fn metrics_collectors(&self) -> Result<Option<Arc<Metrics>>, ServerError> {
+        let options = match &self.metrics_options {
+            Some(options) => options,
+            None => return Ok(None),
+        };
+        let mut metrics = self.metrics.lock().unwrap();
+        if metrics.is_none() {
+            let registered = Metrics::for_options(options).context(ServerErrorKind::MetricsError)?;
+            *metrics = Some(registered);
+        }
+        Ok(metrics.clone())
+    }
+
+    // Accepts control connections until the shutdown indicator resolves.
+    #[tracing_attributes::instrument(skip(shutdown_indicator))]
+    async fn listen_normal_mode(
+        &self,
+        mut listener: tokio::net::TcpListener,
+        mut shutdown_indicator: Pin<Box<dyn Future<Output = options::Shutdown> + Send + Sync>>,
+    ) -> Result<options::Shutdown, ServerError> {
+        let mut accept_backoff = MIN_ACCEPT_BACKOFF;
         loop {
-            let (tcp_stream, socket_addr) = listener.accept().await.unwrap();
-            info!("Incoming control channel connection from {:?}", socket_addr);
-            let params: LoopConfig<S, U> = (&self).into();
-            let result = spawn_loop::<S, U>(params, tcp_stream, None, None).await;
-            if result.is_err() {
-                warn!("Could not spawn control channel loop for connection: {:?}", result.err().unwrap())
+            tokio::select! {
+                result = listener.accept() => {
+                    let (tcp_stream, socket_addr) = match result {
+                        Ok(v) => v,
+                        Err(err) => {
+                            handle_accept_error(err, &mut accept_backoff).await;
+                            continue;
+                        }
+                    };
+                    accept_backoff = MIN_ACCEPT_BACKOFF;
+                    info!("Incoming control channel connection from {:?}", socket_addr);
+                    let local_addr = match tcp_stream.local_addr() {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            warn!("Could not determine local address of control connection: {}", err);
+                            continue;
+                        }
+                    };
+                    let mut params: LoopConfig<S, U> = self.into();
+                    let shutdown_listener = self.shutdown_notifier.subscribe();
+                    let access = self.access.clone();
+                    let session_limits = self.session_limits.clone();
+                    let implicit_tls = self.ftps_implicit;
+                    // Set up the session in its own task so that a slow access check or (TLS) handshake doesn't hold up the accept loop.
+                    tokio::spawn(async move {
+                        params.tag = match admit(&access, socket_addr).await {
+                            Ok(tag) => tag,
+                            Err(msg) => return refuse_connection(tcp_stream, socket_addr, implicit_tls, &msg).await,
+                        };
+                        let permit = match session_limits.try_acquire(socket_addr.ip()) {
+                            Some(permit) => permit,
+                            None => return refuse_connection(tcp_stream, socket_addr, implicit_tls, TOO_MANY_CONNECTIONS).await,
+                        };
+                        let result = spawn_loop::<S, U, _>(params, tcp_stream, local_addr, socket_addr, None, None, shutdown_listener, permit).await;
+                        if result.is_err() {
+                            warn!("Could not spawn control channel loop for connection: {:?}", result.err().unwrap())
+                        }
+                    });
+                },
+                shutdown_options = &mut shutdown_indicator => {
+                    return Ok(shutdown_options);
+                },
             }
         }
     }
 
-    #[tracing_attributes::instrument]
-    async fn listen_proxy_protocol_mode<T: Into<String> + Debug>(mut self, bind_address: T, external_control_port: u16) {
-        // TODO: Propagate errors to caller instead of doing unwraps.
-        let addr: std::net::SocketAddr = bind_address.into().parse().unwrap();
-        let mut listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    // Accepts proxied control and data connections until the shutdown indicator resolves.
+    #[tracing_attributes::instrument(skip(shutdown_indicator))]
+    async fn listen_proxy_protocol_mode(
+        &mut self,
+        mut listener: tokio::net::TcpListener,
+        external_control_port: u16,
+        mut shutdown_indicator: Pin<Box<dyn Future<Output = options::Shutdown> + Send + Sync>>,
+    ) -> Result<options::Shutdown, ServerError> {
+        let mut accept_backoff = MIN_ACCEPT_BACKOFF;
 
         // this callback is used by all sessions, basically only to
         // request for a passive listening port.
@@ -346,8 +1142,15 @@ where
 
             tokio::select! {
 
-                Some(tcp_stream) = incoming.next() => {
-                    let mut tcp_stream = tcp_stream.unwrap();
+                Some(result) = incoming.next() => {
+                    let mut tcp_stream = match result {
+                        Ok(tcp_stream) => tcp_stream,
+                        Err(err) => {
+                            handle_accept_error(err, &mut accept_backoff).await;
+                            continue;
+                        }
+                    };
+                    accept_backoff = MIN_ACCEPT_BACKOFF;
                     let socket_addr = tcp_stream.peer_addr();
 
                     info!("Incoming proxy connection from {:?}", socket_addr);
@@ -365,17 +1168,35 @@ where
                     if connection.to_port == external_control_port {
                         let socket_addr = SocketAddr::new(connection.from_ip, connection.from_port);
                         info!("Connection from {:?} is a control connection", socket_addr);
-                        let params: LoopConfig<S,U> = (&self).into();
-                        let result = spawn_loop::<S,U>(params, tcp_stream, Some(connection), Some(proxyloop_msg_tx.clone())).await;
-                        if result.is_err() {
-                            warn!("Could not spawn control channel loop for connection: {:?}", result.err().unwrap())
-                        }
+                        let local_addr = SocketAddr::new(connection.to_ip, connection.to_port);
+                        let mut params: LoopConfig<S,U> = (&*self).into();
+                        let proxyloop_msg_tx = proxyloop_msg_tx.clone();
+                        let shutdown_listener = self.shutdown_notifier.subscribe();
+                        let access = self.access.clone();
+                        let session_limits = self.session_limits.clone();
+                        let implicit_tls = self.ftps_implicit;
+                        tokio::spawn(async move {
+                            params.tag = match admit(&access, socket_addr).await {
+                                Ok(tag) => tag,
+                                Err(msg) => return refuse_connection(tcp_stream, socket_addr, implicit_tls, &msg).await,
+                            };
+                            let permit = match session_limits.try_acquire(connection.from_ip) {
+                                Some(permit) => permit,
+                                None => return refuse_connection(tcp_stream, socket_addr, implicit_tls, TOO_MANY_CONNECTIONS).await,
+                            };
+                            let result = spawn_loop::<S,U,_>(params, tcp_stream, local_addr, socket_addr, Some(connection), Some(proxyloop_msg_tx), shutdown_listener, permit).await;
+                            if result.is_err() {
+                                warn!("Could not spawn control channel loop for connection: {:?}", result.err().unwrap())
+                            }
+                        });
                     } else {
                         // handle incoming data connections
                         info!("Connection from {:?} is a data connection: {:?}, {}", socket_addr, self.passive_ports, connection.to_port);
                         if !self.passive_ports.contains(&connection.to_port) {
                             warn!("Incoming proxy connection going to unconfigured port! This port is not configured as a passive listening port: port {} not in passive port range {:?}", connection.to_port, self.passive_ports);
-                            tcp_stream.shutdown(Shutdown::Both).unwrap();
+                            if let Err(err) = tcp_stream.shutdown(Shutdown::Both) {
+                                warn!("Could not shut down data connection to unconfigured port: {}", err);
+                            }
                             continue;
                         }
                         self.dispatch_data_connection(tcp_stream, connection).await;
@@ -383,11 +1204,17 @@ where
                 },
                 Some(msg) = proxyloop_msg_rx.next() => {
                     match msg {
-                        ProxyLoopMsg::AssignDataPortCommand (session_arc) => {
-                            self.select_and_register_passive_port(session_arc).await;
+                        ProxyLoopMsg::AssignDataPortCommand (session_arc, command, generation) => {
+                            self.select_and_register_passive_port(session_arc, command, generation, proxyloop_msg_tx.clone()).await;
+                        },
+                        ProxyLoopMsg::DataPortExpired (session_arc, port, generation) => {
+                            self.expire_data_port(session_arc, port, generation).await;
                         },
                     }
                 },
+                shutdown_options = &mut shutdown_indicator => {
+                    return Ok(shutdown_options);
+                },
             };
         }
     }
@@ -400,17 +1227,21 @@ where
     async fn dispatch_data_connection(&mut self, tcp_stream: tokio::net::TcpStream, connection: ConnectionTuple) {
         if let Some(switchboard) = &mut self.proxy_protocol_switchboard {
             match switchboard.get_session_by_incoming_data_connection(&connection).await {
-                Some(session) => {
+                Some((session, generation)) => {
                     let mut session = session.lock().await;
                     let tx_some = session.control_msg_tx.clone();
                     if let Some(tx) = tx_some {
-                        spawn_processing(&mut session, tcp_stream, tx);
+                        if let Err(err) = spawn_processing(&mut session, tcp_stream, tx, generation) {
+                            warn!("Dropping data connection ({:?}): {}", connection, err);
+                        }
                         switchboard.unregister(&connection);
                     }
                 }
                 None => {
                     warn!("Unexpected connection ({:?})", connection);
-                    tcp_stream.shutdown(Shutdown::Both).unwrap();
+                    if let Err(err) = tcp_stream.shutdown(Shutdown::Both) {
+                        warn!("Could not shut down unexpected data connection: {}", err);
+                    }
                     return;
                 }
             }
@@ -418,41 +1249,165 @@ where
     }
 
     #[tracing_attributes::instrument]
-    async fn select_and_register_passive_port(&mut self, session_arc: SharedSession<S, U>) {
+    async fn select_and_register_passive_port(
+        &mut self,
+        session_arc: SharedSession<S, U>,
+        command: PassiveCommand,
+        generation: u64,
+        proxyloop_msg_tx: ProxyLoopSender<S, U>,
+    ) {
         info!("Received internal message to allocate data port");
         // 1. reserve a port
         // 2. put the session_arc and tx in the hashmap with srcip+dstport as key
         // 3. put expiry time in the LIFO list
-        // 4. send reply to client: "Entering Passive Mode ({},{},{},{},{},{})"
+        // 4. send reply to client: "Entering Passive Mode ({},{},{},{},{},{})" or "Entering Extended Passive Mode (|||port|)"
 
-        let mut p1 = 0;
-        let mut p2 = 0;
+        let mut port = 0;
         if let Some(switchboard) = &mut self.proxy_protocol_switchboard {
-            let port = switchboard.reserve_next_free_port(session_arc.clone()).await.unwrap();
+            match switchboard.reserve_next_free_port(session_arc.clone(), generation).await {
+                Ok(reserved) => port = reserved,
+                Err(err) => {
+                    warn!("Could not reserve a data port: {:?}", err);
+                    let session = session_arc.lock().await;
+                    if let Some(metrics) = &session.metrics {
+                        metrics.add_passive_port_failure_metric();
+                    }
+                    if let Some(mut tx) = session.control_msg_tx.clone() {
+                        let reply = InternalMsg::CommandChannelReply(ReplyCode::CantOpenDataConnection, "No data connection established".to_string());
+                        if let Err(err) = tx.send(reply).await {
+                            warn!("Could not notify control channel of failed data port reservation: {}", err);
+                        }
+                    }
+                    return;
+                }
+            }
             info!("Reserving data port: {:?}", port);
-            p1 = port >> 8;
-            p2 = port - (p1 * 256);
         }
         let session = session_arc.lock().await;
         if let Some(conn) = session.control_connection_info {
-            let octets = match conn.to_ip {
-                IpAddr::V4(ip) => ip.octets(),
-                IpAddr::V6(_) => panic!("Won't happen."),
-            };
             let tx_some = session.control_msg_tx.clone();
             if let Some(tx) = tx_some {
                 let mut tx = tx.clone();
-                tx.send(InternalMsg::CommandChannelReply(
-                    ReplyCode::EnteringPassiveMode,
-                    format!("Entering Passive Mode ({},{},{},{},{},{})", octets[0], octets[1], octets[2], octets[3], p1, p2),
-                ))
-                .await
-                .unwrap();
+                let ip = self.passive_host.advertised_ip(conn.to_ip, conn.from_ip);
+                if let Reply::CodeAndMsg { code, msg } = command.reply(ip, port) {
+                    tx.send(InternalMsg::CommandChannelReply(code, msg)).await.unwrap();
+                }
+            }
+        }
+        let session_end = session.end.clone();
+        let span = session.span.clone();
+        drop(session);
+
+        // Give the port back if the client doesn't connect to it in time or the session ends first.
+        let accept_timeout = self.data_timeouts.passive_accept;
+        let mut proxyloop_msg_tx = proxyloop_msg_tx;
+        let expire = async move {
+            tokio::select! {
+                _ = tokio::time::delay_for(accept_timeout) => {},
+                _ = session_end => {},
+            }
+            if let Err(err) = proxyloop_msg_tx.send(ProxyLoopMsg::DataPortExpired(session_arc, port, generation)).await {
+                warn!("Could not expire data port {}: {}", port, err);
+            }
+        };
+        tokio::spawn(expire.instrument(span));
+    }
+
+    #[tracing_attributes::instrument]
+    async fn expire_data_port(&mut self, session_arc: SharedSession<S, U>, port: u16, generation: u64) {
+        let session = session_arc.lock().await;
+        if let (Some(switchboard), Some(conn)) = (&mut self.proxy_protocol_switchboard, session.control_connection_info) {
+            if !switchboard.release(&conn, port) {
+                // The client connected in time.
+                return;
+            }
+            match session.control_msg_tx.clone() {
+                Some(mut tx) if !tx.is_closed() => {
+                    warn!("Client {:?} did not connect to data port {} in time", conn.from_ip, port);
+                    if let Err(err) = tx.send(InternalMsg::DataConnectionTimedOut(generation)).await {
+                        warn!("Could not notify control channel of data connection timeout: {}", err);
+                    }
+                }
+                _ => debug!("Released data port {} of ended session", port),
             }
         }
     }
 }
 
+// Parses the given address and binds a TCP listener to it.
+async fn bind(bind_address: String) -> Result<tokio::net::TcpListener, ServerError> {
+    let addr = match bind_address.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(err) => return Err(err.context(ServerErrorKind::InvalidBindAddress { address: bind_address }).into()),
+    };
+    match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => Ok(listener),
+        Err(err) => Err(err.context(ServerErrorKind::BindError { address: bind_address }).into()),
+    }
+}
+
+// Runs the access checks for a new client, returning the tag for its session if it is let in or the
+// message to refuse it with otherwise.
+async fn admit(access: &AccessControl, peer_addr: SocketAddr) -> Result<Option<String>, String> {
+    match access.check(peer_addr).await {
+        options::ConnectionDecision::Accept => Ok(None),
+        options::ConnectionDecision::Tag(tag) => Ok(Some(tag)),
+        options::ConnectionDecision::Reject(msg) => Err(msg),
+    }
+}
+
+// Tells a client that it was refused with a 421 reply. Clients that expect a TLS handshake first
+// (implicit FTPS) wouldn't understand a plaintext reply so they are just disconnected.
+async fn refuse_connection<IO: AsyncWrite + Unpin>(mut stream: IO, peer_addr: SocketAddr, implicit_tls: bool, msg: &str) {
+    warn!("Refusing connection from {:?}: {:?}", peer_addr, msg);
+    if implicit_tls {
+        return;
+    }
+    // The message may come from a connection filter and must not end the reply line early.
+    let msg = msg.replace(&['\r', '\n'][..], " ");
+    if let Err(err) = stream.write_all(format!("421 {}\r\n", msg).as_bytes()).await {
+        warn!("Could not send reply to refused connection: {}", err);
+    }
+}
+
+// Resolves a passive host given by name to its first IPv4 address since that is all PASV can
+// advertise. Other variants are returned as is.
+async fn resolve_passive_host(host: &options::PassiveHost) -> Result<options::PassiveHost, ServerError> {
+    let name = match host {
+        options::PassiveHost::Dns(name) => name,
+        _ => return Ok(host.clone()),
+    };
+    let kind = || ServerErrorKind::PassiveHostResolveError { host: name.clone() };
+    let mut addrs = tokio::net::lookup_host((name.as_str(), 0)).await.context(kind())?;
+    let ipv4 = addrs.find_map(|addr| match addr.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    });
+    match ipv4 {
+        Some(ip) => {
+            info!("Resolved passive host {} to {}", name, ip);
+            Ok(options::PassiveHost::Ip(ip))
+        }
+        None => Err(kind().into()),
+    }
+}
+
+// Errors returned by accept() that only concern the connection being accepted are logged and
+// ignored. Other errors, like running out of file descriptors, are likely to persist for a while so
+// we back off before accepting again.
+async fn handle_accept_error(err: io::Error, backoff: &mut Duration) {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset => {
+            warn!("Could not accept connection: {}", err);
+        }
+        _ => {
+            warn!("Error accepting connections, retrying in {:?}: {}", backoff, err);
+            tokio::time::delay_for(*backoff).await;
+            *backoff = std::cmp::min(*backoff * 2, MAX_ACCEPT_BACKOFF);
+        }
+    }
+}
+
 impl<S, U> From<&Server<S, U>> for LoopConfig<S, U>
 where
     U: UserDetail + 'static,
@@ -464,11 +1419,22 @@ where
         LoopConfig {
             authenticator: server.authenticator.clone(),
             storage: (server.storage)(),
-            ftps_config: server.ftps_mode.clone(),
-            collect_metrics: server.collect_metrics,
+            ftps_config: server.ftps_mode.lock().unwrap().clone().unwrap_or(FTPSConfig::Off),
+            ftps_implicit: server.ftps_implicit,
+            ftps_required: server.ftps_required,
+            metrics: server.metrics.lock().unwrap().clone(),
+            user_labels: server.user_labels.clone(),
             greeting: server.greeting,
             idle_session_timeout: server.idle_session_timeout,
             passive_ports: server.passive_ports.clone(),
+            passive_host: server.passive_host.clone(),
+            active_mode: server.active_mode.clone(),
+            tag: None,
+            failed_logins: server.access.failed_logins.clone(),
+            global_buckets: server.global_buckets.clone(),
+            session_rate_limits: server.session_rate_limits,
+            data_timeouts: server.data_timeouts,
+            sessions: server.handle.registry(),
         }
     }
 }
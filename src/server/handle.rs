@@ -0,0 +1,156 @@
+//! Contains the `ServerHandle` struct that can be used to observe a running [`Server`].
+//!
+//! [`Server`]: ../struct.Server.html
+
+use super::{
+    error::{ServerError, ServerErrorKind},
+    registry::SessionRegistry,
+    tls::{CertResolver, FTPSNotAvailable},
+};
+use failure::{Fail, ResultExt};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+/// A cheaply cloneable handle to a [`Server`]. It is obtained with [`Server::handle`] before the
+/// server is started and stays usable while the server runs.
+///
+/// # Example
+///
+/// ```rust
+/// use libunftp::Server;
+///
+/// let server = Server::new_with_fs_root("/srv/ftp");
+/// let handle = server.handle();
+/// assert_eq!(handle.local_addr(), None); // Not listening yet
+/// ```
+///
+/// [`Server`]: struct.Server.html
+/// [`Server::handle`]: struct.Server.html#method.handle
+#[derive(Clone, Debug, Default)]
+pub struct ServerHandle {
+    local_addr: Arc<RwLock<Option<SocketAddr>>>,
+    sessions: SessionRegistry,
+    certs: Arc<RwLock<Option<Arc<CertResolver>>>>,
+}
+
+impl ServerHandle {
+    /// Returns the address the server's control channel listener is bound to, or `None` when the
+    /// server is not listening. This is useful when binding to port 0 and letting the operating
+    /// system pick a free port.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.read().unwrap()
+    }
+
+    pub(crate) fn set_local_addr(&self, addr: Option<SocketAddr>) {
+        *self.local_addr.write().unwrap() = addr;
+    }
+
+    /// Returns the sessions that are connected at the moment, oldest first.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// let server = Server::new_with_fs_root("/srv/ftp");
+    /// let handle = server.handle();
+    /// for session in handle.sessions() {
+    ///     println!("{} {} {:?}", session.id, session.peer_addr, session.username);
+    /// }
+    /// ```
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        self.sessions.list()
+    }
+
+    /// Closes the control connection of the session with the given id, which also aborts its
+    /// transfers. The client gets a `421` reply. Returns `false` if there is no such session.
+    pub fn disconnect(&self, id: u64) -> bool {
+        self.sessions.disconnect(id)
+    }
+
+    /// Loads the certificates and key files given to [`Server::ftps`] and
+    /// [`Server::ftps_sni_certificate`] and the certificate authorities given to
+    /// [`Server::ftps_client_auth`] again, for instance after they were renewed. New TLS handshakes
+    /// use the new certificates, sessions that are secured already keep going.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error when FTPS is not configured, the server has not started yet or any of the
+    /// files cannot be loaded. The current certificates all stay in use in that case.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use libunftp::Server;
+    ///
+    /// let server = Server::new_with_fs_root("/srv/ftp");
+    /// let handle = server.handle();
+    /// assert!(handle.reload_certificates().is_err()); // FTPS is not configured
+    /// ```
+    ///
+    /// [`Server::ftps`]: struct.Server.html#method.ftps
+    /// [`Server::ftps_sni_certificate`]: struct.Server.html#method.ftps_sni_certificate
+    /// [`Server::ftps_client_auth`]: struct.Server.html#method.ftps_client_auth
+    pub fn reload_certificates(&self) -> Result<(), ServerError> {
+        match &*self.certs.read().unwrap() {
+            Some(certs) => Ok(certs.reload().context(ServerErrorKind::TlsConfigError)?),
+            None => Err(FTPSNotAvailable.context(ServerErrorKind::TlsConfigError).into()),
+        }
+    }
+
+    pub(crate) fn set_certs(&self, certs: Option<Arc<CertResolver>>) {
+        *self.certs.write().unwrap() = certs;
+    }
+
+    pub(crate) fn registry(&self) -> SessionRegistry {
+        self.sessions.clone()
+    }
+}
+
+/// Information about a connected session, as returned by [`ServerHandle::sessions`].
+///
+/// [`ServerHandle::sessions`]: struct.ServerHandle.html#method.sessions
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// Identifies the session, for use with [`ServerHandle::disconnect`].
+    ///
+    /// [`ServerHandle::disconnect`]: struct.ServerHandle.html#method.disconnect
+    pub id: u64,
+    /// The address of the client.
+    pub peer_addr: SocketAddr,
+    /// The name of the user once logged in.
+    pub username: Option<String>,
+    /// When the client connected.
+    pub connected_at: SystemTime,
+    /// When the user logged in.
+    pub logged_in_at: Option<SystemTime>,
+    /// The current working directory.
+    pub cwd: PathBuf,
+    /// The transfer that is running at the moment, if any.
+    pub transfer: Option<TransferInfo>,
+    /// The number of bytes sent to the client with completed downloads.
+    pub bytes_sent: u64,
+    /// The number of bytes received from the client with completed uploads.
+    pub bytes_received: u64,
+    /// The tag that the [`ConnectionFilter`] attached to the session, if any.
+    ///
+    /// [`ConnectionFilter`]: options/trait.ConnectionFilter.html
+    pub tag: Option<String>,
+}
+
+/// Information about a running transfer, see [`SessionInfo`].
+///
+/// [`SessionInfo`]: struct.SessionInfo.html
+#[derive(Clone, Debug)]
+pub struct TransferInfo {
+    /// The FTP command that started the transfer, like `RETR` or `LIST`.
+    pub command: String,
+    /// The file or directory being transferred.
+    pub path: PathBuf,
+    /// When the transfer started.
+    pub started_at: SystemTime,
+}
@@ -0,0 +1,81 @@
+//! Contains the stream wrapper that fails reads and writes once no data moved for too long.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{Delay, Instant},
+};
+
+// Wraps a data connection and fails pending reads and writes with `TimedOut` when the wrapped
+// stream made no progress within the timeout. The flag returned by `timed_out` tells the owner that
+// this happened, also when the error got lost in a storage backend.
+pub struct Inactivity<T> {
+    inner: T,
+    timeout: Duration,
+    delay: Delay,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl<T> Inactivity<T> {
+    pub fn new(inner: T, timeout: Duration) -> Self {
+        Inactivity {
+            inner,
+            timeout,
+            delay: tokio::time::delay_for(timeout),
+            timed_out: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn timed_out(&self) -> Arc<AtomicBool> {
+        self.timed_out.clone()
+    }
+
+    fn poll_io<R>(&mut self, cx: &mut Context<'_>, f: impl FnOnce(Pin<&mut T>, &mut Context<'_>) -> Poll<io::Result<R>>) -> Poll<io::Result<R>>
+    where
+        T: Unpin,
+    {
+        match f(Pin::new(&mut self.inner), cx) {
+            Poll::Ready(result) => {
+                self.delay.reset(Instant::now() + self.timeout);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match Pin::new(&mut self.delay).poll(cx) {
+                Poll::Ready(()) => {
+                    self.timed_out.store(true, Ordering::Relaxed);
+                    Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "no data moved on the data connection")))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Inactivity<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(cx, |inner, cx| inner.poll_read(cx, buf))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Inactivity<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(cx, |inner, cx| inner.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(cx, |inner, cx| inner.poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_io(cx, |inner, cx| inner.poll_shutdown(cx))
+    }
+}
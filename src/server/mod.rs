@@ -1,12 +1,23 @@
 //! Contains the `Server` struct that is used to configure and control a FTP server instance.
 
+mod access;
+#[cfg(feature = "admin_http")]
+mod admin;
 mod chancomms;
 mod controlchan;
 mod datachan;
+pub(crate) mod error;
+mod failed_logins;
 pub(crate) mod ftpserver;
+pub(crate) mod handle;
+mod inactivity;
 mod password;
 mod proxy_protocol;
+mod registry;
 mod session;
+mod session_limits;
+mod shutdown;
+mod throttle;
 mod tls;
 
 pub(crate) use chancomms::InternalMsg;
@@ -14,4 +25,5 @@ pub(crate) use controlchan::command::Command;
 pub(crate) use controlchan::reply::{Reply, ReplyCode};
 pub(crate) use controlchan::ControlChanErrorKind;
 pub(crate) use controlchan::Event;
+pub(crate) use failed_logins::LockoutKey;
 pub(self) use session::{Session, SessionState};
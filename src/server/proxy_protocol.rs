@@ -30,7 +30,7 @@ pub enum ProxyError {
     HeaderSize,
     NotProxyHdr,
     DecodeError,
-    IPv4Required,
+    UnknownAddressFamily,
     UnsupportedVersion,
 }
 
@@ -113,10 +113,10 @@ pub async fn get_peer_from_proxy_header(tcp_stream: &mut tokio::net::TcpStream)
             destination_port,
             ..
         } => {
-            if family == ProxyAddressFamily::IPv4 {
-                Ok(ConnectionTuple::new(source, source_port, destination, destination_port))
+            if family == ProxyAddressFamily::Unknown {
+                Err(ProxyError::UnknownAddressFamily)
             } else {
-                Err(ProxyError::IPv4Required)
+                Ok(ConnectionTuple::new(source, source_port, destination, destination_port))
             }
         }
         _ => Err(ProxyError::UnsupportedVersion),
@@ -129,6 +129,10 @@ pub fn construct_proxy_hash_key(connection: &ConnectionTuple, port: u16) -> Stri
     format!("{}.{}", connection.from_ip, port)
 }
 
+// A session that reserved a data port, together with the generation of the data connection that
+// it reserved the port for.
+type Reservation<S, U> = (SharedSession<S, U>, u64);
+
 /// Connect clients to the right data channel
 #[derive(Debug)]
 pub struct ProxyProtocolSwitchboard<S, U>
@@ -136,7 +140,7 @@ where
     S: StorageBackend<U>,
     U: UserDetail,
 {
-    switchboard: HashMap<String, Option<SharedSession<S, U>>>,
+    switchboard: HashMap<String, Option<Reservation<S, U>>>,
     port_range: Range<u16>,
 }
 
@@ -161,10 +165,10 @@ where
         }
     }
 
-    fn try_and_claim(&mut self, hash: String, session_arc: SharedSession<S, U>) -> Result<(), ProxyProtocolError> {
+    fn try_and_claim(&mut self, hash: String, session_arc: SharedSession<S, U>, generation: u64) -> Result<(), ProxyProtocolError> {
         match self.switchboard.get(&hash) {
             Some(_) => Err(ProxyProtocolError::EntryNotAvailable),
-            None => match self.switchboard.insert(hash, Some(session_arc)) {
+            None => match self.switchboard.insert(hash, Some((session_arc, generation))) {
                 Some(_) => {
                     warn!("This is a data race condition. This shouldn't happen");
                     // just return Ok anyway however
@@ -189,8 +193,14 @@ where
         }
     }
 
+    // Frees the given data port reserved for the client of the given control connection. Returns
+    // false if it was not reserved (anymore).
+    pub fn release(&mut self, connection: &ConnectionTuple, port: u16) -> bool {
+        self.switchboard.remove(&construct_proxy_hash_key(connection, port)).is_some()
+    }
+
     #[tracing_attributes::instrument]
-    pub async fn get_session_by_incoming_data_connection(&mut self, connection: &ConnectionTuple) -> Option<SharedSession<S, U>> {
+    pub async fn get_session_by_incoming_data_connection(&mut self, connection: &ConnectionTuple) -> Option<Reservation<S, U>> {
         let hash = Self::get_hash_with_connection(connection);
 
         match self.switchboard.get(&hash) {
@@ -203,7 +213,7 @@ where
     /// but initialize it to None
     // TODO: set a TTL on the hashmap entries
     #[tracing_attributes::instrument]
-    pub async fn reserve_next_free_port(&mut self, session_arc: SharedSession<S, U>) -> Result<u16, ProxyProtocolError> {
+    pub async fn reserve_next_free_port(&mut self, session_arc: SharedSession<S, U>, generation: u64) -> Result<u16, ProxyProtocolError> {
         let rng_length = self.port_range.end - self.port_range.start;
 
         let mut rng = OS_RNG.lock().await;
@@ -214,7 +224,7 @@ where
             if let Some(conn) = session.control_connection_info {
                 let hash = construct_proxy_hash_key(&conn, port as u16);
 
-                match &self.try_and_claim(hash.clone(), session_arc.clone()) {
+                match &self.try_and_claim(hash.clone(), session_arc.clone(), generation) {
                     Ok(_) => return Ok(port as u16),
                     Err(_) => continue,
                 }
@@ -232,7 +242,10 @@ mod tests {
     use proxy_protocol::version1::ProxyAddressFamily;
     use proxy_protocol::ProxyHeader;
     use std::net::Shutdown;
-    use std::net::{IpAddr::V4, Ipv4Addr};
+    use std::net::{
+        IpAddr::{V4, V6},
+        Ipv4Addr, Ipv6Addr,
+    };
     use std::time::Duration;
     use tokio::io::AsyncWriteExt;
     use tokio::time::delay_for;
@@ -320,4 +333,23 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn ipv6_header_parses_fine() {
+        let (mut s, mut c) = get_connected_tcp_streams().await;
+
+        let server = tokio::spawn(async move { super::get_peer_from_proxy_header(&mut s).await.unwrap() });
+        let client = tokio::spawn(async move {
+            c.write_all("PROXY TCP6 2001:db8::1 2001:db8::2 56324 2121\r\n".as_ref()).await.unwrap();
+            c.shutdown(Shutdown::Both).unwrap();
+        });
+
+        let res = tokio::join!(server, client);
+        let connection = res.0.unwrap();
+
+        assert_eq!(connection.from_ip, V6("2001:db8::1".parse::<Ipv6Addr>().unwrap()));
+        assert_eq!(connection.from_port, 56324);
+        assert_eq!(connection.to_ip, V6("2001:db8::2".parse::<Ipv6Addr>().unwrap()));
+        assert_eq!(connection.to_port, 2121);
+    }
 }
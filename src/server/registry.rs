@@ -0,0 +1,151 @@
+//! Contains the registry that keeps track of the sessions of a server so that they can be listed
+//! and disconnected through the `ServerHandle`.
+
+use super::handle::SessionInfo;
+use futures::channel::oneshot;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+// SessionRegistry is shared by the Server and its ServerHandle.
+#[derive(Clone, Debug, Default)]
+pub struct SessionRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    sessions: HashMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    // Fired to make the control loop of the session close the connection.
+    disconnect_tx: Option<oneshot::Sender<()>>,
+}
+
+impl SessionRegistry {
+    // Adds a new session for the client at the given address. The session is listed until the
+    // returned Registration is dropped and should close its control connection when the returned
+    // receiver resolves.
+    pub fn register(&self, peer_addr: SocketAddr, tag: Option<String>) -> (Registration, oneshot::Receiver<()>) {
+        let (disconnect_tx, disconnect_rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let info = SessionInfo {
+            id,
+            peer_addr,
+            username: None,
+            connected_at: SystemTime::now(),
+            logged_in_at: None,
+            cwd: PathBuf::from("/"),
+            transfer: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            tag,
+        };
+        inner.sessions.insert(
+            id,
+            Entry {
+                info,
+                disconnect_tx: Some(disconnect_tx),
+            },
+        );
+        let record = SessionRecord { id, registry: self.clone() };
+        (Registration { record }, disconnect_rx)
+    }
+
+    // Returns the sessions ordered by id, so oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut sessions: Vec<SessionInfo> = inner.sessions.values().map(|entry| entry.info.clone()).collect();
+        sessions.sort_by_key(|info| info.id);
+        sessions
+    }
+
+    // Tells the session with the given id to close its control connection. Returns false if
+    // there is no such session.
+    pub fn disconnect(&self, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.sessions.get_mut(&id).and_then(|entry| entry.disconnect_tx.take()) {
+            Some(tx) => tx.send(()).is_ok(),
+            None => inner.sessions.contains_key(&id),
+        }
+    }
+}
+
+// SessionRecord is used to keep the information about a session up to date. Updates are ignored
+// once the session was removed from the registry.
+#[derive(Clone, Debug)]
+pub struct SessionRecord {
+    id: u64,
+    registry: SessionRegistry,
+}
+
+impl SessionRecord {
+    // The id of the session, unique within the server.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn update<F: FnOnce(&mut SessionInfo)>(&self, f: F) {
+        if let Some(entry) = self.registry.inner.lock().unwrap().sessions.get_mut(&self.id) {
+            f(&mut entry.info);
+        }
+    }
+}
+
+// Registration is held by the control loop of a session and removes it from the registry when
+// dropped.
+#[derive(Debug)]
+pub struct Registration {
+    record: SessionRecord,
+}
+
+impl Registration {
+    pub fn record(&self) -> SessionRecord {
+        self.record.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.record.registry.inner.lock().unwrap().sessions.remove(&self.record.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionRegistry;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn lists_updates_and_disconnects_sessions() {
+        let registry = SessionRegistry::default();
+        let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let (first, mut first_rx) = registry.register(peer, None);
+        let (second, _second_rx) = registry.register(peer, Some("tagged".to_string()));
+
+        first.record().update(|info| info.username = Some("alice".to_string()));
+        let sessions = registry.list();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].username, Some("alice".to_string()));
+        assert_eq!(sessions[1].tag, Some("tagged".to_string()));
+
+        let id = sessions[0].id;
+        assert!(registry.disconnect(id));
+        assert_eq!(first_rx.try_recv(), Ok(Some(())));
+
+        drop(first);
+        drop(second);
+        assert!(registry.list().is_empty());
+        assert!(!registry.disconnect(id));
+    }
+}
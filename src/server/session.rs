@@ -1,17 +1,32 @@
 //! The session module implements per-connection session handling and currently also
 //! implements the handling for the *data* channel.
 
-use super::{chancomms::InternalMsg, controlchan::command::Command, proxy_protocol::ConnectionTuple, tls::FTPSConfig};
+use super::{
+    chancomms::InternalMsg, controlchan::command::Command, failed_logins::FailedLogins, handle::SessionInfo, proxy_protocol::ConnectionTuple,
+    registry::SessionRecord, throttle::Buckets, tls::FTPSConfig,
+};
 use crate::{
-    metrics,
+    auth::ClientCert,
+    metrics::{Metrics, UserLabels},
+    options::{DataTimeouts, FtpsRequired},
     storage::{Metadata, StorageBackend},
 };
-use futures::channel::mpsc::{Receiver, Sender};
-use std::{fmt::Debug, path::PathBuf, sync::Arc};
+use futures::{
+    channel::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    future::{FutureExt, Shared},
+};
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc};
+use tracing::Span;
 
 #[derive(PartialEq, Debug)]
 pub enum SessionState {
     New,
+    // USER was given by a client with a certificate, which is being checked to see if the client
+    // can log in without a password.
+    WaitCertAuth,
     WaitPass,
     WaitCmd,
 }
@@ -19,6 +34,9 @@ pub enum SessionState {
 // The session shared via an asynchronous lock
 pub type SharedSession<S, U> = Arc<tokio::sync::Mutex<Session<S, U>>>;
 
+// Resolves once the control connection of a session is gone.
+pub type SessionEnd = Shared<oneshot::Receiver<()>>;
+
 // This is where we keep the state for a ftp session.
 #[derive(Debug)]
 pub struct Session<S, U: Send + Sync + Debug>
@@ -34,8 +52,15 @@ where
     pub data_cmd_rx: Option<Receiver<Command>>,
     pub data_abort_tx: Option<Sender<()>>,
     pub data_abort_rx: Option<Receiver<()>>,
+    // Counts the data connection setups (PASV, EPSV, PORT, EPRT) so that the events of an earlier
+    // one can be told apart from those of the one that replaced it.
+    pub data_generation: u64,
+    // Dropped to close the listener of the last PASV or EPSV while the client didn't connect to it yet.
+    pub passive_listener: Option<oneshot::Sender<()>>,
     pub control_msg_tx: Option<Sender<InternalMsg>>,
     pub control_connection_info: Option<ConnectionTuple>,
+    // The address of the client on the other end of the control connection.
+    pub source: SocketAddr,
     pub cwd: std::path::PathBuf,
     pub rename_from: Option<PathBuf>,
     pub state: SessionState,
@@ -46,11 +71,44 @@ where
     pub cmd_tls: bool,
     // True if the data channel is in secure mode at the moment. Changed by the PROT command.
     pub data_tls: bool,
-    // True if metrics for prometheus are updated.
-    pub collect_metrics: bool,
+    // The verified certificate the client presented when the control channel was secured.
+    pub client_cert: Option<ClientCert>,
+    // Tells which channels must be protected by TLS. Starts with the server policy and is made
+    // stricter with the policy of the user once logged in.
+    pub ftps_required: FtpsRequired,
+    // The prometheus metrics of the server, if they are collected.
+    pub metrics: Option<Arc<Metrics>>,
+    // Hands out the user labels for the transfer metrics when per-user metrics are enabled.
+    pub user_labels: Option<Arc<UserLabels>>,
     // The starting byte for a STOR or RETR command. Set by the _Restart of Interrupted Transfer (REST)_
     // command to support resume functionality.
     pub start_pos: u64,
+    // True while a data transfer command (RETR, STOR, LIST, NLST) is being executed on the data channel.
+    pub data_busy: bool,
+    // True when the client sent ABOR during a transfer and still awaits the reply to it.
+    pub abort_pending: bool,
+    // True once the client sent EPSV ALL, after which only EPSV may be used to set up data connections.
+    pub epsv_all: bool,
+    // The tag that the connection filter attached to the session, if any.
+    pub tag: Option<String>,
+    // Keeps track of failed logins across sessions when brute-force protection is enabled.
+    pub failed_logins: Option<Arc<FailedLogins>>,
+    // The token buckets shared by all sessions for the server wide rate limits.
+    pub global_buckets: Buckets,
+    // The token buckets of this session, for the session rate limits or those of the user once
+    // logged in.
+    pub session_buckets: Buckets,
+    // The timeouts that apply to the data connections of this session.
+    pub data_timeouts: DataTimeouts,
+    // Passive listeners and data connections wait on this to clean up after the session.
+    pub end: SessionEnd,
+    // Taken by the control loop, which drops it when it exits and so resolves `end`.
+    pub end_tx: Option<oneshot::Sender<()>>,
+    // Keeps the entry of this session in the session registry up to date.
+    pub record: Option<SessionRecord>,
+    // The root span of the session. Tasks spawned for the session, like those of the data
+    // channel, run inside it so that their logs can be told apart per session.
+    pub span: Span,
 }
 
 impl<S, U: Send + Sync + Debug + 'static> Session<S, U>
@@ -59,7 +117,8 @@ where
     S::File: tokio::io::AsyncRead + Send,
     S::Metadata: Metadata,
 {
-    pub(super) fn new(storage: Arc<S>) -> Self {
+    pub(super) fn new(storage: Arc<S>, source: SocketAddr) -> Self {
+        let (end_tx, end_rx) = oneshot::channel();
         Session {
             user: Arc::new(None),
             username: None,
@@ -68,16 +127,41 @@ where
             data_cmd_rx: None,
             data_abort_tx: None,
             data_abort_rx: None,
+            data_generation: 0,
+            passive_listener: None,
             control_msg_tx: None,
             control_connection_info: None,
+            source,
             cwd: "/".into(),
             rename_from: None,
             state: SessionState::New,
             ftps_config: FTPSConfig::Off,
             cmd_tls: false,
+            client_cert: None,
             data_tls: false,
-            collect_metrics: false,
+            ftps_required: FtpsRequired::Off,
+            metrics: None,
+            user_labels: None,
             start_pos: 0,
+            data_busy: false,
+            abort_pending: false,
+            epsv_all: false,
+            tag: None,
+            failed_logins: None,
+            global_buckets: Buckets::default(),
+            session_buckets: Buckets::default(),
+            data_timeouts: DataTimeouts::default(),
+            end: end_rx.shared(),
+            end_tx: Some(end_tx),
+            record: None,
+            span: Span::none(),
+        }
+    }
+
+    // Updates the information about this session in the session registry.
+    pub fn update_info<F: FnOnce(&mut SessionInfo)>(&self, f: F) {
+        if let Some(record) = &self.record {
+            record.update(f);
         }
     }
 
@@ -86,11 +170,11 @@ where
         self
     }
 
-    pub fn metrics(mut self, collect_metrics: bool) -> Self {
-        if collect_metrics {
-            metrics::inc_session();
+    pub fn metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        if let Some(metrics) = &metrics {
+            metrics.inc_session();
         }
-        self.collect_metrics = collect_metrics;
+        self.metrics = metrics;
         self
     }
 
@@ -112,9 +196,9 @@ where
     S::Metadata: Metadata,
 {
     fn drop(&mut self) {
-        if self.collect_metrics {
+        if let Some(metrics) = &self.metrics {
             // Decrease the sessions metrics gauge when the session goes out of scope.
-            metrics::dec_session();
+            metrics.dec_session();
         }
     }
 }
@@ -0,0 +1,96 @@
+//! Contains the types used to cap the number of concurrent sessions, in total and per client IP
+//! address.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+// SessionLimits is owned by the Server, its clones share the counts. A Permit is taken out for every
+// admitted control connection before its session is set up and the connection is refused when none
+// can be had.
+#[derive(Clone, Debug, Default)]
+pub struct SessionLimits {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl SessionLimits {
+    // Returns a Permit for a new session from the given IP address or None if that would exceed
+    // one of the limits.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<Permit> {
+        let mut counts = self.counts.lock().unwrap();
+        let for_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if reached(self.max_sessions, counts.total) || reached(self.max_sessions_per_ip, for_ip) {
+            return None;
+        }
+        counts.total += 1;
+        counts.per_ip.insert(ip, for_ip + 1);
+        Some(Permit {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+fn reached(max: Option<usize>, count: usize) -> bool {
+    match max {
+        Some(max) => count >= max,
+        None => false,
+    }
+}
+
+// Permit is held by a session for as long as it lives and gives back its place when dropped.
+#[derive(Debug)]
+pub struct Permit {
+    ip: IpAddr,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(for_ip) = counts.per_ip.get_mut(&self.ip) {
+            *for_ip -= 1;
+            if *for_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionLimits;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn limits_are_enforced_and_released() {
+        let limits = SessionLimits {
+            max_sessions: Some(2),
+            max_sessions_per_ip: Some(1),
+            ..Default::default()
+        };
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let c = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+
+        let permit_a = limits.try_acquire(a).unwrap();
+        assert!(limits.try_acquire(a).is_none());
+        let _permit_b = limits.try_acquire(b).unwrap();
+        assert!(limits.try_acquire(c).is_none());
+
+        drop(permit_a);
+        assert!(limits.try_acquire(c).is_some());
+        assert!(limits.try_acquire(a).is_some());
+    }
+}
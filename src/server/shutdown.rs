@@ -0,0 +1,64 @@
+//! Contains the types used to notify running sessions of a (graceful) server shutdown and to wait
+//! for them to wind down.
+
+use tokio::sync::{broadcast, mpsc};
+
+// Notifier is owned by the Server. It broadcasts the shutdown signal to all sessions and keeps
+// track of them through the linger channel: every Listener holds a clone of the linger sender so
+// we know all sessions are gone once the receiving end yields None.
+#[derive(Debug)]
+pub struct Notifier {
+    shutdown_tx: broadcast::Sender<()>,
+    linger_tx: mpsc::Sender<()>,
+    linger_rx: mpsc::Receiver<()>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let (linger_tx, linger_rx) = mpsc::channel(1);
+        Notifier {
+            shutdown_tx,
+            linger_tx,
+            linger_rx,
+        }
+    }
+
+    // Creates a Listener for a new session.
+    pub fn subscribe(&self) -> Listener {
+        Listener {
+            shutdown_rx: self.shutdown_tx.subscribe(),
+            _linger_tx: self.linger_tx.clone(),
+        }
+    }
+
+    // Tells all subscribed sessions that the server is shutting down.
+    pub fn notify(&self) {
+        // An error only means that there are no sessions at the moment.
+        let _ = self.shutdown_tx.send(());
+    }
+
+    // Resolves when all the Listeners handed out by this Notifier have been dropped.
+    pub async fn linger(self) {
+        let Notifier { linger_tx, mut linger_rx, .. } = self;
+        drop(linger_tx);
+        let _ = linger_rx.recv().await;
+    }
+}
+
+// Listener is held by a session for as long as it lives.
+#[derive(Debug)]
+pub struct Listener {
+    shutdown_rx: broadcast::Receiver<()>,
+    _linger_tx: mpsc::Sender<()>,
+}
+
+impl Listener {
+    // Resolves when the server starts shutting down. Sessions outlive a Server that is dropped
+    // without being shut down, so this never resolves once the Notifier is gone.
+    pub async fn listen(&mut self) {
+        if let Err(broadcast::RecvError::Closed) = self.shutdown_rx.recv().await {
+            futures::future::pending::<()>().await;
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Contains the token bucket based reader and writer wrappers that limit the transfer rate of
+//! data connections.
+
+use crate::options::RateLimits;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Delay,
+};
+
+// A bucket that fills up with tokens, one per byte, at a fixed rate and holds at most a second
+// worth of them. Transfers may take more tokens than available, after which the bucket needs to
+// refill before the next read or write is allowed.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = std::cmp::max(bytes_per_sec, 1) as f64;
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    // Returns how long to wait before tokens are available again.
+    fn wait_time(&mut self) -> Duration {
+        self.refill();
+        if self.tokens > 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+// The buckets for both directions of a set of rate limits.
+#[derive(Clone, Debug, Default)]
+pub struct Buckets {
+    pub download: Option<SharedBucket>,
+    pub upload: Option<SharedBucket>,
+}
+
+impl Buckets {
+    pub fn new(limits: RateLimits) -> Self {
+        let bucket = |rate: u64| Arc::new(Mutex::new(TokenBucket::new(rate)));
+        Buckets {
+            download: limits.download.map(bucket),
+            upload: limits.upload.map(bucket),
+        }
+    }
+}
+
+// Keeps the transfer rate of the wrapped stream within the limits of all the given buckets. It is
+// a plain pass-through when there are no buckets.
+pub struct Throttled<T> {
+    inner: T,
+    buckets: Vec<SharedBucket>,
+    delay: Option<Delay>,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, buckets: Vec<SharedBucket>) -> Self {
+        Throttled { inner, buckets, delay: None }
+    }
+
+    // Resolves once all the buckets have tokens and returns the maximum number of bytes to
+    // transfer in one go: a tenth of a second worth at the lowest rate, to keep things smooth.
+    fn poll_allowance(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                futures::ready!(Pin::new(delay).poll(cx));
+                self.delay = None;
+            }
+            let mut wait = Duration::from_secs(0);
+            let mut allowance = wanted;
+            for bucket in &self.buckets {
+                let mut bucket = bucket.lock().unwrap();
+                wait = std::cmp::max(wait, bucket.wait_time());
+                allowance = std::cmp::min(allowance, std::cmp::max((bucket.rate / 10.0) as usize, 1));
+            }
+            if wait == Duration::from_secs(0) {
+                return Poll::Ready(allowance);
+            }
+            self.delay = Some(tokio::time::delay_for(wait));
+        }
+    }
+
+    fn take(&self, bytes: usize) {
+        for bucket in &self.buckets {
+            bucket.lock().unwrap().take(bytes);
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Throttled<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buckets.is_empty() || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let allowance = futures::ready!(this.poll_allowance(cx, buf.len()));
+        let result = futures::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..allowance]));
+        if let Ok(n) = result {
+            this.take(n);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Throttled<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buckets.is_empty() || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let allowance = futures::ready!(this.poll_allowance(cx, buf.len()));
+        let result = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowance]));
+        if let Ok(n) = result {
+            this.take(n);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Throttled, TokenBucket};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn reads_at_the_configured_rate() {
+        let data = vec![0u8; 3000];
+        let bucket = Arc::new(Mutex::new(TokenBucket::new(1000)));
+        let mut reader = Throttled::new(&data[..], vec![bucket]);
+        let start = Instant::now();
+        let mut output = vec![];
+        reader.read_to_end(&mut output).await.unwrap();
+        // The first second worth is available right away.
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+        assert_eq!(output, data);
+    }
+}
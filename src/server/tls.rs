@@ -1,17 +1,77 @@
-use rustls::{Certificate, NoClientAuth, PrivateKey};
-use std::convert::TryFrom;
+use crate::{
+    auth::ClientCert,
+    options::{FtpsClientAuth, TlsOptions, TlsVersion},
+};
+use futures::channel::oneshot;
+use log::{info, warn};
+use rustls::{
+    sign::{self, CertifiedKey},
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, CipherSuite, ClientCertVerified, ClientCertVerifier, ClientHello,
+    DistinguishedNames, NoClientAuth, NoServerSessionStorage, PrivateKey, ProtocolVersion, ResolvesServerCert, RootCertStore, ServerSessionMemoryCache,
+    Session, SupportedCipherSuite, TLSError,
+};
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::{webpki, TlsAcceptor};
 
 // FTPSConfig shows how TLS security is configured for the server or a particular channel.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub enum FTPSConfig {
     Off,
-    On { certs_file: PathBuf, key_file: PathBuf },
+    // The acceptor is built once when the server starts and shared by all sessions for both the
+    // control and data channels. It gets its certificates and client certificate verifier from the
+    // resolver so that they can be reloaded.
+    On { acceptor: Arc<TlsAcceptor>, certs: Arc<CertResolver> },
+}
+
+impl FTPSConfig {
+    // Loads the default certificates and key and those for specific server names, and sets up the
+    // acceptor for them with the given options. Client certificates are verified against the given
+    // certificate authorities if any.
+    pub fn new(
+        default: CertFiles,
+        by_name: &HashMap<String, CertFiles>,
+        client_auth: Option<(FtpsClientAuth, PathBuf)>,
+        options: &TlsOptions,
+    ) -> Result<Self, TlsConfigError> {
+        let certs = Arc::new(CertResolver::new(default, by_name, client_auth)?);
+        let config = new_config(certs.clone(), options)?;
+        Ok(FTPSConfig::On {
+            acceptor: Arc::new(TlsAcceptor::from(config)),
+            certs,
+        })
+    }
+
+    // Returns the acceptor to do TLS handshakes with, if FTPS is configured.
+    pub fn acceptor(&self) -> Result<Arc<TlsAcceptor>, FTPSNotAvailable> {
+        match self {
+            FTPSConfig::Off => Err(FTPSNotAvailable),
+            FTPSConfig::On { acceptor, .. } => Ok(acceptor.clone()),
+        }
+    }
+
+    // Returns the resolver that holds the certificates, if FTPS is configured.
+    pub fn certs(&self) -> Option<Arc<CertResolver>> {
+        match self {
+            FTPSConfig::Off => None,
+            FTPSConfig::On { certs, .. } => Some(certs.clone()),
+        }
+    }
+}
+
+impl fmt::Debug for FTPSConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FTPSConfig::Off => write!(f, "Off"),
+            FTPSConfig::On { .. } => write!(f, "On"),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -25,55 +85,348 @@ impl fmt::Display for FTPSNotAvailable {
 
 impl Error for FTPSNotAvailable {}
 
-// Converts
-impl TryFrom<FTPSConfig> for tokio_rustls::TlsAcceptor {
-    type Error = FTPSNotAvailable;
+// TlsConfigError tells why the certificates or key for FTPS could not be loaded.
+#[derive(Debug, Clone)]
+pub struct TlsConfigError(String);
 
-    fn try_from(config: FTPSConfig) -> Result<Self, Self::Error> {
-        match config {
-            FTPSConfig::Off => Err(FTPSNotAvailable),
-            FTPSConfig::On { certs_file, key_file } => {
-                let acceptor: tokio_rustls::TlsAcceptor = new_config(certs_file, key_file).into();
-                Ok(acceptor)
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for TlsConfigError {}
+
+// CertFiles are the paths of a certificate chain and its private key.
+#[derive(Debug, Clone)]
+pub struct CertFiles {
+    pub certs_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl CertFiles {
+    fn load(&self) -> Result<CertifiedKey, TlsConfigError> {
+        load_certified_key(&self.certs_file, &self.key_file)
+    }
+}
+
+// The certificates and client certificate verifier that were loaded last from the files of a
+// CertResolver.
+struct Loaded {
+    default: CertifiedKey,
+    by_name: HashMap<String, CertifiedKey>,
+    client_verifier: Arc<dyn ClientCertVerifier>,
+}
+
+// CertResolver picks the certificate for the server name that the client asks for with SNI, or the
+// default one when the client sends no name or one without a certificate of its own. It also
+// verifies client certificates against the certificate authorities given for client
+// authentication. It hands out what was loaded last. Reloading only affects new handshakes,
+// sessions that are secured already keep going with what they got.
+pub struct CertResolver {
+    default: CertFiles,
+    by_name: HashMap<String, CertFiles>,
+    client_auth: Option<(FtpsClientAuth, PathBuf)>,
+    current: RwLock<Loaded>,
+}
+
+impl CertResolver {
+    fn new(default: CertFiles, by_name: &HashMap<String, CertFiles>, client_auth: Option<(FtpsClientAuth, PathBuf)>) -> Result<Self, TlsConfigError> {
+        let mut normalized = HashMap::new();
+        for (name, files) in by_name {
+            if webpki::DNSNameRef::try_from_ascii_str(name).is_err() {
+                return Err(TlsConfigError(format!(
+                    "invalid server name {:?} for certificates {:?}",
+                    name, files.certs_file
+                )));
             }
+            normalized.insert(normalize(name), files.clone());
         }
+        let current = RwLock::new(load_all(&default, &normalized, &client_auth)?);
+        Ok(CertResolver {
+            default,
+            by_name: normalized,
+            client_auth,
+            current,
+        })
+    }
+
+    // Loads the certificates, key and certificate authority files again. The current ones stay in
+    // use when that fails.
+    pub fn reload(&self) -> Result<(), TlsConfigError> {
+        let loaded = load_all(&self.default, &self.by_name, &self.client_auth)?;
+        *self.current.write().unwrap() = loaded;
+        info!("Reloaded the TLS certificates");
+        Ok(())
+    }
+
+    // The modification times of all files, used to detect changes.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let cert_files = std::iter::once(&self.default)
+            .chain(self.by_name.values())
+            .flat_map(|files| vec![&files.certs_file, &files.key_file]);
+        let ca_file = self.client_auth.iter().map(|(_, ca_file)| ca_file);
+        cert_files
+            .chain(ca_file)
+            .map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+            .collect()
+    }
+
+    fn client_verifier(&self) -> Arc<dyn ClientCertVerifier> {
+        self.current.read().unwrap().client_verifier.clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let current = self.current.read().unwrap();
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name: &str = name.into();
+            current.by_name.get(&normalize(name))
+        });
+        Some(by_name.unwrap_or(&current.default).clone())
     }
 }
 
-pub fn new_config<P: AsRef<Path>>(certs_file: P, key_file: P) -> Arc<rustls::ServerConfig> {
-    let certs: Vec<Certificate> = load_certs(certs_file);
-    let privkey: PrivateKey = load_private_key(key_file);
+// Hands client certificates to the verifier that was loaded last.
+impl ClientCertVerifier for CertResolver {
+    fn offer_client_auth(&self) -> bool {
+        self.client_verifier().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&webpki::DNSName>) -> Option<bool> {
+        self.client_verifier().client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&webpki::DNSName>) -> Option<DistinguishedNames> {
+        self.client_verifier().client_auth_root_subjects(sni)
+    }
 
-    let mut config = rustls::ServerConfig::new(NoClientAuth::new());
-    config.key_log = Arc::new(rustls::KeyLogFile::new());
-    config.set_single_cert(certs, privkey).expect("Failed to setup TLS certificate chain and key");
-    Arc::new(config)
+    fn verify_client_cert(&self, presented_certs: &[Certificate], sni: Option<&webpki::DNSName>) -> Result<ClientCertVerified, TLSError> {
+        self.client_verifier().verify_client_cert(presented_certs, sni)
+    }
 }
 
-fn load_certs<P: AsRef<Path>>(filename: P) -> Vec<rustls::Certificate> {
-    let certfile: File = File::open(filename).expect("cannot open certificate file");
-    let mut reader: BufReader<File> = BufReader::new(certfile);
-    rustls::internal::pemfile::certs(&mut reader).unwrap()
+// Loads all certificates, key and certificate authority files, so that nothing is swapped in
+// unless all of them load.
+fn load_all(default: &CertFiles, by_name: &HashMap<String, CertFiles>, client_auth: &Option<(FtpsClientAuth, PathBuf)>) -> Result<Loaded, TlsConfigError> {
+    let mut loaded = HashMap::new();
+    for (name, files) in by_name {
+        loaded.insert(name.clone(), files.load()?);
+    }
+    Ok(Loaded {
+        default: default.load()?,
+        by_name: loaded,
+        client_verifier: load_client_verifier(client_auth)?,
+    })
 }
 
-fn load_private_key<P: AsRef<Path>>(filename: P) -> rustls::PrivateKey {
-    let rsa_keys = {
-        let keyfile = File::open(&filename).expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::rsa_private_keys(&mut reader).expect("file contains invalid rsa private key")
+// Sets up the verification of client certificates against the certificate authorities in the
+// given file, or none if client authentication isn't configured.
+fn load_client_verifier(client_auth: &Option<(FtpsClientAuth, PathBuf)>) -> Result<Arc<dyn ClientCertVerifier>, TlsConfigError> {
+    let (client_auth, ca_file) = match client_auth {
+        Some(client_auth) => client_auth,
+        None => return Ok(NoClientAuth::new()),
     };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots
+            .add(&cert)
+            .map_err(|err| TlsConfigError(format!("invalid certificate authority in {:?}: {}", ca_file, err)))?;
+    }
+    Ok(match client_auth {
+        FtpsClientAuth::Request => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        FtpsClientAuth::Require => AllowAnyAuthenticatedClient::new(roots),
+    })
+}
+
+// Server names are matched case insensitively and without a trailing dot.
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CertResolver")
+            .field("default", &self.default)
+            .field("by_name", &self.by_name)
+            .field("client_auth", &self.client_auth)
+            .finish()
+    }
+}
+
+// Checks the certificates and key files of the resolver every interval in the background and
+// reloads them when they changed, until the returned sender is dropped or fired. A failed reload is
+// tried again on the next check, since the files may be replaced one after the other.
+pub fn watch(certs: Arc<CertResolver>, interval: Duration) -> oneshot::Sender<()> {
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let mut last_modified = certs.modified();
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let watching = async {
+            loop {
+                ticks.tick().await;
+                let modified = certs.modified();
+                if modified == last_modified {
+                    continue;
+                }
+                match certs.reload() {
+                    Ok(()) => last_modified = modified,
+                    Err(err) => warn!("Could not reload the TLS certificates, keeping the current ones: {}", err),
+                }
+            }
+        };
+        tokio::select! {
+            _ = watching => {},
+            _ = stop_rx => {},
+        }
+    });
+    stop_tx
+}
 
-    let pkcs8_keys = {
-        let keyfile = File::open(&filename).expect("cannot open private key file");
-        let mut reader = BufReader::new(keyfile);
-        rustls::internal::pemfile::pkcs8_private_keys(&mut reader).expect("file contains invalid pkcs8 private key (encrypted keys not supported)")
+fn new_config(certs: Arc<CertResolver>, options: &TlsOptions) -> Result<Arc<rustls::ServerConfig>, TlsConfigError> {
+    let mut config = rustls::ServerConfig::new(certs.clone());
+    config.cert_resolver = certs;
+
+    config.versions = [(TlsVersion::V1_2, ProtocolVersion::TLSv1_2), (TlsVersion::V1_3, ProtocolVersion::TLSv1_3)]
+        .iter()
+        .filter(|(version, _)| *version >= options.min_version && *version <= options.max_version)
+        .map(|(_, protocol_version)| *protocol_version)
+        .collect();
+    if config.versions.is_empty() {
+        return Err(TlsConfigError(format!(
+            "the minimum TLS version {:?} is above the maximum {:?}",
+            options.min_version, options.max_version
+        )));
+    }
+    if let Some(names) = &options.cipher_suites {
+        config.ciphersuites = names.iter().map(|name| cipher_suite(name)).collect::<Result<_, _>>()?;
+    }
+    let versions = &config.versions;
+    if !config
+        .ciphersuites
+        .iter()
+        .any(|suite| versions.iter().any(|version| suite.usable_for_version(*version)))
+    {
+        return Err(TlsConfigError(
+            "none of the cipher suites can be used with the allowed TLS versions".to_string(),
+        ));
+    }
+
+    if options.key_log {
+        config.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+    if options.session_tickets {
+        config.ticketer = rustls::Ticketer::new();
+    }
+    config.session_storage = match options.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
     };
+    Ok(Arc::new(config))
+}
+
+// The IANA names of the cipher suites that rustls supports.
+const CIPHER_SUITE_NAMES: &[(&str, CipherSuite)] = &[
+    ("TLS_CHACHA20_POLY1305_SHA256", CipherSuite::TLS13_CHACHA20_POLY1305_SHA256),
+    ("TLS_AES_256_GCM_SHA384", CipherSuite::TLS13_AES_256_GCM_SHA384),
+    ("TLS_AES_128_GCM_SHA256", CipherSuite::TLS13_AES_128_GCM_SHA256),
+    (
+        "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    ("TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256),
+    ("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384", CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384),
+    ("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256", CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256),
+];
+
+// Looks up a cipher suite by its IANA name. rustls prefixes the TLS 1.3 suites with TLS13
+// instead of TLS, both are accepted.
+fn cipher_suite(name: &str) -> Result<&'static SupportedCipherSuite, TlsConfigError> {
+    let mut wanted = name.to_ascii_uppercase();
+    if wanted.starts_with("TLS13_") {
+        wanted = wanted.replacen("TLS13_", "TLS_", 1);
+    }
+    CIPHER_SUITE_NAMES
+        .iter()
+        .find(|(iana_name, _)| *iana_name == wanted)
+        .and_then(|(_, suite)| rustls::ALL_CIPHERSUITES.iter().copied().find(|supported| supported.suite == *suite))
+        .ok_or_else(|| TlsConfigError(format!("unknown cipher suite {}", name)))
+}
+
+// Returns the certificate that the client presented in the handshake, if any. It has been verified
+// by then.
+pub fn client_cert<IO>(stream: &tokio_rustls::server::TlsStream<IO>) -> Option<ClientCert> {
+    let (_, session) = stream.get_ref();
+    let mut certs = session.get_peer_certificates()?;
+    if certs.is_empty() {
+        return None;
+    }
+    Some(ClientCert::from_der(certs.remove(0).0))
+}
+
+fn load_certified_key(certs_file: &Path, key_file: &Path) -> Result<CertifiedKey, TlsConfigError> {
+    let certs: Vec<Certificate> = load_certs(certs_file)?;
+    let privkey: PrivateKey = load_private_key(key_file)?;
+    let signing_key = sign::any_supported_type(&privkey).map_err(|_| TlsConfigError(format!("unsupported private key in {:?}", key_file)))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+fn open<P: AsRef<Path>>(filename: P, what: &str) -> Result<BufReader<File>, TlsConfigError> {
+    match File::open(&filename) {
+        Ok(file) => Ok(BufReader::new(file)),
+        Err(err) => Err(TlsConfigError(format!("cannot open {} {:?}: {}", what, filename.as_ref(), err))),
+    }
+}
+
+fn load_certs<P: AsRef<Path>>(filename: P) -> Result<Vec<rustls::Certificate>, TlsConfigError> {
+    let mut reader = open(&filename, "certificate file")?;
+    match rustls::internal::pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(TlsConfigError(format!("no certificates found in {:?}", filename.as_ref()))),
+    }
+}
+
+fn load_private_key<P: AsRef<Path>>(filename: P) -> Result<rustls::PrivateKey, TlsConfigError> {
+    let invalid = || TlsConfigError(format!("no valid private key found in {:?}", filename.as_ref()));
+    let rsa_keys = rustls::internal::pemfile::rsa_private_keys(&mut open(&filename, "private key file")?).map_err(|_| invalid())?;
+    // Encrypted keys are not supported.
+    let pkcs8_keys = rustls::internal::pemfile::pkcs8_private_keys(&mut open(&filename, "private key file")?).map_err(|_| invalid())?;
 
     // prefer to load pkcs8 keys
-    if !pkcs8_keys.is_empty() {
-        pkcs8_keys[0].clone()
-    } else {
-        assert!(!rsa_keys.is_empty());
-        rsa_keys[0].clone()
+    pkcs8_keys.into_iter().chain(rsa_keys).next().ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cipher_suite;
+    use rustls::CipherSuite;
+
+    #[test]
+    fn looks_up_cipher_suites_by_name() {
+        let suite = |name| cipher_suite(name).map(|supported| supported.suite).ok();
+        assert_eq!(suite("TLS_AES_128_GCM_SHA256"), Some(CipherSuite::TLS13_AES_128_GCM_SHA256));
+        assert_eq!(suite("tls13_aes_128_gcm_sha256"), Some(CipherSuite::TLS13_AES_128_GCM_SHA256));
+        assert_eq!(
+            suite("TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"),
+            Some(CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384)
+        );
+        assert_eq!(suite("TLS_RSA_WITH_RC4_128_MD5"), None);
+    }
+
+    #[test]
+    fn knows_all_supported_cipher_suites() {
+        for supported in rustls::ALL_CIPHERSUITES.iter() {
+            assert!(
+                super::CIPHER_SUITE_NAMES.iter().any(|(_, suite)| *suite == supported.suite),
+                "{:?}",
+                supported.suite
+            );
+        }
     }
 }
@@ -185,6 +185,13 @@ pub trait StorageBackend<U: Sync + Send + Debug>: Send + Sync + Debug {
         start_pos: u64,
     ) -> Result<u64>;
 
+    /// Called when the client aborted an upload to the given path, after `put` was cancelled.
+    /// Back-ends can use this to discard the partially written file. Files are kept by default so
+    /// that the upload can be resumed with the REST command.
+    async fn put_aborted<P: AsRef<Path> + Send + Debug>(&self, _user: &Option<U>, _path: P) -> Result<()> {
+        Ok(())
+    }
+
     /// Deletes the file at the given path.
     async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &Option<U>, path: P) -> Result<()>;
 
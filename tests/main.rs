@@ -10,11 +10,24 @@ use std::path::PathBuf;
 use std::str;
 use tokio::runtime::Runtime;
 
-fn test_with(addr: &'static str, path: impl Into<PathBuf> + Send, test: impl FnOnce() -> ()) {
+// Starts the given server on a new runtime and gives it a moment to start listening. The server
+// stops when the returned runtime is dropped.
+fn spawn_server<S, U>(server: libunftp::Server<S, U>, addr: &str) -> Runtime
+where
+    S: libunftp::storage::StorageBackend<U> + 'static,
+    S::File: tokio::io::AsyncRead + Send,
+    S::Metadata: libunftp::storage::Metadata,
+    U: libunftp::auth::UserDetail + 'static,
+{
     let rt = Runtime::new().unwrap();
-    let server = libunftp::Server::new_with_fs_root(path.into());
-    let _thread = rt.spawn(server.listen(addr));
+    rt.spawn(server.listen(addr.to_string()));
     std::thread::sleep(Duration::new(1, 0));
+    rt
+}
+
+fn test_with(addr: &'static str, path: impl Into<PathBuf> + Send, test: impl FnOnce() -> ()) {
+    let server = libunftp::Server::new_with_fs_root(path.into());
+    let _rt = spawn_server(server, addr);
     test();
 }
 
@@ -365,3 +378,1401 @@ fn size() {
         assert_eq!(size3, fs::metadata(&file_in_root).unwrap().len() as usize, "Wrong size returned.");
     });
 }
+
+#[test]
+fn shutdown_closes_idle_sessions() {
+    let addr = "127.0.0.1:1249";
+    let root = tempfile::TempDir::new().unwrap();
+    let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
+
+    let mut rt = Runtime::new().unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).shutdown_indicator(async move {
+        shutdown_rx.await.ok();
+        libunftp::options::Shutdown::new().grace_period(Duration::from_secs(5))
+    });
+    let server_handle = rt.spawn(server.listen(addr));
+    std::thread::sleep(Duration::new(1, 0));
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+
+    // A session that is busy with a STOU upload is left alone until the upload is done.
+    let mut uploading = FtpStream::connect(addr).unwrap();
+    uploading.login("hoi", "jij").unwrap();
+    let tcps = uploading.get_ref();
+    tcps.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(tcps);
+    let mut reply = String::new();
+    (&*tcps).write_all(b"PASV\r\n").unwrap();
+    reader.read_line(&mut reply).unwrap();
+    let port = passive_port(&reply);
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    (&*tcps).write_all(b"STOU\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("150"), "Unexpected reply: {}", reply);
+    data.write_all(b"first part").unwrap();
+
+    shutdown_tx.send(()).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    data.write_all(b" and the rest").unwrap();
+    drop(data);
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("226"), "Unexpected reply: {}", reply);
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("421"), "Unexpected reply: {}", reply);
+    rt.block_on(server_handle).unwrap().unwrap();
+
+    // The idle session got a 421 and was closed, and no new connections are accepted.
+    ftp_stream.noop().unwrap_err();
+    assert!(FtpStream::connect(addr).is_err());
+}
+
+#[test]
+fn shutdown_closes_session_after_broken_transfer() {
+    let addr = "127.0.0.1:1289";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("big.bin"), vec![7u8; 100_000]).unwrap();
+    let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
+
+    let mut rt = Runtime::new().unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf())
+        .session_rate_limits(libunftp::options::RateLimits::new().download(10_000))
+        .shutdown_indicator(async move {
+            shutdown_rx.await.ok();
+            libunftp::options::Shutdown::new().grace_period(Duration::from_secs(5))
+        });
+    let server_handle = rt.spawn(server.listen(addr));
+    std::thread::sleep(Duration::new(1, 0));
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    let port = passive_port(&send_command(&mut control, "PASV"));
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(send_command(&mut control, "RETR big.bin").starts_with("150"));
+    let mut buf = [0u8; 1024];
+    std::io::Read::read(&mut data, &mut buf).unwrap();
+
+    // The download breaks off while the server waits for it to finish.
+    shutdown_tx.send(()).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    let started = std::time::Instant::now();
+    drop(data);
+    let reply = read_reply(&mut control);
+    assert!(reply.starts_with("426"), "Unexpected reply: {}", reply);
+    let reply = read_reply(&mut control);
+    assert!(reply.starts_with("421"), "Unexpected reply: {}", reply);
+    rt.block_on(server_handle).unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(4), "The server waited for the grace period");
+}
+
+#[test]
+fn listen_returns_bind_errors() {
+    let mut rt = Runtime::new().unwrap();
+    let root = std::env::temp_dir();
+
+    let server = libunftp::Server::new_with_fs_root(root.clone());
+    let err = rt.block_on(server.listen("not an address")).unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &libunftp::ServerErrorKind::InvalidBindAddress {
+            address: "not an address".to_string()
+        }
+    );
+
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap().to_string();
+    let server = libunftp::Server::new_with_fs_root(root);
+    let err = rt.block_on(server.listen(addr.clone())).unwrap_err();
+    assert_eq!(err.kind(), &libunftp::ServerErrorKind::BindError { address: addr });
+}
+
+#[test]
+fn listen_on_prebound_listener() {
+    let mut rt = Runtime::new().unwrap();
+    let listener = rt.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir());
+    let handle = server.handle();
+    assert_eq!(handle.local_addr(), None);
+    rt.spawn(server.listen_on(listener));
+    std::thread::sleep(Duration::new(1, 0));
+
+    assert_eq!(handle.local_addr(), Some(addr));
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream.pwd().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_connection() {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("a.txt"), b"a").unwrap();
+    let mut rt = Runtime::new().unwrap();
+    let (stream, client) = rt.block_on(async { tokio::net::UnixStream::pair() }).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf());
+    let local_addr = "127.0.0.1:21".parse().unwrap();
+    let peer_addr = "192.0.2.1:50000".parse().unwrap();
+    rt.block_on(server.serve_connection(stream, local_addr, peer_addr)).unwrap();
+
+    // The session keeps running after the server that started it is dropped.
+    drop(server);
+    rt.block_on(async move {
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("220"));
+        writer.write_all(b"USER hoi\r\nPASS jij\r\n").await.unwrap();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("331"));
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("230"));
+
+        // The data connection is set up on the local address.
+        writer.write_all(b"PASV\r\n").await.unwrap();
+        let reply = lines.next_line().await.unwrap().unwrap();
+        let re = Regex::new(r"\(127,0,0,1,(\d+),(\d+)\)").unwrap();
+        let caps = re.captures(&reply).unwrap();
+        let port = caps[1].parse::<u16>().unwrap() * 256 + caps[2].parse::<u16>().unwrap();
+        let mut data = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        writer.write_all(b"NLST\r\n").await.unwrap();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("150"));
+        let mut listing = String::new();
+        data.read_to_string(&mut listing).await.unwrap();
+        assert_eq!(listing, "a.txt\r\n");
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("226"));
+        writer.write_all(b"QUIT\r\n").await.unwrap();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("221"));
+    });
+}
+
+#[test]
+fn epsv_over_ipv6() {
+    let addr = "[::1]:1250";
+    let root = std::env::temp_dir();
+    test_with(addr, root, || {
+        let control = std::net::TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(control.try_clone().unwrap());
+        let mut writer = control;
+        let mut send = |cmd: &str| -> String {
+            if !cmd.is_empty() {
+                writer.write_all(format!("{}\r\n", cmd).as_bytes()).unwrap();
+            }
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+
+        assert!(send("").starts_with("220"));
+        assert!(send("USER hoi").starts_with("331"));
+        assert!(send("PASS jij").starts_with("230"));
+        assert!(send("PASV").starts_with("425"));
+        assert!(send("EPSV 1").starts_with("522"));
+
+        let reply = send("EPSV");
+        assert!(reply.starts_with("229 Entering Extended Passive Mode (|||"), "Unexpected reply: {}", reply);
+        let port: u16 = reply.split('|').nth(3).unwrap().parse().unwrap();
+        let mut data = std::net::TcpStream::connect(("::1", port)).unwrap();
+        assert!(send("NLST").starts_with("150"));
+        let mut listing = String::new();
+        std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+        assert!(send("").starts_with("226"));
+    });
+}
+
+#[test]
+fn pasv_over_ipv6_with_passive_host() {
+    let addr = "[::1]:1285";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).passive_host(std::net::Ipv4Addr::new(203, 0, 113, 7));
+    let _rt = spawn_server(server, addr);
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    let reply = send_command(&mut control, "PASV");
+    assert!(reply.starts_with("227 Entering Passive Mode (203,0,113,7,"), "Unexpected reply: {}", reply);
+}
+
+#[test]
+fn active_mode() {
+    let addr = "127.0.0.1:1251";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).active_mode(libunftp::options::ActiveMode::new().source_ports(30000..30100));
+    let _rt = spawn_server(server, addr);
+
+    let control = std::net::TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(control.try_clone().unwrap());
+    let mut writer = control;
+    let mut send = |cmd: &str| -> String {
+        if !cmd.is_empty() {
+            writer.write_all(format!("{}\r\n", cmd).as_bytes()).unwrap();
+        }
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+
+    assert!(send("").starts_with("220"));
+    assert!(send("USER hoi").starts_with("331"));
+    assert!(send("PASS jij").starts_with("230"));
+
+    // The data connection may only go to the client's own address.
+    assert!(send("PORT 10,0,0,1,4,0").starts_with("504"));
+
+    // The server connects when the transfer command comes in, not before.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    assert!(send(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff)).starts_with("200"));
+    std::thread::sleep(Duration::from_millis(200));
+    listener.set_nonblocking(true).unwrap();
+    assert_eq!(listener.accept().unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    listener.set_nonblocking(false).unwrap();
+    assert!(send("NLST").starts_with("150"));
+    let (mut data, source) = listener.accept().unwrap();
+    assert!((30000..30100).contains(&source.port()));
+    let mut listing = String::new();
+    std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+    assert!(send("").starts_with("226"));
+
+    let port = listener.local_addr().unwrap().port();
+    assert!(send(&format!("EPRT |1|127.0.0.1|{}|", port)).starts_with("200"));
+    assert!(send("NLST").starts_with("150"));
+    let (mut data, _) = listener.accept().unwrap();
+    std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+    assert!(send("").starts_with("226"));
+
+    // A client that is not listening gets a 425 once it asks for a transfer.
+    drop(listener);
+    assert!(send(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff)).starts_with("200"));
+    assert!(send("NLST").starts_with("150"));
+    assert!(send("").starts_with("425"));
+
+    // PORT closes the listener of an earlier PASV.
+    let reply = send("PASV");
+    let pasv_port = passive_port(&reply);
+    assert!(send(&format!("PORT 127,0,0,1,{},{}", port >> 8, port & 0xff)).starts_with("200"));
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(std::net::TcpStream::connect(("127.0.0.1", pasv_port)).is_err());
+}
+
+#[test]
+fn active_mode_to_foreign_addresses() {
+    let addr = "127.0.0.1:1284";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).active_mode(libunftp::options::ActiveMode::new().allow_foreign_address(true));
+    let _rt = spawn_server(server, addr);
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+
+    // Other hosts are fine, but not their privileged ports.
+    assert!(send_command(&mut control, "PORT 10,0,0,1,4,0").starts_with("200"));
+    assert!(send_command(&mut control, "PORT 10,0,0,1,0,25").starts_with("504"));
+    assert!(send_command(&mut control, "EPRT |1|10.0.0.1|21|").starts_with("504"));
+}
+
+#[test]
+fn active_mode_is_off_by_default() {
+    let addr = "127.0.0.1:1252";
+    let root = std::env::temp_dir();
+    test_with(addr, root, || {
+        let mut ftp_stream = FtpStream::connect(addr).unwrap();
+        ftp_stream.login("hoi", "jij").unwrap();
+        let mut tcps = ftp_stream.get_ref();
+        tcps.write_all(b"PORT 127,0,0,1,4,0\r\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(tcps).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("502"), "Unexpected reply: {}", reply);
+    });
+}
+
+#[test]
+fn ftps_required_refuses_plaintext_login() {
+    let addr = "127.0.0.1:1253";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root)
+        .ftps("tests/resources/server.pem", "tests/resources/server.key")
+        .ftps_required(libunftp::options::FtpsRequired::Control);
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    let err = ftp_stream.login("hoi", "jij").unwrap_err().to_string();
+    assert!(err.contains("534"), "Unexpected error: {}", err);
+}
+
+#[derive(Debug)]
+struct TlsUser;
+
+impl libunftp::auth::UserDetail for TlsUser {
+    fn ftps_required(&self) -> libunftp::options::FtpsRequired {
+        libunftp::options::FtpsRequired::ControlAndData
+    }
+}
+
+impl std::fmt::Display for TlsUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TlsUser")
+    }
+}
+
+#[derive(Debug)]
+struct TlsUserAuthenticator;
+
+#[async_trait::async_trait]
+impl libunftp::auth::Authenticator<TlsUser> for TlsUserAuthenticator {
+    async fn authenticate(&self, _username: &str, _password: &str) -> std::result::Result<TlsUser, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(TlsUser)
+    }
+}
+
+#[test]
+fn ftps_required_per_user() {
+    let addr = "127.0.0.1:1254";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_authenticator(
+        Box::new(move || libunftp::storage::filesystem::Filesystem::new(root.clone())),
+        std::sync::Arc::new(TlsUserAuthenticator),
+    );
+    let _rt = spawn_server(server, addr);
+
+    // TLS isn't available so the user can't satisfy the policy.
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    let err = ftp_stream.login("hoi", "jij").unwrap_err().to_string();
+    assert!(err.contains("530"), "Unexpected error: {}", err);
+}
+
+#[test]
+fn passive_host() {
+    let addr = "127.0.0.1:1255";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).passive_host(libunftp::options::PassiveHost::Dynamic(std::sync::Arc::new(|peer| {
+        if peer.is_loopback() {
+            std::net::Ipv4Addr::new(10, 0, 0, 7)
+        } else {
+            std::net::Ipv4Addr::new(203, 0, 113, 7)
+        }
+    })));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let mut tcps = ftp_stream.get_ref();
+    tcps.write_all(b"PASV\r\n").unwrap();
+    let mut reply = String::new();
+    BufReader::new(tcps).read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("227 Entering Passive Mode (10,0,0,7,"), "Unexpected reply: {}", reply);
+}
+
+#[test]
+fn passive_host_that_does_not_resolve() {
+    let mut rt = Runtime::new().unwrap();
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir()).passive_host("unftp.invalid");
+    let err = rt.block_on(server.listen("127.0.0.1:0")).unwrap_err();
+    assert_eq!(
+        err.kind(),
+        &libunftp::ServerErrorKind::PassiveHostResolveError {
+            host: "unftp.invalid".to_string()
+        }
+    );
+}
+
+#[test]
+fn max_sessions_per_ip() {
+    let addr = "127.0.0.1:1256";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).max_sessions(2).max_sessions_per_ip(1);
+    let _rt = spawn_server(server, addr);
+
+    let greeting = |stream: &std::net::TcpStream| -> String {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        line
+    };
+
+    let first = std::net::TcpStream::connect(addr).unwrap();
+    assert!(greeting(&first).starts_with("220"));
+    let second = std::net::TcpStream::connect(addr).unwrap();
+    assert_eq!(greeting(&second), "421 Too many connections\r\n");
+
+    // The place is given back once the first session ends.
+    (&first).write_all(b"QUIT\r\n").unwrap();
+    assert!(greeting(&first).starts_with("221"));
+    std::thread::sleep(Duration::from_millis(100));
+    let third = std::net::TcpStream::connect(addr).unwrap();
+    assert!(greeting(&third).starts_with("220"));
+}
+
+fn read_greeting(addr: &str) -> String {
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    line
+}
+
+#[test]
+fn ip_allow_list() {
+    let addr = "127.0.0.1:1257";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).ip_allow_list(vec!["10.0.0.0/8".parse().unwrap()]);
+    let _rt = spawn_server(server, addr);
+
+    assert_eq!(read_greeting(addr), "421 Access denied\r\n");
+}
+
+#[derive(Debug)]
+struct LocalOnlyFilter;
+
+#[async_trait::async_trait]
+impl libunftp::options::ConnectionFilter for LocalOnlyFilter {
+    async fn check(&self, peer_addr: std::net::SocketAddr) -> libunftp::options::ConnectionDecision {
+        if peer_addr.ip().is_loopback() {
+            libunftp::options::ConnectionDecision::Reject(String::from("Partners only"))
+        } else {
+            libunftp::options::ConnectionDecision::Accept
+        }
+    }
+}
+
+#[test]
+fn connection_filter() {
+    let addr = "127.0.0.1:1258";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root)
+        .ip_allow_list(vec!["127.0.0.0/8".parse().unwrap()])
+        .connection_filter(std::sync::Arc::new(LocalOnlyFilter));
+    let _rt = spawn_server(server, addr);
+
+    assert_eq!(read_greeting(addr), "421 Partners only\r\n");
+}
+
+#[derive(Debug, Default)]
+struct SlowFirstRejectFilter(std::sync::atomic::AtomicUsize);
+
+#[async_trait::async_trait]
+impl libunftp::options::ConnectionFilter for SlowFirstRejectFilter {
+    async fn check(&self, _peer_addr: std::net::SocketAddr) -> libunftp::options::ConnectionDecision {
+        if self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+            libunftp::options::ConnectionDecision::Reject(String::from("Go\r\n220 away"))
+        } else {
+            libunftp::options::ConnectionDecision::Accept
+        }
+    }
+}
+
+#[test]
+fn connection_filter_before_session_limits() {
+    let addr = "127.0.0.1:1283";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root)
+        .max_sessions(1)
+        .connection_filter(std::sync::Arc::new(SlowFirstRejectFilter::default()));
+    let _rt = spawn_server(server, addr);
+
+    // The connection that is still being checked by the filter doesn't take up a session.
+    let mut rejected = std::net::TcpStream::connect(addr).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(read_greeting(addr).starts_with("220"));
+
+    // Line breaks in the message of the filter can't sneak in another reply.
+    let mut reply = String::new();
+    std::io::Read::read_to_string(&mut rejected, &mut reply).unwrap();
+    assert_eq!(reply, "421 Go  220 away\r\n");
+}
+
+#[derive(Debug)]
+struct SecretAuthenticator;
+
+#[async_trait::async_trait]
+impl libunftp::auth::Authenticator<libunftp::auth::DefaultUser> for SecretAuthenticator {
+    async fn authenticate(
+        &self,
+        _username: &str,
+        password: &str,
+    ) -> std::result::Result<libunftp::auth::DefaultUser, Box<dyn std::error::Error + Send + Sync>> {
+        match password {
+            "secret" => Ok(libunftp::auth::DefaultUser {}),
+            _ => Err("bad password".into()),
+        }
+    }
+}
+
+#[test]
+fn failed_logins_policy() {
+    let addr = "127.0.0.1:1259";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_authenticator(
+        Box::new(move || libunftp::storage::filesystem::Filesystem::new(root.clone())),
+        std::sync::Arc::new(SecretAuthenticator),
+    )
+    .failed_logins_policy(libunftp::options::FailedLoginsPolicy::new().max_attempts(2));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    for _ in 0..2 {
+        let err = ftp_stream.login("hoi", "wrong").unwrap_err().to_string();
+        assert!(err.contains("Authentication failed"), "Unexpected error: {}", err);
+    }
+    // Now even the right password is refused and new connections are turned away.
+    let err = ftp_stream.login("hoi", "secret").unwrap_err().to_string();
+    assert!(err.contains("Too many failed login attempts"), "Unexpected error: {}", err);
+    assert_eq!(read_greeting(addr), "421 Too many failed login attempts, try again later\r\n");
+}
+
+#[test]
+fn session_rate_limits() {
+    let addr = "127.0.0.1:1260";
+    let root = tempfile::TempDir::new().unwrap();
+    let data = vec![7u8; 20_000];
+    fs::write(root.path().join("big.bin"), &data).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).session_rate_limits(libunftp::options::RateLimits::new().download(10_000));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let start = std::time::Instant::now();
+    let remote_file = ftp_stream.simple_retr("big.bin").unwrap();
+    // The first second worth of data is sent right away, the rest takes another second.
+    assert!(start.elapsed() >= Duration::from_millis(900), "Transfer took {:?}", start.elapsed());
+    assert_eq!(remote_file.into_inner(), data);
+}
+
+#[test]
+fn passive_accept_timeout() {
+    let addr = "127.0.0.1:1261";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).data_timeouts(libunftp::options::DataTimeouts::new().passive_accept(Duration::from_secs(1)));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let tcps = ftp_stream.get_ref();
+    tcps.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(tcps);
+    let mut reply = String::new();
+    (&*tcps).write_all(b"PASV\r\n").unwrap();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("227"), "Unexpected reply: {}", reply);
+
+    // The client never connects to the data port.
+    (&*tcps).write_all(b"NLST\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("150"), "Unexpected reply: {}", reply);
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("425"), "Unexpected reply: {}", reply);
+}
+
+#[test]
+fn replaced_passive_listener_expires() {
+    let addr = "127.0.0.1:1281";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("file.txt"), b"hello").unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf())
+        .data_timeouts(libunftp::options::DataTimeouts::new().passive_accept(Duration::from_secs(2)));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let tcps = ftp_stream.get_ref();
+    tcps.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(tcps);
+    let mut pasv = || -> u16 {
+        let mut reply = String::new();
+        (&*tcps).write_all(b"PASV\r\n").unwrap();
+        reader.read_line(&mut reply).unwrap();
+        passive_port(&reply)
+    };
+
+    // The listener of the first PASV expires while the one of the second is still waiting.
+    pasv();
+    std::thread::sleep(Duration::from_secs(1));
+    let port = pasv();
+    std::thread::sleep(Duration::from_millis(1500));
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    let mut reader = BufReader::new(tcps);
+    let mut reply = String::new();
+    (&*tcps).write_all(b"NLST\r\n").unwrap();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("150"), "Unexpected reply: {}", reply);
+    let mut listing = String::new();
+    std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+    assert!(listing.contains("file.txt"), "Unexpected listing: {}", listing);
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("226"), "Unexpected reply: {}", reply);
+}
+
+#[test]
+fn abort_running_transfer() {
+    let addr = "127.0.0.1:1262";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("big.bin"), vec![7u8; 100_000]).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).session_rate_limits(libunftp::options::RateLimits::new().download(10_000));
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let tcps = ftp_stream.get_ref();
+    tcps.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut reader = BufReader::new(tcps);
+    let mut reply = String::new();
+    (&*tcps).write_all(b"PASV\r\n").unwrap();
+    reader.read_line(&mut reply).unwrap();
+    let port = passive_port(&reply);
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    (&*tcps).write_all(b"RETR big.bin\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("150"), "Unexpected reply: {}", reply);
+    let mut buf = [0u8; 1024];
+    std::io::Read::read(&mut data, &mut buf).unwrap();
+
+    (&*tcps).write_all(b"ABOR\r\n").unwrap();
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("426"), "Unexpected reply: {}", reply);
+    reply.clear();
+    reader.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("226"), "Unexpected reply: {}", reply);
+
+    // The data connection got closed before the whole file was sent.
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = vec![];
+    let _ = std::io::Read::read_to_end(&mut data, &mut rest);
+    assert!(rest.len() < 99_000, "Received {} more bytes", rest.len());
+}
+
+#[test]
+fn data_connection_closed_during_transfer() {
+    let addr = "127.0.0.1:1288";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("big.bin"), vec![7u8; 100_000]).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).session_rate_limits(libunftp::options::RateLimits::new().download(10_000));
+    let _rt = spawn_server(server, addr);
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    let port = passive_port(&send_command(&mut control, "PASV"));
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(send_command(&mut control, "RETR big.bin").starts_with("150"));
+    let mut buf = [0u8; 1024];
+    std::io::Read::read(&mut data, &mut buf).unwrap();
+
+    // The client goes away in the middle of the download.
+    drop(data);
+    let reply = read_reply(&mut control);
+    assert!(reply.starts_with("426"), "Unexpected reply: {}", reply);
+
+    // The session is ready for the next transfer.
+    assert!(send_command(&mut control, "ABOR").starts_with("226"));
+    let port = passive_port(&send_command(&mut control, "PASV"));
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(send_command(&mut control, "NLST").starts_with("150"));
+    let mut listing = String::new();
+    std::io::Read::read_to_string(&mut data, &mut listing).unwrap();
+    assert_eq!(listing, "big.bin\r\n");
+    assert!(read_reply(&mut control).starts_with("226"));
+}
+
+#[test]
+fn session_end_closes_data_connections() {
+    let addr = "127.0.0.1:1263";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("big.bin"), vec![7u8; 100_000]).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).session_rate_limits(libunftp::options::RateLimits::new().download(10_000));
+    let _rt = spawn_server(server, addr);
+
+    let pasv = |ftp_stream: &mut FtpStream| -> u16 {
+        let tcps = ftp_stream.get_ref();
+        (&*tcps).write_all(b"PASV\r\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(tcps).read_line(&mut reply).unwrap();
+        passive_port(&reply)
+    };
+
+    // The passive listener is closed when the client leaves without connecting to it.
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let port = pasv(&mut ftp_stream);
+    ftp_stream.quit().unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    // A running download is stopped when the control connection goes away.
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let port = pasv(&mut ftp_stream);
+    let mut data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut tcps = ftp_stream.get_ref();
+    tcps.write_all(b"RETR big.bin\r\n").unwrap();
+    let mut buf = [0u8; 1024];
+    std::io::Read::read(&mut data, &mut buf).unwrap();
+    drop(ftp_stream);
+
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = vec![];
+    std::io::Read::read_to_end(&mut data, &mut rest).unwrap();
+    assert!(rest.len() < 99_000, "Received {} more bytes", rest.len());
+}
+
+#[test]
+fn list_and_disconnect_sessions() {
+    let addr = "127.0.0.1:1264";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::create_dir(root.path().join("sub")).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf());
+    let handle = server.handle();
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    ftp_stream.cwd("sub").unwrap();
+    let sessions = handle.sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].username, Some("hoi".to_string()));
+    assert_eq!(sessions[0].cwd, PathBuf::from("/sub"));
+    assert!(sessions[0].logged_in_at.is_some());
+    assert!(sessions[0].transfer.is_none());
+
+    assert!(handle.disconnect(sessions[0].id));
+    let mut reply = String::new();
+    BufReader::new(ftp_stream.get_ref()).read_line(&mut reply).unwrap();
+    assert!(reply.starts_with("421"), "Unexpected reply: {}", reply);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(handle.sessions().is_empty());
+    assert!(!handle.disconnect(sessions[0].id));
+}
+
+#[cfg(feature = "admin_http")]
+#[test]
+fn admin_listener() {
+    let addr = "127.0.0.1:1265";
+    let admin_addr = "127.0.0.1:1266";
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_fs_root(root).metrics().admin_listener(admin_addr);
+    let _rt = spawn_server(server, addr);
+
+    let get = |path: &str| -> String {
+        let mut stream = std::net::TcpStream::connect(admin_addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+        response
+    };
+
+    assert!(get("/health").starts_with("HTTP/1.1 200"));
+    assert!(get("/ready").starts_with("HTTP/1.1 200"));
+    assert!(get("/nope").starts_with("HTTP/1.1 404"));
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+    let metrics = get("/metrics");
+    assert!(metrics.starts_with("HTTP/1.1 200"));
+    assert!(metrics.contains("ftp_command_total"), "Unexpected metrics: {}", metrics);
+}
+
+#[test]
+fn transfer_metrics_per_user() {
+    let addr = "127.0.0.1:1267";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("small.bin"), vec![7u8; 1000]).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).metrics_per_user(1);
+    let _rt = spawn_server(server, addr);
+
+    for user in &["alice", "bob"] {
+        let mut ftp_stream = FtpStream::connect(addr).unwrap();
+        ftp_stream.login(user, "secret").unwrap();
+        ftp_stream.simple_retr("small.bin").unwrap();
+        ftp_stream.quit().unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(100));
+
+    let families = prometheus::gather();
+    let family = |name: &str| families.iter().find(|family| family.get_name() == name).unwrap();
+    let label = |metric: &prometheus::proto::Metric, name: &str| -> String {
+        metric
+            .get_label()
+            .iter()
+            .find(|label| label.get_name() == name)
+            .unwrap()
+            .get_value()
+            .to_string()
+    };
+
+    let sizes = family("ftp_transfer_size_bytes");
+    let retr = sizes.get_metric().iter().find(|metric| label(metric, "command") == "retr").unwrap();
+    assert_eq!(retr.get_histogram().get_sample_count(), 2);
+    assert_eq!(retr.get_histogram().get_sample_sum(), 2000.0);
+
+    // Only the first user gets a label of its own.
+    let mut users: Vec<String> = family("ftp_user_transfers_total")
+        .get_metric()
+        .iter()
+        .map(|metric| label(metric, "user"))
+        .collect();
+    users.sort();
+    assert_eq!(users, vec!["alice".to_string(), "other".to_string()]);
+}
+
+#[test]
+fn metrics_options() {
+    let registry = prometheus::Registry::new();
+    let options = |listener: &str| {
+        libunftp::options::MetricsOptions::new()
+            .registry(registry.clone())
+            .namespace("partners_ftp")
+            .const_label("listener", listener)
+    };
+    let mut rt = Runtime::new().unwrap();
+    for (addr, listener) in &[("127.0.0.1:1268", "partners"), ("127.0.0.1:1269", "internal")] {
+        let server = libunftp::Server::new_with_fs_root(std::env::temp_dir()).metrics_options(options(listener));
+        rt.spawn(server.listen(*addr));
+    }
+    std::thread::sleep(Duration::new(1, 0));
+
+    let mut ftp_stream = FtpStream::connect("127.0.0.1:1268").unwrap();
+    ftp_stream.login("hoi", "jij").unwrap();
+
+    let families = registry.gather();
+    let sessions = families.iter().find(|family| family.get_name() == "partners_ftp_sessions_total").unwrap();
+    let mut per_listener: Vec<(String, f64)> = sessions
+        .get_metric()
+        .iter()
+        .map(|metric| (metric.get_label()[0].get_value().to_string(), metric.get_gauge().get_value()))
+        .collect();
+    per_listener.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(per_listener, vec![("internal".to_string(), 0.0), ("partners".to_string(), 1.0)]);
+    assert!(prometheus::gather().iter().all(|family| !family.get_name().starts_with("partners_ftp")));
+
+    // Servers can't report under the same names and labels.
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir()).metrics_options(options("partners"));
+    let err = rt.block_on(server.listen("127.0.0.1:1270")).unwrap_err();
+    assert_eq!(err.kind(), &libunftp::ServerErrorKind::MetricsError);
+}
+
+#[test]
+fn session_spans() {
+    // Collects the formatted log lines of all tests, so only the lines of this session are checked.
+    let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let writer_output = output.clone();
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || SharedWriter(writer_output.clone()))
+        .try_init()
+        .unwrap();
+
+    let addr = "127.0.0.1:1271";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("small.bin"), vec![7u8; 10]).unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf());
+    let handle = server.handle();
+    let _rt = spawn_server(server, addr);
+
+    let mut ftp_stream = FtpStream::connect(addr).unwrap();
+    ftp_stream.login("alice", "secret").unwrap();
+    let id = handle.sessions()[0].id;
+    ftp_stream.simple_retr("small.bin").unwrap();
+    ftp_stream.quit().unwrap();
+
+    // Other tests log data commands too, their sessions have different peer addresses.
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    let peer = format!("peer={}", ftp_stream.get_ref().local_addr().unwrap());
+    let line = output
+        .lines()
+        .find(|line| line.contains("Data command received") && line.contains(&peer))
+        .unwrap();
+    let span = format!("session{{id={} {} username=alice}}", id, peer);
+    assert!(line.contains(&span), "Unexpected log line: {}", line);
+}
+
+struct SharedWriter(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Sends an FTP command over the given stream and returns the first line of the reply.
+fn send_command<S: std::io::Read + Write>(stream: &mut S, command: &str) -> String {
+    write!(stream, "{}\r\n", command).unwrap();
+    read_reply(stream)
+}
+
+// Returns the port from a reply to PASV.
+fn passive_port(reply: &str) -> u16 {
+    let re = Regex::new(r"\((\d+),(\d+),(\d+),(\d+),(\d+),(\d+)\)").unwrap();
+    let caps = re.captures(reply).unwrap_or_else(|| panic!("Unexpected reply: {}", reply));
+    caps[5].parse::<u16>().unwrap() * 256 + caps[6].parse::<u16>().unwrap()
+}
+
+fn read_reply<S: std::io::Read>(stream: &mut S) -> String {
+    let mut reply = vec![];
+    let mut byte = [0u8; 1];
+    while !reply.ends_with(b"\n") {
+        stream.read_exact(&mut byte).unwrap();
+        reply.push(byte[0]);
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+// Wraps the given connection in TLS, trusting the certificate authority of the test certificates.
+fn tls_client(stream: std::net::TcpStream) -> rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream> {
+    tls_client_for(stream, "localhost", true)
+}
+
+// Like tls_client, but expects a certificate for the given server name and only tells the server
+// which name it wants when sni is set.
+fn tls_client_for(stream: std::net::TcpStream, server_name: &str, sni: bool) -> rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream> {
+    let mut config = tls_client_config();
+    config.enable_sni = sni;
+    tls_client_with(stream, server_name, config)
+}
+
+fn tls_client_config() -> rustls::ClientConfig {
+    let mut config = rustls::ClientConfig::new();
+    let mut ca = BufReader::new(fs::File::open("tests/resources/ca.pem").unwrap());
+    config.root_store.add_pem_file(&mut ca).unwrap();
+    config
+}
+
+fn tls_client_with(
+    stream: std::net::TcpStream,
+    server_name: &str,
+    config: rustls::ClientConfig,
+) -> rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream> {
+    let name = tokio_rustls::webpki::DNSNameRef::try_from_ascii_str(server_name).unwrap();
+    let session = rustls::ClientSession::new(&std::sync::Arc::new(config), name);
+    rustls::StreamOwned::new(session, stream)
+}
+
+#[test]
+fn ftps_data_channels() {
+    let addr = "127.0.0.1:1272";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("a.txt"), b"a").unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf()).ftps("tests/resources/server.pem", "tests/resources/server.key");
+    let _rt = spawn_server(server, addr);
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "AUTH TLS").starts_with("234"));
+    let mut control = tls_client(control);
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    assert!(send_command(&mut control, "PBSZ 0").starts_with("200"));
+    assert!(send_command(&mut control, "PROT P").starts_with("200"));
+
+    // Every data connection does its own handshake with the shared configuration.
+    for _ in 0..2 {
+        let reply = send_command(&mut control, "PASV");
+        let port = passive_port(&reply);
+        let data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut data = tls_client(data);
+        assert!(send_command(&mut control, "NLST").starts_with("150"));
+        let listing = read_tls_listing(&mut data);
+        assert_eq!(listing, "a.txt\r\n");
+        assert!(read_reply(&mut control).starts_with("226"));
+    }
+}
+
+#[test]
+fn ftps_required_on_data_channels() {
+    let addr = "127.0.0.1:1286";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("a.txt"), b"a").unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf())
+        .ftps("tests/resources/server.pem", "tests/resources/server.key")
+        .ftps_required(libunftp::options::FtpsRequired::ControlAndData);
+    let _rt = spawn_server(server, addr);
+
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "AUTH TLS").starts_with("234"));
+    let mut control = tls_client(control);
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    // Clients that aren't logged in yet are told to log in first.
+    assert!(send_command(&mut control, "NLST").starts_with("530"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    assert!(send_command(&mut control, "PBSZ 0").starts_with("200"));
+
+    for command in &["RETR a.txt", "STOR b.txt", "STOU", "LIST", "NLST"] {
+        let reply = send_command(&mut control, command);
+        assert!(reply.starts_with("521"), "Unexpected reply to {}: {}", command, reply);
+    }
+    assert!(send_command(&mut control, "PROT C").starts_with("200"));
+    assert!(send_command(&mut control, "NLST").starts_with("521"));
+
+    assert!(send_command(&mut control, "PROT P").starts_with("200"));
+    let reply = send_command(&mut control, "PASV");
+    let port = passive_port(&reply);
+    let data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut data = tls_client(data);
+    assert!(send_command(&mut control, "NLST").starts_with("150"));
+    let listing = read_tls_listing(&mut data);
+    assert_eq!(listing, "a.txt\r\n");
+    assert!(read_reply(&mut control).starts_with("226"));
+}
+
+#[test]
+fn ftps_implicit() {
+    let addr = "127.0.0.1:1282";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("a.txt"), b"a").unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf())
+        .ftps_implicit("tests/resources/server.pem", "tests/resources/server.key")
+        .idle_session_timeout(1);
+    let _rt = spawn_server(server, addr);
+
+    // The greeting is sent over TLS and the data channel is protected without PROT P.
+    let control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut control = tls_client(control);
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    let reply = send_command(&mut control, "PASV");
+    let port = passive_port(&reply);
+    let data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut data = tls_client(data);
+    assert!(send_command(&mut control, "NLST").starts_with("150"));
+    let listing = read_tls_listing(&mut data);
+    assert_eq!(listing, "a.txt\r\n");
+    assert!(read_reply(&mut control).starts_with("226"));
+
+    // A client that never starts the handshake is disconnected after the idle session timeout.
+    let mut silent = std::net::TcpStream::connect(addr).unwrap();
+    silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buf = [0u8; 16];
+    let start = std::time::Instant::now();
+    assert_eq!(std::io::Read::read(&mut silent, &mut buf).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn ftps_with_missing_certificates() {
+    let mut rt = Runtime::new().unwrap();
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir()).ftps("tests/resources/nope.pem", "tests/resources/server.key");
+    let err = rt.block_on(server.listen("127.0.0.1:1273")).unwrap_err();
+    assert_eq!(err.kind(), &libunftp::ServerErrorKind::TlsConfigError);
+}
+
+// Reads a listing from a TLS data connection. The server ends it with a close_notify alert, which
+// rustls reports as a ConnectionAborted error rather than the end of the stream.
+fn read_tls_listing(data: &mut rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>) -> String {
+    let mut listing = String::new();
+    let err = std::io::Read::read_to_string(data, &mut listing).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionAborted, "Expected close_notify: {}", err);
+    listing
+}
+
+// Secures a new control connection and returns it with the certificate the server presented.
+fn tls_login(addr: &str) -> (rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>, rustls::Certificate) {
+    tls_login_for(addr, "localhost", true)
+}
+
+fn tls_login_for(addr: &str, server_name: &str, sni: bool) -> (rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream>, rustls::Certificate) {
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "AUTH TLS").starts_with("234"));
+    let mut control = tls_client_for(control, server_name, sni);
+    assert!(send_command(&mut control, "USER hoi").starts_with("331"));
+    let certificate = peer_certificate(&control);
+    (control, certificate)
+}
+
+fn peer_certificate<S: rustls::Session>(stream: &rustls::StreamOwned<S, std::net::TcpStream>) -> rustls::Certificate {
+    stream.sess.get_peer_certificates().unwrap()[0].clone()
+}
+
+fn first_certificate(filename: &str) -> rustls::Certificate {
+    let mut reader = BufReader::new(fs::File::open(filename).unwrap());
+    rustls::internal::pemfile::certs(&mut reader).unwrap()[0].clone()
+}
+
+#[test]
+fn ftps_reload_certificates() {
+    let addr = "127.0.0.1:1274";
+    let dir = tempfile::TempDir::new().unwrap();
+    let (certs_file, key_file) = (dir.path().join("server.pem"), dir.path().join("server.key"));
+    fs::copy("tests/resources/server.pem", &certs_file).unwrap();
+    fs::copy("tests/resources/server.key", &key_file).unwrap();
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir()).ftps(certs_file.clone(), key_file.clone());
+    let handle = server.handle();
+    let _rt = spawn_server(server, addr);
+
+    let (mut before, certificate) = tls_login(addr);
+    assert_eq!(certificate, first_certificate("tests/resources/server.pem"));
+
+    // A broken file is refused and the current certificate stays in use.
+    fs::write(&certs_file, b"garbage").unwrap();
+    let err = handle.reload_certificates().unwrap_err();
+    assert_eq!(err.kind(), &libunftp::ServerErrorKind::TlsConfigError);
+    assert_eq!(tls_login(addr).1, first_certificate("tests/resources/server.pem"));
+
+    fs::copy("tests/resources/renewed.pem", &certs_file).unwrap();
+    fs::copy("tests/resources/renewed.key", &key_file).unwrap();
+    handle.reload_certificates().unwrap();
+    assert_eq!(tls_login(addr).1, first_certificate("tests/resources/renewed.pem"));
+
+    // The session that was secured before the reload keeps going.
+    assert!(send_command(&mut before, "PASS jij").starts_with("230"));
+}
+
+#[test]
+fn ftps_reload_interval() {
+    let addr = "127.0.0.1:1275";
+    let dir = tempfile::TempDir::new().unwrap();
+    let (certs_file, key_file) = (dir.path().join("server.pem"), dir.path().join("server.key"));
+    fs::copy("tests/resources/server.pem", &certs_file).unwrap();
+    fs::copy("tests/resources/server.key", &key_file).unwrap();
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir())
+        .ftps(certs_file.clone(), key_file.clone())
+        .ftps_reload_interval(Duration::from_millis(100));
+    let _rt = spawn_server(server, addr);
+    assert_eq!(tls_login(addr).1, first_certificate("tests/resources/server.pem"));
+
+    fs::copy("tests/resources/renewed.pem", &certs_file).unwrap();
+    fs::copy("tests/resources/renewed.key", &key_file).unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(tls_login(addr).1, first_certificate("tests/resources/renewed.pem"));
+}
+
+#[test]
+fn ftps_sni_certificates() {
+    let addr = "127.0.0.1:1276";
+    let root = tempfile::TempDir::new().unwrap();
+    fs::write(root.path().join("a.txt"), b"a").unwrap();
+    let server = libunftp::Server::new_with_fs_root(root.path().to_path_buf())
+        .ftps_sni_certificate("FTP.Partner.Example", "tests/resources/partner.pem", "tests/resources/partner.key")
+        .ftps("tests/resources/server.pem", "tests/resources/server.key");
+    let _rt = spawn_server(server, addr);
+
+    assert_eq!(tls_login_for(addr, "localhost", true).1, first_certificate("tests/resources/server.pem"));
+    // Clients that don't ask for a name get the default certificate.
+    assert_eq!(tls_login_for(addr, "localhost", false).1, first_certificate("tests/resources/server.pem"));
+
+    let (mut control, certificate) = tls_login_for(addr, "ftp.partner.example", true);
+    assert_eq!(certificate, first_certificate("tests/resources/partner.pem"));
+    assert!(send_command(&mut control, "PASS jij").starts_with("230"));
+    assert!(send_command(&mut control, "PBSZ 0").starts_with("200"));
+    assert!(send_command(&mut control, "PROT P").starts_with("200"));
+    let reply = send_command(&mut control, "PASV");
+    let port = passive_port(&reply);
+    let data = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    data.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut data = tls_client_for(data, "ftp.partner.example", true);
+    assert!(send_command(&mut control, "NLST").starts_with("150"));
+    let listing = read_tls_listing(&mut data);
+    assert_eq!(listing, "a.txt\r\n");
+    assert_eq!(peer_certificate(&data), first_certificate("tests/resources/partner.pem"));
+    assert!(read_reply(&mut control).starts_with("226"));
+}
+
+// Logs in partner-bot by certificate alone and alice with her password and certificate.
+#[derive(Debug)]
+struct CertificateAuthenticator;
+
+#[async_trait::async_trait]
+impl libunftp::auth::Authenticator<libunftp::auth::DefaultUser> for CertificateAuthenticator {
+    async fn authenticate(
+        &self,
+        _username: &str,
+        _password: &str,
+    ) -> std::result::Result<libunftp::auth::DefaultUser, Box<dyn std::error::Error + Send + Sync>> {
+        Err("a certificate is required".into())
+    }
+
+    async fn authenticate_with_credentials(
+        &self,
+        username: &str,
+        credentials: &libunftp::auth::Credentials,
+    ) -> std::result::Result<libunftp::auth::DefaultUser, Box<dyn std::error::Error + Send + Sync>> {
+        // Take a while to check certificates, like an authenticator that looks them up elsewhere.
+        if credentials.password.is_none() {
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+        }
+        let common_name = credentials.certificate.as_ref().and_then(|cert| cert.common_name());
+        match (username, credentials.password.as_deref(), common_name) {
+            ("bot", None, Some("partner-bot")) => Ok(libunftp::auth::DefaultUser {}),
+            ("alice", Some("secret"), Some(_)) => Ok(libunftp::auth::DefaultUser {}),
+            _ => Err("bad credentials".into()),
+        }
+    }
+}
+
+fn certificate_server(
+    client_auth: libunftp::options::FtpsClientAuth,
+) -> libunftp::Server<libunftp::storage::filesystem::Filesystem, libunftp::auth::DefaultUser> {
+    let root = std::env::temp_dir();
+    libunftp::Server::new_with_authenticator(
+        Box::new(move || libunftp::storage::filesystem::Filesystem::new(root.clone())),
+        std::sync::Arc::new(CertificateAuthenticator),
+    )
+    .ftps("tests/resources/server.pem", "tests/resources/server.key")
+    .ftps_client_auth(client_auth, "tests/resources/ca.pem")
+}
+
+// Secures a new control connection, presenting the test client certificate if asked to.
+fn connect_with_certificate(addr: &str, with_certificate: bool) -> rustls::StreamOwned<rustls::ClientSession, std::net::TcpStream> {
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "AUTH TLS").starts_with("234"));
+    let mut config = tls_client_config();
+    if with_certificate {
+        let certs = rustls::internal::pemfile::certs(&mut BufReader::new(fs::File::open("tests/resources/client.pem").unwrap())).unwrap();
+        let mut keys = rustls::internal::pemfile::pkcs8_private_keys(&mut BufReader::new(fs::File::open("tests/resources/client.key").unwrap())).unwrap();
+        config.set_single_client_cert(certs, keys.remove(0)).unwrap();
+    }
+    tls_client_with(control, "localhost", config)
+}
+
+#[test]
+fn ftps_client_certificates() {
+    let addr = "127.0.0.1:1277";
+    let _rt = spawn_server(certificate_server(libunftp::options::FtpsClientAuth::Request), addr);
+
+    // The certificate alone is enough for the bot.
+    let mut control = connect_with_certificate(addr, true);
+    assert!(send_command(&mut control, "USER bot").starts_with("230"));
+    assert!(send_command(&mut control, "PWD").starts_with("257"));
+
+    // Alice needs her password as well.
+    let mut control = connect_with_certificate(addr, true);
+    assert!(send_command(&mut control, "USER alice").starts_with("331"));
+    assert!(send_command(&mut control, "PASS secret").starts_with("230"));
+
+    // Commands sent right after USER wait for the certificate check.
+    let mut control = connect_with_certificate(addr, true);
+    write!(control, "USER alice\r\nPASS secret\r\n").unwrap();
+    assert!(read_reply(&mut control).starts_with("331"));
+    assert!(read_reply(&mut control).starts_with("230"));
+    let mut control = connect_with_certificate(addr, true);
+    write!(control, "USER bot\r\nPWD\r\n").unwrap();
+    assert!(read_reply(&mut control).starts_with("230"));
+    assert!(read_reply(&mut control).starts_with("257"));
+
+    // Without a certificate the password is not enough.
+    let mut control = connect_with_certificate(addr, false);
+    assert!(send_command(&mut control, "USER alice").starts_with("331"));
+    assert!(send_command(&mut control, "PASS secret").starts_with("530"));
+}
+
+#[test]
+fn ftps_client_certificate_required() {
+    let addr = "127.0.0.1:1278";
+    let _rt = spawn_server(certificate_server(libunftp::options::FtpsClientAuth::Require), addr);
+
+    let mut control = connect_with_certificate(addr, true);
+    assert!(send_command(&mut control, "USER bot").starts_with("230"));
+
+    // The server ends the handshake when the client has no certificate.
+    let mut control = connect_with_certificate(addr, false);
+    let _ = write!(control, "USER bot\r\n");
+    let mut byte = [0u8; 1];
+    let read = std::io::Read::read(&mut control, &mut byte);
+    assert!(read.map(|n| n == 0).unwrap_or(true), "Expected the connection to be closed");
+}
+
+#[test]
+fn ftps_reload_client_auth_certificate_authorities() {
+    let addr = "127.0.0.1:1287";
+    let dir = tempfile::TempDir::new().unwrap();
+    let ca_file = dir.path().join("ca.pem");
+    // The client certificate wasn't issued by this one.
+    fs::copy("tests/resources/partner.pem", &ca_file).unwrap();
+    let root = std::env::temp_dir();
+    let server = libunftp::Server::new_with_authenticator(
+        Box::new(move || libunftp::storage::filesystem::Filesystem::new(root.clone())),
+        std::sync::Arc::new(CertificateAuthenticator),
+    )
+    .ftps("tests/resources/server.pem", "tests/resources/server.key")
+    .ftps_client_auth(libunftp::options::FtpsClientAuth::Require, ca_file.clone());
+    let handle = server.handle();
+    let _rt = spawn_server(server, addr);
+
+    let mut control = connect_with_certificate(addr, true);
+    let _ = write!(control, "USER bot\r\n");
+    let mut byte = [0u8; 1];
+    let read = std::io::Read::read(&mut control, &mut byte);
+    assert!(read.map(|n| n == 0).unwrap_or(true), "Expected the connection to be closed");
+
+    fs::copy("tests/resources/ca.pem", &ca_file).unwrap();
+    handle.reload_certificates().unwrap();
+    let mut control = connect_with_certificate(addr, true);
+    assert!(send_command(&mut control, "USER bot").starts_with("230"));
+}
+
+#[test]
+fn ftps_tls_options() {
+    use libunftp::options::{TlsOptions, TlsVersion};
+    use rustls::Session;
+
+    let addr = "127.0.0.1:1279";
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir())
+        .ftps("tests/resources/server.pem", "tests/resources/server.key")
+        .ftps_tls_options(
+            TlsOptions::new()
+                .min_version(TlsVersion::V1_3)
+                .cipher_suites(vec!["TLS_CHACHA20_POLY1305_SHA256"]),
+        );
+    let _rt = spawn_server(server, addr);
+
+    let (control, _) = tls_login(addr);
+    assert_eq!(control.sess.get_protocol_version(), Some(rustls::ProtocolVersion::TLSv1_3));
+    assert_eq!(
+        control.sess.get_negotiated_ciphersuite().unwrap().suite,
+        rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+    );
+
+    // Clients that only speak TLS 1.2 are turned away.
+    let mut control = std::net::TcpStream::connect(addr).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(read_reply(&mut control).starts_with("220"));
+    assert!(send_command(&mut control, "AUTH TLS").starts_with("234"));
+    let mut config = tls_client_config();
+    config.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+    let mut control = tls_client_with(control, "localhost", config);
+    assert!(write!(control, "USER hoi\r\n").is_err());
+}
+
+#[test]
+fn ftps_tls_options_with_unknown_cipher_suite() {
+    let mut rt = Runtime::new().unwrap();
+    let server = libunftp::Server::new_with_fs_root(std::env::temp_dir())
+        .ftps("tests/resources/server.pem", "tests/resources/server.key")
+        .ftps_tls_options(libunftp::options::TlsOptions::new().cipher_suites(vec!["TLS_RSA_WITH_RC4_128_MD5"]));
+    let err = rt.block_on(server.listen("127.0.0.1:1280")).unwrap_err();
+    assert_eq!(err.kind(), &libunftp::ServerErrorKind::TlsConfigError);
+}